@@ -0,0 +1,126 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use error_chain::ChainedError;
+
+use crate::errors::*;
+use crate::metrics::{CounterVec, MetricOpts, Metrics};
+use crate::util::spawn_thread;
+
+const RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+
+// Quotas and response limits of an API key tier (unset fields fall back to the global config)
+#[derive(Deserialize, Debug, Default)]
+pub struct Tier {
+    pub rate_limit: Option<f64>,
+    pub rate_limit_burst: Option<f64>,
+    pub chain_txs_per_page: Option<usize>,
+    pub max_mempool_txs: Option<usize>,
+    pub utxos_limit: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct KeyEntry {
+    tier: String,
+    name: Option<String>, // used as the metrics label, to avoid exporting the key itself
+}
+
+// The API keys file format:
+// { "tiers": { "<tier>": {<Tier fields>}, ... }, "keys": { "<key>": {"tier": "<tier>", "name": "<name>"}, ... } }
+#[derive(Deserialize)]
+struct KeysFile {
+    tiers: HashMap<String, Tier>,
+    keys: HashMap<String, KeyEntry>,
+}
+
+pub struct ApiKey {
+    pub name: String,
+    pub tier_name: String,
+    pub tier: Arc<Tier>,
+}
+
+pub struct ApiKeys {
+    path: PathBuf,
+    keys: RwLock<HashMap<String, Arc<ApiKey>>>,
+    modified: Mutex<Option<SystemTime>>,
+
+    // monitoring
+    requests: CounterVec,
+}
+
+impl ApiKeys {
+    /// Loads the API keys file, and keeps reloading it whenever it changes.
+    pub fn start(path: PathBuf, metrics: &Metrics) -> Result<Arc<ApiKeys>> {
+        let api_keys = Arc::new(ApiKeys {
+            path,
+            keys: RwLock::new(HashMap::new()),
+            modified: Mutex::new(None),
+            requests: metrics.counter_vec(
+                MetricOpts::new("api_key_requests", "# of requests per API key"),
+                &["key", "tier"],
+            ),
+        });
+        api_keys.reload()?;
+
+        let reloader = Arc::clone(&api_keys);
+        spawn_thread("apikeys", move || loop {
+            thread::sleep(RELOAD_INTERVAL);
+            if let Err(e) = reloader.reload() {
+                warn!("failed to reload API keys: {}", e.display_chain());
+            }
+        });
+        Ok(api_keys)
+    }
+
+    pub fn lookup(&self, key: &str) -> Option<Arc<ApiKey>> {
+        self.keys.read().unwrap().get(key).cloned()
+    }
+
+    pub fn record_request(&self, key: &ApiKey) {
+        self.requests
+            .with_label_values(&[&key.name, &key.tier_name])
+            .inc();
+    }
+
+    fn reload(&self) -> Result<()> {
+        let modified = fs::metadata(&self.path)
+            .and_then(|meta| meta.modified())
+            .chain_err(|| format!("failed to stat {:?}", self.path))?;
+        let mut last_modified = self.modified.lock().unwrap();
+        if *last_modified == Some(modified) {
+            return Ok(());
+        }
+
+        let contents = fs::read_to_string(&self.path)
+            .chain_err(|| format!("failed to read {:?}", self.path))?;
+        let file: KeysFile = serde_json::from_str(&contents)
+            .chain_err(|| format!("invalid API keys file {:?}", self.path))?;
+        let tiers: HashMap<String, Arc<Tier>> = file
+            .tiers
+            .into_iter()
+            .map(|(name, tier)| (name, Arc::new(tier)))
+            .collect();
+        let mut keys = HashMap::new();
+        for (key, entry) in file.keys {
+            let tier = tiers
+                .get(&entry.tier)
+                .chain_err(|| format!("unknown tier {:?}", entry.tier))?;
+            let tier_name = entry.tier.clone();
+            let api_key = ApiKey {
+                name: entry.name.unwrap_or_else(|| tier_name.clone()),
+                tier_name,
+                tier: Arc::clone(tier),
+            };
+            keys.insert(key, Arc::new(api_key));
+        }
+
+        info!("loaded {} API keys from {:?}", keys.len(), self.path);
+        *self.keys.write().unwrap() = keys;
+        *last_modified = Some(modified);
+        Ok(())
+    }
+}
@@ -11,14 +11,17 @@ use std::sync::{Arc, RwLock};
 use std::time::Duration;
 
 use electrs::{
+    apikeys::ApiKeys,
     config::Config,
     daemon::Daemon,
     electrum::RPC as ElectrumRPC,
     errors::*,
     metrics::Metrics,
     new_index::{precache, ChainQuery, FetchFrom, Indexer, Mempool, Query, Store},
+    ratelimit::RateLimiter,
     rest,
     signal::Waiter,
+    webhooks::Webhooks,
 };
 
 #[cfg(feature = "liquid")]
@@ -76,12 +79,22 @@ fn run_server(config: Arc<Config>) -> Result<()> {
         precache::precache(&chain, precache_scripthashes);
     }
 
+    let mempool_path = config.db_path.join("mempool.dat");
     let mempool = Arc::new(RwLock::new(Mempool::new(
         Arc::clone(&chain),
         &metrics,
         Arc::clone(&config),
     )));
-    mempool.write().unwrap().update(&daemon)?;
+    {
+        let mut mempool = mempool.write().unwrap();
+        if let Err(err) = mempool.load(&mempool_path) {
+            warn!("failed to load the saved mempool: {}", err.display_chain());
+        }
+        mempool.update(&daemon)?;
+        // the txs loaded from the saved mempool or that entered it during the restart are not
+        // notified as new (the indexer doesn't record events during its initial update either)
+        mempool.take_events();
+    }
 
     #[cfg(feature = "liquid")]
     let asset_db = config.asset_db_path.as_ref().map(|db_dir| {
@@ -100,14 +113,37 @@ fn run_server(config: Arc<Config>) -> Result<()> {
     ));
 
     // TODO: configuration for which servers to start
-    let rest_server = rest::start(Arc::clone(&config), Arc::clone(&query));
-    let electrum_server = ElectrumRPC::start(Arc::clone(&config), Arc::clone(&query), &metrics);
+    let api_keys = match config.api_keys_file {
+        Some(ref path) => Some(ApiKeys::start(path.clone(), &metrics)?),
+        None => None,
+    };
+    let limiter = Arc::new(RateLimiter::new(&config, &metrics, api_keys.clone()));
+    let webhooks = match config.webhooks_admin_key {
+        Some(_) => Some(Webhooks::start(Arc::clone(&chain), &metrics)),
+        None => None,
+    };
+    let rest_server = rest::start(
+        Arc::clone(&config),
+        Arc::clone(&query),
+        Arc::clone(&limiter),
+        api_keys,
+        webhooks.clone(),
+    );
+    let electrum_server = ElectrumRPC::start(
+        Arc::clone(&config),
+        Arc::clone(&query),
+        &metrics,
+        Arc::clone(&limiter),
+    );
 
     loop {
         if let Err(err) = signal.wait(Duration::from_secs(5), true) {
             info!("stopping server: {}", err);
             rest_server.stop();
             // the electrum server is stopped when dropped
+            if let Err(err) = mempool.read().unwrap().dump(&mempool_path) {
+                warn!("failed to save the mempool: {}", err.display_chain());
+            }
             break;
         }
 
@@ -121,8 +157,18 @@ fn run_server(config: Arc<Config>) -> Result<()> {
         // Update mempool
         mempool.write().unwrap().update(&daemon)?;
 
-        // Update subscribed clients
-        electrum_server.notify();
+        // Update subscribed clients (only for the scripthashes that were touched)
+        let mut touched = indexer.take_touched();
+        touched.extend(mempool.write().unwrap().take_touched());
+        electrum_server.notify(touched);
+
+        // Update the http event streams and webhooks
+        let mut events = indexer.take_events();
+        events.extend(mempool.write().unwrap().take_events());
+        rest_server.notify(&events);
+        if let Some(ref webhooks) = webhooks {
+            webhooks.notify(&events);
+        }
     }
     info!("server stopped");
     Ok(())
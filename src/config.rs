@@ -1,6 +1,7 @@
 use clap::{App, Arg};
 use dirs::home_dir;
 use std::fs;
+use std::net::IpAddr;
 use std::net::SocketAddr;
 use std::net::ToSocketAddrs;
 use std::path::{Path, PathBuf};
@@ -32,11 +33,28 @@ pub struct Config {
     pub light_mode: bool,
     pub address_search: bool,
     pub index_unspendables: bool,
+    pub block_filters: bool,
+    pub chain_stats: bool,
     pub cors: Option<String>,
+    pub rate_limit: Option<f64>,
+    pub rate_limit_burst: f64,
+    pub trusted_proxies: Vec<IpAddr>,
+    pub api_keys_file: Option<PathBuf>,
+    pub require_api_key: bool,
+    pub webhooks_admin_key: Option<String>,
     pub precache_scripts: Option<String>,
+    pub fee_estimator: FeeEstimator,
     pub utxos_limit: usize,
+    pub addresses_batch_limit: usize,
+    pub xpub_gap_limit: u32,
     pub electrum_txs_limit: usize,
     pub electrum_banner: String,
+    pub electrum_max_batch_size: usize,
+    pub electrum_max_connections: usize,
+    pub electrum_max_line_length: usize,
+    pub electrum_max_in_flight: usize,
+    pub electrum_max_subscriptions: usize,
+    pub electrum_idle_timeout: u64,
 
     #[cfg(feature = "liquid")]
     pub parent_network: Network,
@@ -154,24 +172,90 @@ impl Config {
                     .long("index-unspendables")
                     .help("Enable indexing of provably unspendable outputs")
             )
+            .arg(
+                Arg::with_name("block_filters")
+                    .long("block-filters")
+                    .help("Enable indexing of BIP158 compact block filters (must be enabled from the initial sync, not available with liquid)")
+            )
+            .arg(
+                Arg::with_name("chain_stats")
+                    .long("chain-stats")
+                    .help("Enable indexing of chain-wide supply, utxo set and address stats (must be enabled from the initial sync, not available with liquid)")
+            )
             .arg(
                 Arg::with_name("cors")
                     .long("cors")
                     .help("Origins allowed to make cross-site requests")
                     .takes_value(true)
             )
+            .arg(
+                Arg::with_name("rate_limit")
+                    .long("rate-limit")
+                    .help("Per-client request budget, in cost units per second (default disabled). A request costs one unit plus one unit per 100 scanned DB rows. Applies to the Electrum and HTTP APIs.")
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::with_name("rate_limit_burst")
+                    .long("rate-limit-burst")
+                    .help("Maximum budget a client can accumulate, in cost units")
+                    .default_value("300")
+            )
+            .arg(
+                Arg::with_name("trusted_proxies")
+                    .long("trusted-proxies")
+                    .help("Comma-separated addresses of the reverse proxies whose X-Forwarded-For header identifies the HTTP client for rate limiting")
+                    .takes_value(true)
+                    .use_delimiter(true)
+            )
+            .arg(
+                Arg::with_name("api_keys_file")
+                    .long("api-keys-file")
+                    .help("Path to a JSON file with the API keys and their tiers (reloaded on change). Applies to the HTTP API.")
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::with_name("require_api_key")
+                    .long("require-api-key")
+                    .help("Reject HTTP requests without a valid API key (requires --api-keys-file)")
+                    .requires("api_keys_file")
+            )
+            .arg(
+                Arg::with_name("webhooks_admin_key")
+                    .long("webhooks-admin-key")
+                    .help("Enables the webhooks, managed through the /admin/webhooks HTTP endpoints using this key (X-Admin-Key header)")
+                    .takes_value(true)
+            )
             .arg(
                 Arg::with_name("precache_scripts")
                     .long("precache-scripts")
                     .help("Path to file with list of scripts to pre-cache")
                     .takes_value(true)
             )
+            .arg(
+                Arg::with_name("fee_estimator")
+                    .long("fee-estimator")
+                    .help("Source of the fee estimates: the daemon's estimatesmartfee, or a local estimator based on the mempool and the recent blocks")
+                    .possible_values(&["daemon", "local"])
+                    .default_value("daemon")
+            )
             .arg(
                 Arg::with_name("utxos_limit")
                     .long("utxos-limit")
                     .help("Maximum number of utxos to process per address. Lookups for addresses with more utxos will fail. Applies to the Electrum and HTTP APIs.")
                     .default_value("500")
             )
+            .arg(
+                Arg::with_name("addresses_batch_limit")
+                    .long("addresses-batch-limit")
+                    .help("Maximum number of addresses (or scripthashes) per batch request. Applies to the HTTP API.")
+                    .default_value("100")
+            )
+            .arg(
+                Arg::with_name("xpub_gap_limit")
+                    .long("xpub-gap-limit")
+                    .help("Default number of consecutive unused addresses scanned by xpub/descriptor lookups, at most 100 (HTTP API)")
+                    .default_value("20")
+            )
             .arg(
                 Arg::with_name("electrum_txs_limit")
                     .long("electrum-txs-limit")
@@ -182,6 +266,36 @@ impl Config {
                     .long("electrum-banner")
                     .help("Welcome banner for the Electrum server, shown in the console to clients.")
                     .takes_value(true)
+            ).arg(
+                Arg::with_name("electrum_max_batch_size")
+                    .long("electrum-max-batch-size")
+                    .help("Maximum number of requests in an Electrum JSON-RPC batch.")
+                    .default_value("100")
+            ).arg(
+                Arg::with_name("electrum_max_connections")
+                    .long("electrum-max-connections")
+                    .help("Maximum number of concurrent Electrum connections. New connections above the limit are rejected.")
+                    .default_value("10000")
+            ).arg(
+                Arg::with_name("electrum_max_line_length")
+                    .long("electrum-max-line-length")
+                    .help("Maximum length of an Electrum request line (in bytes). Connections sending longer lines are closed.")
+                    .default_value("1000000")
+            ).arg(
+                Arg::with_name("electrum_max_in_flight")
+                    .long("electrum-max-in-flight")
+                    .help("Maximum number of pending requests per Electrum connection. Further requests are read once these are handled.")
+                    .default_value("10")
+            ).arg(
+                Arg::with_name("electrum_max_subscriptions")
+                    .long("electrum-max-subscriptions")
+                    .help("Maximum number of scripthash subscriptions per Electrum connection. Connections exceeding it are closed.")
+                    .default_value("10000")
+            ).arg(
+                Arg::with_name("electrum_idle_timeout")
+                    .long("electrum-idle-timeout")
+                    .help("Close Electrum connections that are idle (or not reading replies) for this many seconds.")
+                    .default_value("600")
             );
 
         #[cfg(unix)]
@@ -359,9 +473,17 @@ impl Config {
             daemon_rpc_addr,
             cookie,
             utxos_limit: value_t_or_exit!(m, "utxos_limit", usize),
+            addresses_batch_limit: value_t_or_exit!(m, "addresses_batch_limit", usize),
+            xpub_gap_limit: value_t_or_exit!(m, "xpub_gap_limit", u32),
             electrum_rpc_addr,
             electrum_txs_limit: value_t_or_exit!(m, "electrum_txs_limit", usize),
             electrum_banner,
+            electrum_max_batch_size: value_t_or_exit!(m, "electrum_max_batch_size", usize),
+            electrum_max_connections: value_t_or_exit!(m, "electrum_max_connections", usize),
+            electrum_max_line_length: value_t_or_exit!(m, "electrum_max_line_length", usize),
+            electrum_max_in_flight: value_t_or_exit!(m, "electrum_max_in_flight", usize),
+            electrum_max_subscriptions: value_t_or_exit!(m, "electrum_max_subscriptions", usize),
+            electrum_idle_timeout: value_t_or_exit!(m, "electrum_idle_timeout", u64),
             http_addr,
             http_socket_file,
             monitoring_addr,
@@ -369,8 +491,28 @@ impl Config {
             light_mode: m.is_present("light_mode"),
             address_search: m.is_present("address_search"),
             index_unspendables: m.is_present("index_unspendables"),
+            block_filters: m.is_present("block_filters"),
+            chain_stats: m.is_present("chain_stats"),
             cors: m.value_of("cors").map(|s| s.to_string()),
+            rate_limit: if m.is_present("rate_limit") {
+                Some(value_t_or_exit!(m, "rate_limit", f64))
+            } else {
+                None
+            },
+            rate_limit_burst: value_t_or_exit!(m, "rate_limit_burst", f64),
+            trusted_proxies: if m.is_present("trusted_proxies") {
+                values_t_or_exit!(m, "trusted_proxies", IpAddr)
+            } else {
+                vec![]
+            },
+            api_keys_file: m.value_of("api_keys_file").map(PathBuf::from),
+            require_api_key: m.is_present("require_api_key"),
+            webhooks_admin_key: m.value_of("webhooks_admin_key").map(|s| s.to_string()),
             precache_scripts: m.value_of("precache_scripts").map(|s| s.to_string()),
+            fee_estimator: match m.value_of("fee_estimator") {
+                Some("local") => FeeEstimator::Local,
+                _ => FeeEstimator::Daemon,
+            },
 
             #[cfg(feature = "liquid")]
             parent_network,
@@ -401,6 +543,12 @@ impl Config {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FeeEstimator {
+    Daemon, // estimatesmartfee
+    Local,  // see util::fees::estimate_feerates()
+}
+
 struct StaticCookie {
     value: Vec<u8>,
 }
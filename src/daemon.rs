@@ -73,7 +73,11 @@ fn parse_jsonrpc_reply(mut reply: Value, method: &str, expected_id: u64) -> Resu
                     match code {
                         // RPC_IN_WARMUP -> retry by later reconnection
                         -28 => bail!(ErrorKind::Connection(err.to_string())),
-                        _ => bail!("{} RPC error: {}", method, err),
+                        _ => bail!(ErrorKind::RpcError(
+                            method.to_string(),
+                            code,
+                            err["message"].as_str().unwrap_or_default().to_string(),
+                        )),
                     }
                 }
             }
@@ -98,6 +102,22 @@ fn parse_jsonrpc_reply(mut reply: Value, method: &str, expected_id: u64) -> Resu
     bail!("non-object reply: {:?}", reply);
 }
 
+#[derive(Deserialize, Debug)]
+pub struct MempoolAcceptFees {
+    pub base: f64, // in BTC
+}
+
+// Fields other than the txid may be missing when a package is rejected as a whole
+#[derive(Deserialize, Debug)]
+pub struct MempoolAcceptResult {
+    pub txid: Txid,
+    pub allowed: Option<bool>,
+    pub vsize: Option<u64>,
+    pub fees: Option<MempoolAcceptFees>,
+    #[serde(rename = "reject-reason")]
+    pub reject_reason: Option<String>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct BlockchainInfo {
     pub chain: String,
@@ -534,6 +554,11 @@ impl Daemon {
         )
     }
 
+    pub fn test_mempool_accept(&self, txhexes: &[String]) -> Result<Vec<MempoolAcceptResult>> {
+        let results = self.request("testmempoolaccept", json!([txhexes]))?;
+        Ok(from_value(results).chain_err(|| "invalid testmempoolaccept reply")?)
+    }
+
     // Get estimated feerates for the provided confirmation targets using a batch RPC request
     // Missing estimates are logged but do not cause a failure, whatever is available is returned
     #[allow(clippy::float_cmp)]
@@ -1,9 +1,10 @@
 use std::collections::HashMap;
-use std::io::{BufRead, BufReader, Write};
-use std::net::{Shutdown, SocketAddr, TcpStream};
-use std::sync::mpsc::{Sender, SyncSender, TrySendError};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::Sender;
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::Duration;
 
 use bitcoin::hashes::sha256d::Hash as Sha256dHash;
 use bitcoin::Txid;
@@ -11,7 +12,14 @@ use crypto::digest::Digest;
 use crypto::sha2::Sha256;
 use error_chain::ChainedError;
 use hex;
+use rayon::prelude::*;
 use serde_json::{from_str, Value};
+use tokio::io::{
+    split, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, ReadHalf, WriteHalf,
+};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot, Semaphore};
+use tokio::{task, time};
 
 #[cfg(not(feature = "liquid"))]
 use bitcoin::consensus::encode::serialize;
@@ -21,17 +29,19 @@ use elements::encode::serialize;
 use crate::config::Config;
 use crate::electrum::{get_electrum_height, ProtocolVersion};
 use crate::errors::*;
-use crate::metrics::{Gauge, HistogramOpts, HistogramVec, MetricOpts, Metrics};
-use crate::new_index::Query;
+use crate::metrics::{CounterVec, Gauge, HistogramOpts, HistogramVec, MetricOpts, Metrics};
+use crate::new_index::{Query, TouchedScripts};
+use crate::ratelimit::{self, Client, RateLimiter};
 use crate::util::electrum_merkle::{get_header_merkle_proof, get_id_from_pos, get_tx_merkle_proof};
 use crate::util::{
     create_socket, full_hash, spawn_thread, BlockId, BoolThen, Channel, FullHash, HeaderEntry,
-    SyncChannel,
 };
 
 const ELECTRS_VERSION: &str = env!("CARGO_PKG_VERSION");
 const PROTOCOL_VERSION: ProtocolVersion = ProtocolVersion::new(1, 4);
 const MAX_HEADERS: usize = 2016;
+// room for periodic updates in each connection's queue, on top of the in-flight requests
+const NOTIFICATIONS_BACKLOG: usize = 10;
 
 #[cfg(feature = "electrum-discovery")]
 use crate::electrum::{DiscoveryManager, ServerFeatures};
@@ -91,15 +101,39 @@ fn get_status_hash(txs: Vec<(Txid, Option<BlockId>)>, query: &Query) -> Option<F
     }
 }
 
+// Per-connection resource limits, violating any of them closes the connection
+// (except for max_in_flight, which pauses reading requests until the pending ones are handled)
+#[derive(Clone, Copy)]
+struct ConnectionLimits {
+    max_line_length: usize,
+    max_in_flight: usize,
+    max_subscriptions: usize,
+    idle_timeout: Duration,
+}
+
+impl From<&Config> for ConnectionLimits {
+    fn from(config: &Config) -> Self {
+        ConnectionLimits {
+            max_line_length: config.electrum_max_line_length,
+            max_in_flight: config.electrum_max_in_flight,
+            max_subscriptions: config.electrum_max_subscriptions,
+            idle_timeout: Duration::from_secs(config.electrum_idle_timeout),
+        }
+    }
+}
+
 struct Connection {
     query: Arc<Query>,
     last_header_entry: Option<HeaderEntry>,
     status_hashes: HashMap<Sha256dHash, Value>, // ScriptHash -> StatusHash
-    stream: TcpStream,
     addr: SocketAddr,
-    chan: SyncChannel<Message>,
+    sender: mpsc::Sender<Message>,
     stats: Arc<Stats>,
     txs_limit: usize,
+    max_batch_size: usize,
+    limits: ConnectionLimits,
+    limiter: Arc<RateLimiter>,
+    api_key: Option<String>,
     #[cfg(feature = "electrum-discovery")]
     discovery: Option<Arc<DiscoveryManager>>,
 }
@@ -107,21 +141,27 @@ struct Connection {
 impl Connection {
     pub fn new(
         query: Arc<Query>,
-        stream: TcpStream,
         addr: SocketAddr,
+        sender: mpsc::Sender<Message>,
         stats: Arc<Stats>,
         txs_limit: usize,
+        max_batch_size: usize,
+        limits: ConnectionLimits,
+        limiter: Arc<RateLimiter>,
         #[cfg(feature = "electrum-discovery")] discovery: Option<Arc<DiscoveryManager>>,
     ) -> Connection {
         Connection {
             query,
             last_header_entry: None, // disable header subscription for now
             status_hashes: HashMap::new(),
-            stream,
             addr,
-            chan: SyncChannel::new(10),
+            sender,
             stats,
             txs_limit,
+            max_batch_size,
+            limits,
+            limiter,
+            api_key: None,
             #[cfg(feature = "electrum-discovery")]
             discovery,
         }
@@ -142,6 +182,14 @@ impl Connection {
         ]))
     }
 
+    // Extension method: account the following requests to the given API key
+    fn server_authenticate(&mut self, params: &[Value]) -> Result<Value> {
+        let api_key = params.get(0).chain_err(|| "missing api_key")?;
+        let api_key = api_key.as_str().chain_err(|| "non-string api_key")?;
+        self.api_key = Some(api_key.to_string());
+        Ok(json!(true))
+    }
+
     fn server_banner(&self) -> Result<Value> {
         Ok(json!(self.query.config().electrum_banner.clone()))
     }
@@ -216,6 +264,28 @@ impl Connection {
         }))
     }
 
+    // extension: returns the BIP158 basic filter of the block and its BIP157 filter header
+    #[cfg(not(feature = "liquid"))]
+    fn blockchain_block_filter(&self, params: &[Value]) -> Result<Value> {
+        let height = usize_from_value(params.get(0), "height")?;
+        let chain = self.query.chain();
+        let entry = chain
+            .header_by_height(height)
+            .chain_err(|| "missing header")?;
+        let filter = chain
+            .get_block_filter(entry.hash())
+            .chain_err(|| "missing block filter")?;
+        let filter_header = chain
+            .get_filter_header(entry.hash())
+            .chain_err(|| "missing filter header")?;
+
+        Ok(json!({
+            "blockhash": entry.hash(),
+            "filter": hex::encode(filter),
+            "header": filter_header,
+        }))
+    }
+
     fn blockchain_block_headers(&self, params: &[Value]) -> Result<Value> {
         let start_height = usize_from_value(params.get(0), "start_height")?;
         let count = MAX_HEADERS.min(usize_from_value(params.get(1), "count")?);
@@ -269,6 +339,11 @@ impl Connection {
 
     fn blockchain_scripthash_subscribe(&mut self, params: &[Value]) -> Result<Value> {
         let script_hash = hash_from_value(params.get(0)).chain_err(|| "bad script_hash")?;
+        if !self.status_hashes.contains_key(&script_hash)
+            && self.status_hashes.len() >= self.limits.max_subscriptions
+        {
+            bail!(ErrorKind::LimitExceeded("subscriptions"));
+        }
 
         let history_txids = get_history(&self.query, &script_hash[..], self.txs_limit)?;
         let status_hash = get_status_hash(history_txids, &self.query)
@@ -309,6 +384,59 @@ impl Connection {
             .collect::<Vec<_>>()))
     }
 
+    // Extension method: confirmed history between two heights (inclusive)
+    fn blockchain_scripthash_get_history_range(&self, params: &[Value]) -> Result<Value> {
+        let script_hash = hash_from_value(params.get(0)).chain_err(|| "bad script_hash")?;
+        let start_height = usize_from_value(params.get(1), "start_height")?;
+        let end_height = usize_from_value(params.get(2), "end_height")?;
+        let descending = bool_from_value_or(params.get(3), "descending", false)?;
+        self.history_range(&script_hash[..], start_height, end_height, descending)
+    }
+
+    // Extension method: confirmed history between two block timestamps (inclusive)
+    fn blockchain_scripthash_get_history_by_time(&self, params: &[Value]) -> Result<Value> {
+        let script_hash = hash_from_value(params.get(0)).chain_err(|| "bad script_hash")?;
+        let start_time = usize_from_value(params.get(1), "start_time")? as u32;
+        let end_time = usize_from_value(params.get(2), "end_time")? as u32;
+        let descending = bool_from_value_or(params.get(3), "descending", false)?;
+
+        let chain = self.query.chain();
+        let start_height = chain.height_by_time(start_time);
+        let end_height = match chain.height_by_time(end_time.saturating_add(1)) {
+            0 => return Ok(json!([])), // before the genesis block
+            height => height - 1,
+        };
+        self.history_range(&script_hash[..], start_height, end_height, descending)
+    }
+
+    fn history_range(
+        &self,
+        script_hash: &[u8],
+        start_height: usize,
+        end_height: usize,
+        descending: bool,
+    ) -> Result<Value> {
+        // ask for one extra entry, to fail rather than silently truncate the history
+        let history_txids = self.query.chain().history_range_txids(
+            script_hash,
+            start_height,
+            end_height,
+            descending,
+            None,
+            self.txs_limit + 1,
+        );
+        ensure!(history_txids.len() <= self.txs_limit, ErrorKind::TooPopular);
+
+        Ok(json!(history_txids
+            .into_iter()
+            .map(|(txid, blockid)| GetHistoryResult {
+                txid,
+                height: blockid.height as isize,
+                fee: None,
+            })
+            .collect::<Vec<_>>()))
+    }
+
     fn blockchain_scripthash_listunspent(&self, params: &[Value]) -> Result<Value> {
         let script_hash = hash_from_value(params.get(0)).chain_err(|| "bad script_hash")?;
         let utxos = self.query.utxo(&script_hash[..])?;
@@ -325,12 +453,18 @@ impl Connection {
         )))
     }
 
-    fn blockchain_transaction_broadcast(&self, params: &[Value]) -> Result<Value> {
+    fn blockchain_transaction_broadcast(&mut self, params: &[Value]) -> Result<Value> {
         let tx = params.get(0).chain_err(|| "missing tx")?;
         let tx = tx.as_str().chain_err(|| "non-string tx")?.to_string();
         let txid = self.query.broadcast_raw(&tx)?;
-        if let Err(e) = self.chan.sender().try_send(Message::PeriodicUpdate) {
-            warn!("failed to issue PeriodicUpdate after broadcast: {}", e);
+        // the scripthashes affected by the broadcasted tx are unknown here, so check them all
+        let touched = Arc::new(TouchedScripts::All);
+        if self
+            .sender
+            .try_send(Message::PeriodicUpdate(touched))
+            .is_err()
+        {
+            warn!("failed to issue PeriodicUpdate after broadcast");
         }
         Ok(json!(txid))
     }
@@ -389,24 +523,35 @@ impl Connection {
             "merkle" : merkle}))
     }
 
-    fn handle_command(&mut self, method: &str, params: &[Value], id: &Value) -> Result<Value> {
-        let timer = self
-            .stats
-            .latency
-            .with_label_values(&[method])
-            .start_timer();
-        let result = match method {
+    // Methods that modify the connection state (everything else only reads from `Query`)
+    fn call_mut(&mut self, method: &str, params: &[Value]) -> Result<Value> {
+        match method {
+            "blockchain.headers.subscribe" => self.blockchain_headers_subscribe(),
+            "blockchain.scripthash.subscribe" => self.blockchain_scripthash_subscribe(&params),
+            "blockchain.transaction.broadcast" => self.blockchain_transaction_broadcast(&params),
+            "server.authenticate" => self.server_authenticate(&params),
+            _ => self.call(method, params),
+        }
+    }
+
+    fn call(&self, method: &str, params: &[Value]) -> Result<Value> {
+        match method {
             "blockchain.block.header" => self.blockchain_block_header(&params),
             "blockchain.block.headers" => self.blockchain_block_headers(&params),
+            #[cfg(not(feature = "liquid"))]
+            "blockchain.block.filter" => self.blockchain_block_filter(&params),
             "blockchain.estimatefee" => self.blockchain_estimatefee(&params),
-            "blockchain.headers.subscribe" => self.blockchain_headers_subscribe(),
             "blockchain.relayfee" => self.blockchain_relayfee(),
             #[cfg(not(feature = "liquid"))]
             "blockchain.scripthash.get_balance" => self.blockchain_scripthash_get_balance(&params),
             "blockchain.scripthash.get_history" => self.blockchain_scripthash_get_history(&params),
+            "blockchain.scripthash.get_history_range" => {
+                self.blockchain_scripthash_get_history_range(&params)
+            }
+            "blockchain.scripthash.get_history_by_time" => {
+                self.blockchain_scripthash_get_history_by_time(&params)
+            }
             "blockchain.scripthash.listunspent" => self.blockchain_scripthash_listunspent(&params),
-            "blockchain.scripthash.subscribe" => self.blockchain_scripthash_subscribe(&params),
-            "blockchain.transaction.broadcast" => self.blockchain_transaction_broadcast(&params),
             "blockchain.transaction.get" => self.blockchain_transaction_get(&params),
             "blockchain.transaction.get_merkle" => self.blockchain_transaction_get_merkle(&params),
             "blockchain.transaction.id_from_pos" => {
@@ -425,25 +570,110 @@ impl Connection {
             "server.add_peer" => self.server_add_peer(&params),
 
             &_ => bail!("unknown method {} {:?}", method, params),
-        };
+        }
+    }
+
+    fn rate_limit_clients(&self) -> Vec<Client> {
+        let mut clients = vec![Client::Ip(self.addr.ip())];
+        if let Some(ref api_key) = self.api_key {
+            clients.push(Client::ApiKey(api_key.clone()));
+        }
+        clients
+    }
+
+    fn handle_command(&mut self, method: &str, params: &[Value], id: &Value) -> Result<Value> {
+        let timer = self
+            .stats
+            .latency
+            .with_label_values(&[method])
+            .start_timer();
+        let clients = self.rate_limit_clients();
+        let limiter = Arc::clone(&self.limiter);
+        let result = limiter.admit(&clients, method).and_then(|()| {
+            let (result, rows) = ratelimit::measure_scanned_rows(|| self.call_mut(method, params));
+            limiter.charge(&clients, method, rows);
+            result
+        });
         timer.observe_duration();
-        // TODO: return application errors should be sent to the client
-        Ok(match result {
-            Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
-            Err(e) => {
-                warn!(
-                    "rpc #{} {} {:?} failed: {}",
-                    id,
-                    method,
-                    params,
-                    e.display_chain()
-                );
-                json!({"jsonrpc": "2.0", "id": id, "error": format!("{}", e)})
+        make_reply(method, params, id, result)
+    }
+
+    fn handle_stateless_command(
+        &self,
+        method: &str,
+        params: &[Value],
+        id: &Value,
+    ) -> Result<Value> {
+        let timer = self
+            .stats
+            .latency
+            .with_label_values(&[method])
+            .start_timer();
+        // admitted as part of the batch, and measured on the batch worker thread, which charges the
+        // rows read on its behalf by nested thread pools (see `measure_scanned_rows`)
+        let (result, rows) = ratelimit::measure_scanned_rows(|| self.call(method, params));
+        self.limiter
+            .charge_rows(&self.rate_limit_clients(), method, rows);
+        timer.observe_duration();
+        make_reply(method, params, id, result)
+    }
+
+    fn handle_batch(&mut self, cmds: &[Value]) -> Result<Value> {
+        if cmds.is_empty() || cmds.len() > self.max_batch_size {
+            let msg = format!("batch size must be between 1 and {}", self.max_batch_size);
+            return Ok(json!({"jsonrpc": "2.0", "id": Value::Null, "error": msg}));
+        }
+        // the commands are handled in order: runs of consecutive read-only commands are computed
+        // in parallel, and each stateful one is handled on its own at its position
+        let mut values = Vec::with_capacity(cmds.len());
+        let mut remaining = cmds;
+        while !remaining.is_empty() {
+            let stateless = remaining
+                .iter()
+                .take_while(|cmd| !is_stateful_command(cmd))
+                .count();
+            if stateless == 0 {
+                let (method, params, id) = parse_command(&remaining[0])?;
+                values.push(self.handle_command(method, params, id)?);
+                remaining = &remaining[1..];
+                continue;
             }
-        })
+            let (run, rest) = remaining.split_at(stateless);
+            values.extend(self.handle_stateless_commands(run)?);
+            remaining = rest;
+        }
+        Ok(Value::Array(values))
     }
 
-    fn update_subscriptions(&mut self) -> Result<Vec<Value>> {
+    fn handle_stateless_commands(&self, cmds: &[Value]) -> Result<Vec<Value>> {
+        // the commands are admitted together before any of them runs, so that they can't all
+        // spend the same remaining budget
+        let methods: Vec<&str> = cmds
+            .iter()
+            .filter_map(|cmd| parse_command(cmd).ok())
+            .map(|(method, _, _)| method)
+            .collect();
+        let admitted = self
+            .limiter
+            .reserve(&self.rate_limit_clients(), &methods)
+            .is_ok();
+        cmds.par_iter()
+            .map(|cmd| match parse_command(cmd) {
+                Ok((method, params, id)) if !admitted => {
+                    make_reply(method, params, id, Err(ErrorKind::RateLimited.into()))
+                }
+                Ok((method, params, id)) => self.handle_stateless_command(method, params, id),
+                // the id is echoed when it can be read, so that clients can match the error
+                Err(e) => Ok(json!({
+                    "jsonrpc": "2.0",
+                    "id": cmd.get("id").unwrap_or(&Value::Null),
+                    "error": format!("{}", e),
+                })),
+            })
+            .collect()
+    }
+
+    fn update_subscriptions(&mut self, touched: &TouchedScripts) -> Result<Vec<Value>> {
         let timer = self
             .stats
             .latency
@@ -463,6 +693,9 @@ impl Connection {
             }
         }
         for (script_hash, status_hash) in self.status_hashes.iter_mut() {
+            if !touched.contains(&full_hash(&script_hash[..])) {
+                continue;
+            }
             let history_txids = get_history(&self.query, &script_hash[..], self.txs_limit)?;
             let new_status_hash = get_status_hash(history_txids, &self.query)
                 .map_or(Value::Null, |h| json!(hex::encode(full_hash(&h[..]))));
@@ -479,102 +712,202 @@ impl Connection {
         Ok(result)
     }
 
-    fn send_values(&mut self, values: &[Value]) -> Result<()> {
+    fn handle_request(&mut self, line: &str) -> Result<Value> {
+        let cmd: Value = from_str(line).chain_err(|| "invalid JSON format")?;
+        if let Value::Array(ref cmds) = cmd {
+            return self.handle_batch(cmds);
+        }
+        let (method, params, id) = parse_command(&cmd)?;
+        self.handle_command(method, params, id)
+    }
+
+    async fn send_values(
+        writer: &mut WriteHalf<TcpStream>,
+        values: &[Value],
+        limits: ConnectionLimits,
+    ) -> Result<()> {
         for value in values {
             let line = value.to_string() + "\n";
-            self.stream
-                .write_all(line.as_bytes())
-                .chain_err(|| format!("failed to send {}", value))?;
+            match time::timeout(limits.idle_timeout, writer.write_all(line.as_bytes())).await {
+                Ok(res) => res.chain_err(|| format!("failed to send {}", value))?,
+                Err(_) => bail!(ErrorKind::LimitExceeded("idle_timeout")),
+            }
         }
         Ok(())
     }
 
-    fn handle_replies(&mut self) -> Result<()> {
-        let empty_params = json!([]);
-        loop {
-            let msg = self.chan.receiver().recv().chain_err(|| "channel closed")?;
+    // Requests are handled one at a time on the blocking pool, since queries may hit the DB or the daemon
+    async fn handle_replies(
+        conn: Arc<Mutex<Connection>>,
+        writer: &mut WriteHalf<TcpStream>,
+        mut receiver: mpsc::Receiver<Message>,
+        in_flight: Arc<Semaphore>,
+        limits: ConnectionLimits,
+    ) -> Result<()> {
+        while let Some(msg) = receiver.recv().await {
             trace!("RPC {:?}", msg);
-            match msg {
+            let conn = Arc::clone(&conn);
+            let values = match msg {
                 Message::Request(line) => {
-                    let cmd: Value = from_str(&line).chain_err(|| "invalid JSON format")?;
-                    let reply = match (
-                        cmd.get("method"),
-                        cmd.get("params").unwrap_or_else(|| &empty_params),
-                        cmd.get("id"),
-                    ) {
-                        (
-                            Some(&Value::String(ref method)),
-                            &Value::Array(ref params),
-                            Some(ref id),
-                        ) => self.handle_command(method, params, id)?,
-                        _ => bail!("invalid command: {}", cmd),
-                    };
-                    self.send_values(&[reply])?
-                }
-                Message::PeriodicUpdate => {
-                    let values = self
-                        .update_subscriptions()
-                        .chain_err(|| "failed to update subscriptions")?;
-                    self.send_values(&values)?
+                    let reply = task::spawn_blocking(move || {
+                        let mut conn = conn.lock().unwrap();
+                        conn.handle_request(&line)
+                    })
+                    .await
+                    .chain_err(|| "request handler panicked")?;
+                    in_flight.add_permits(1);
+                    vec![reply?]
                 }
+                Message::PeriodicUpdate(touched) => task::spawn_blocking(move || {
+                    let mut conn = conn.lock().unwrap();
+                    conn.update_subscriptions(&touched)
+                })
+                .await
+                .chain_err(|| "subscriptions update panicked")?
+                .chain_err(|| "failed to update subscriptions")?,
                 Message::Done => return Ok(()),
-            }
+            };
+            Connection::send_values(writer, &values, limits).await?;
         }
+        Ok(())
     }
 
-    fn handle_requests(mut reader: BufReader<TcpStream>, tx: SyncSender<Message>) -> Result<()> {
+    async fn read_requests(
+        reader: ReadHalf<TcpStream>,
+        sender: &mut mpsc::Sender<Message>,
+        in_flight: &Semaphore,
+        limits: ConnectionLimits,
+    ) -> Result<()> {
+        let mut reader = BufReader::new(reader);
         loop {
             let mut line = Vec::<u8>::new();
-            reader
-                .read_until(b'\n', &mut line)
-                .chain_err(|| "failed to read a request")?;
-            if line.is_empty() {
-                tx.send(Message::Done).chain_err(|| "channel closed")?;
+            // read at most one byte past the limit, to detect overly long lines
+            let read = time::timeout(
+                limits.idle_timeout,
+                (&mut reader)
+                    .take(limits.max_line_length as u64 + 1)
+                    .read_until(b'\n', &mut line),
+            )
+            .await;
+            let read = match read {
+                Ok(res) => res.chain_err(|| "failed to read a request")?,
+                Err(_) => bail!(ErrorKind::LimitExceeded("idle_timeout")),
+            };
+            if read == 0 {
                 return Ok(());
-            } else {
-                if line.starts_with(&[22, 3, 1]) {
-                    // (very) naive SSL handshake detection
-                    let _ = tx.send(Message::Done);
-                    bail!("invalid request - maybe SSL-encrypted data?: {:?}", line)
-                }
-                match String::from_utf8(line) {
-                    Ok(req) => tx
-                        .send(Message::Request(req))
-                        .chain_err(|| "channel closed")?,
-                    Err(err) => {
-                        let _ = tx.send(Message::Done);
-                        bail!("invalid UTF8: {}", err)
-                    }
-                }
+            }
+            if line.len() > limits.max_line_length {
+                bail!(ErrorKind::LimitExceeded("line_length"));
+            }
+            if line.starts_with(&[22, 3, 1]) {
+                // (very) naive SSL handshake detection
+                bail!("invalid request - maybe SSL-encrypted data?: {:?}", line)
+            }
+            let req = String::from_utf8(line).chain_err(|| "invalid UTF8")?;
+            // stop reading from the socket until a pending request is handled
+            in_flight.acquire().await.forget();
+            if sender.send(Message::Request(req)).await.is_err() {
+                bail!("request queue is closed");
             }
         }
     }
 
-    pub fn run(mut self) {
-        self.stats.clients.inc();
-        let reader = BufReader::new(self.stream.try_clone().expect("failed to clone TcpStream"));
-        let tx = self.chan.sender();
-        let child = spawn_thread("reader", || Connection::handle_requests(reader, tx));
-        if let Err(e) = self.handle_replies() {
-            error!(
-                "[{}] connection handling failed: {}",
-                self.addr,
-                e.display_chain().to_string()
-            );
+    async fn handle_requests(
+        reader: ReadHalf<TcpStream>,
+        mut sender: mpsc::Sender<Message>,
+        in_flight: Arc<Semaphore>,
+        limits: ConnectionLimits,
+        stats: Arc<Stats>,
+        addr: SocketAddr,
+    ) {
+        if let Err(e) = Connection::read_requests(reader, &mut sender, &in_flight, limits).await {
+            stats.report_failure(addr, "receiver failed", &e);
+        }
+        let _ = sender.send(Message::Done).await;
+    }
+
+    pub async fn run(self, stream: TcpStream, receiver: mpsc::Receiver<Message>) {
+        let addr = self.addr;
+        let limits = self.limits;
+        let stats = Arc::clone(&self.stats);
+        let in_flight = Arc::new(Semaphore::new(limits.max_in_flight));
+        stats.clients.inc();
+
+        let (reader, mut writer) = split(stream);
+        tokio::spawn(Connection::handle_requests(
+            reader,
+            self.sender.clone(),
+            Arc::clone(&in_flight),
+            limits,
+            Arc::clone(&stats),
+            addr,
+        ));
+
+        let conn = Arc::new(Mutex::new(self));
+        if let Err(e) =
+            Connection::handle_replies(Arc::clone(&conn), &mut writer, receiver, in_flight, limits)
+                .await
+        {
+            stats.report_failure(addr, "connection handling failed", &e);
         }
-        self.stats.clients.dec();
-        self.stats
+        stats.clients.dec();
+        stats
             .subscriptions
-            .sub(self.status_hashes.len() as i64);
+            .sub(conn.lock().unwrap().status_hashes.len() as i64);
 
-        debug!("[{}] shutting down connection", self.addr);
-        let _ = self.stream.shutdown(Shutdown::Both);
-        if let Err(err) = child.join().expect("receiver panicked") {
-            error!("[{}] receiver failed: {}", self.addr, err);
+        debug!("[{}] shutting down connection", addr);
+        let _ = writer.shutdown().await;
+    }
+}
+
+fn parse_command(cmd: &Value) -> Result<(&str, &[Value], &Value)> {
+    match (cmd.get("method"), cmd.get("params"), cmd.get("id")) {
+        (Some(Value::String(method)), Some(Value::Array(params)), Some(id)) => {
+            Ok((method.as_str(), params.as_slice(), id))
         }
+        (Some(Value::String(method)), None, Some(id)) => Ok((method.as_str(), &[], id)),
+        _ => bail!("invalid command: {}", cmd),
     }
 }
 
+// Commands that fail to parse are not stateful, and get an error reply
+fn is_stateful_command(cmd: &Value) -> bool {
+    parse_command(cmd).map_or(false, |(method, _, _)| is_stateful(method))
+}
+
+fn is_stateful(method: &str) -> bool {
+    match method {
+        "blockchain.headers.subscribe"
+        | "blockchain.scripthash.subscribe"
+        | "blockchain.transaction.broadcast"
+        | "server.authenticate" => true,
+        _ => false,
+    }
+}
+
+fn make_reply(method: &str, params: &[Value], id: &Value, result: Result<Value>) -> Result<Value> {
+    // limit violations close the connection instead of being reported to the client
+    if let Err(ref e) = result {
+        if let ErrorKind::LimitExceeded(_) = e.kind() {
+            return result;
+        }
+    }
+    // TODO: return application errors should be sent to the client
+    Ok(match result {
+        Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+        Err(e) => {
+            warn!(
+                "rpc #{} {} {:?} failed: {}",
+                id,
+                method,
+                params,
+                e.display_chain()
+            );
+            json!({"jsonrpc": "2.0", "id": id, "error": format!("{}", e)})
+        }
+    })
+}
+
 fn get_history(
     query: &Query,
     scripthash: &[u8],
@@ -598,12 +931,12 @@ struct GetHistoryResult {
 #[derive(Debug)]
 pub enum Message {
     Request(String),
-    PeriodicUpdate,
+    PeriodicUpdate(Arc<TouchedScripts>),
     Done,
 }
 
 pub enum Notification {
-    Periodic,
+    Periodic(TouchedScripts),
     Exit,
 }
 
@@ -616,58 +949,66 @@ struct Stats {
     latency: HistogramVec,
     clients: Gauge,
     subscriptions: Gauge,
+    violations: CounterVec,
 }
 
+impl Stats {
+    fn report_failure(&self, addr: SocketAddr, what: &str, e: &Error) {
+        if let ErrorKind::LimitExceeded(limit) = e.kind() {
+            self.violations.with_label_values(&[*limit]).inc();
+        }
+        error!("[{}] {}: {}", addr, what, e.display_chain().to_string());
+    }
+}
+
+// The message sender of an open connection, and whether it missed the last periodic update
+struct ConnectionSender {
+    sender: mpsc::Sender<Message>,
+    lagging: bool,
+}
+
+// The message senders of all open connections, keyed by a per-connection id
+type Senders = Arc<Mutex<HashMap<usize, ConnectionSender>>>;
+
 impl RPC {
     fn start_notifier(
         notification: Channel<Notification>,
-        senders: Arc<Mutex<Vec<SyncSender<Message>>>>,
-        acceptor: Sender<Option<(TcpStream, SocketAddr)>>,
+        senders: Senders,
+        shutdown: oneshot::Sender<()>,
     ) {
         spawn_thread("notification", move || {
+            let mut shutdown = Some(shutdown);
             for msg in notification.receiver().iter() {
-                let mut senders = senders.lock().unwrap();
                 match msg {
-                    Notification::Periodic => {
-                        for sender in senders.split_off(0) {
-                            if let Err(TrySendError::Disconnected(_)) =
-                                sender.try_send(Message::PeriodicUpdate)
-                            {
-                                continue;
-                            }
-                            senders.push(sender);
+                    Notification::Periodic(touched) => {
+                        let touched = Arc::new(touched);
+                        let all = Arc::new(TouchedScripts::All);
+                        for conn in senders.lock().unwrap().values_mut() {
+                            // the touched scripts only cover the changes since the previous
+                            // update, so connections that missed one have to check them all
+                            let update = if conn.lagging { &all } else { &touched };
+                            conn.lagging = conn
+                                .sender
+                                .try_send(Message::PeriodicUpdate(Arc::clone(update)))
+                                .is_err();
+                        }
+                    }
+                    Notification::Exit => {
+                        if let Some(shutdown) = shutdown.take() {
+                            let _ = shutdown.send(());
                         }
                     }
-                    Notification::Exit => acceptor.send(None).unwrap(), // mark acceptor as done
                 }
             }
         });
     }
 
-    fn start_acceptor(addr: SocketAddr) -> Channel<Option<(TcpStream, SocketAddr)>> {
-        let chan = Channel::unbounded();
-        let acceptor = chan.sender();
-        spawn_thread("acceptor", move || {
-            let socket = create_socket(&addr);
-            socket.listen(511).expect("setting backlog failed");
-            socket
-                .set_nonblocking(false)
-                .expect("cannot set nonblocking to false");
-            let listener = socket.into_tcp_listener();
-
-            info!("Electrum RPC server running on {}", addr);
-            loop {
-                let (stream, addr) = listener.accept().expect("accept failed");
-                stream
-                    .set_nonblocking(false)
-                    .expect("failed to set connection as blocking");
-                acceptor.send(Some((stream, addr))).expect("send failed");
-            }
-        });
-        chan
-    }
-
-    pub fn start(config: Arc<Config>, query: Arc<Query>, metrics: &Metrics) -> RPC {
+    pub fn start(
+        config: Arc<Config>,
+        query: Arc<Query>,
+        metrics: &Metrics,
+        limiter: Arc<RateLimiter>,
+    ) -> RPC {
         let stats = Arc::new(Stats {
             latency: metrics.histogram_vec(
                 HistogramOpts::new("electrum_rpc", "Electrum RPC latency (seconds)"),
@@ -678,11 +1019,19 @@ impl RPC {
                 "electrum_subscriptions",
                 "# of Electrum subscriptions",
             )),
+            violations: metrics.counter_vec(
+                MetricOpts::new(
+                    "electrum_limit_violations",
+                    "# of Electrum connections closed or rejected due to resource limits",
+                ),
+                &["limit"],
+            ),
         });
         stats.clients.set(0);
         stats.subscriptions.set(0);
 
         let notification = Channel::unbounded();
+        let notification_sender = notification.sender();
 
         // Discovery is enabled when electrum-public-hosts is set
         #[cfg(feature = "electrum-discovery")]
@@ -707,78 +1056,120 @@ impl RPC {
             discovery
         });
 
-        let rpc_addr = config.electrum_rpc_addr;
-        let txs_limit = config.electrum_txs_limit;
+        let (shutdown_sender, shutdown_receiver) = oneshot::channel();
+        let senders: Senders = Arc::new(Mutex::new(HashMap::new()));
+        RPC::start_notifier(notification, Arc::clone(&senders), shutdown_sender);
 
         RPC {
-            notification: notification.sender(),
+            notification: notification_sender,
             server: Some(spawn_thread("rpc", move || {
-                let senders = Arc::new(Mutex::new(Vec::<SyncSender<Message>>::new()));
-
-                let acceptor = RPC::start_acceptor(rpc_addr);
-                RPC::start_notifier(notification, senders.clone(), acceptor.sender());
-
-                let mut threads = HashMap::new();
-                let (garbage_sender, garbage_receiver) = crossbeam_channel::unbounded();
-
-                while let Some((stream, addr)) = acceptor.receiver().recv().unwrap() {
-                    // explicitely scope the shadowed variables for the new thread
-                    let query = Arc::clone(&query);
-                    let senders = Arc::clone(&senders);
-                    let stats = Arc::clone(&stats);
-                    let garbage_sender = garbage_sender.clone();
+                run_server(
+                    config,
+                    query,
+                    stats,
+                    senders,
+                    limiter,
                     #[cfg(feature = "electrum-discovery")]
-                    let discovery = discovery.clone();
-
-                    let spawned = spawn_thread("peer", move || {
-                        info!("[{}] connected peer", addr);
-                        let conn = Connection::new(
-                            query,
-                            stream,
-                            addr,
-                            stats,
-                            txs_limit,
-                            #[cfg(feature = "electrum-discovery")]
-                            discovery,
-                        );
-                        senders.lock().unwrap().push(conn.chan.sender());
-                        conn.run();
-                        info!("[{}] disconnected peer", addr);
-                        let _ = garbage_sender.send(std::thread::current().id());
-                    });
-
-                    trace!("[{}] spawned {:?}", addr, spawned.thread().id());
-                    threads.insert(spawned.thread().id(), spawned);
-                    while let Ok(id) = garbage_receiver.try_recv() {
-                        if let Some(thread) = threads.remove(&id) {
-                            trace!("[{}] joining {:?}", addr, id);
-                            if let Err(error) = thread.join() {
-                                error!("failed to join {:?}: {:?}", id, error);
-                            }
-                        }
-                    }
-                }
+                    discovery,
+                    shutdown_receiver,
+                );
+                trace!("RPC connections are closed");
+            })),
+        }
+    }
 
-                trace!("closing {} RPC connections", senders.lock().unwrap().len());
-                for sender in senders.lock().unwrap().iter() {
-                    let _ = sender.send(Message::Done);
-                }
+    pub fn notify(&self, touched: TouchedScripts) {
+        self.notification
+            .send(Notification::Periodic(touched))
+            .unwrap();
+    }
+}
 
-                for (id, thread) in threads {
-                    trace!("joining {:?}", id);
-                    if let Err(error) = thread.join() {
-                        error!("failed to join {:?}: {:?}", id, error);
+#[tokio::main]
+async fn run_server(
+    config: Arc<Config>,
+    query: Arc<Query>,
+    stats: Arc<Stats>,
+    senders: Senders,
+    limiter: Arc<RateLimiter>,
+    #[cfg(feature = "electrum-discovery")] discovery: Option<Arc<DiscoveryManager>>,
+    shutdown: oneshot::Receiver<()>,
+) {
+    let rpc_addr = config.electrum_rpc_addr;
+    let txs_limit = config.electrum_txs_limit;
+    let max_batch_size = config.electrum_max_batch_size;
+    let max_connections = config.electrum_max_connections;
+    let limits = ConnectionLimits::from(&*config);
+
+    let socket = create_socket(&rpc_addr);
+    socket.listen(511).expect("setting backlog failed");
+    socket
+        .set_nonblocking(true)
+        .expect("cannot set nonblocking to true");
+    let mut listener =
+        TcpListener::from_std(socket.into_tcp_listener()).expect("TcpListener::from_std failed");
+    info!("Electrum RPC server running on {}", rpc_addr);
+
+    let connections = Arc::new(AtomicUsize::new(0));
+    tokio::spawn({
+        let senders = Arc::clone(&senders);
+        async move {
+            let mut next_id = 0;
+            loop {
+                let (stream, addr) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        warn!("accept failed: {}", e);
+                        continue;
                     }
+                };
+                if connections.load(Ordering::SeqCst) >= max_connections {
+                    stats.violations.with_label_values(&["connections"]).inc();
+                    warn!("[{}] rejected peer: too many connections", addr);
+                    continue; // dropping the stream closes it
                 }
+                connections.fetch_add(1, Ordering::SeqCst);
 
-                trace!("RPC connections are closed");
-            })),
+                let id = next_id;
+                next_id += 1;
+                let (sender, receiver) =
+                    mpsc::channel(limits.max_in_flight + NOTIFICATIONS_BACKLOG);
+                senders.lock().unwrap().insert(
+                    id,
+                    ConnectionSender {
+                        sender: sender.clone(),
+                        lagging: false,
+                    },
+                );
+                let conn = Connection::new(
+                    Arc::clone(&query),
+                    addr,
+                    sender,
+                    Arc::clone(&stats),
+                    txs_limit,
+                    max_batch_size,
+                    limits,
+                    Arc::clone(&limiter),
+                    #[cfg(feature = "electrum-discovery")]
+                    discovery.clone(),
+                );
+
+                let senders = Arc::clone(&senders);
+                let connections = Arc::clone(&connections);
+                tokio::spawn(async move {
+                    info!("[{}] connected peer", addr);
+                    conn.run(stream, receiver).await;
+                    senders.lock().unwrap().remove(&id);
+                    connections.fetch_sub(1, Ordering::SeqCst);
+                    info!("[{}] disconnected peer", addr);
+                });
+            }
         }
-    }
+    });
 
-    pub fn notify(&self) {
-        self.notification.send(Notification::Periodic).unwrap();
-    }
+    shutdown.await.ok();
+    // dropping the runtime cancels all the tasks, closing the remaining connections
+    trace!("closing {} RPC connections", senders.lock().unwrap().len());
 }
 
 impl Drop for RPC {
@@ -19,6 +19,21 @@ error_chain! {
             display("Too many history entries")
         }
 
+        LimitExceeded(limit: &'static str) {
+            description("Connection resource limit exceeded")
+            display("Connection resource limit exceeded: {}", limit)
+        }
+
+        RateLimited {
+            description("Rate limit exceeded")
+            display("Rate limit exceeded")
+        }
+
+        RpcError(method: String, code: i64, message: String) {
+            description("Daemon RPC error")
+            display("{} RPC error: {}", method, json!({"code": code, "message": message}))
+        }
+
         #[cfg(feature = "electrum-discovery")]
         ElectrumClient(e: electrum_client::Error) {
             description("Electrum client error")
@@ -22,6 +22,7 @@ extern crate serde_json;
 #[macro_use]
 extern crate lazy_static;
 
+pub mod apikeys;
 pub mod chain;
 pub mod config;
 pub mod daemon;
@@ -29,9 +30,11 @@ pub mod electrum;
 pub mod errors;
 pub mod metrics;
 pub mod new_index;
+pub mod ratelimit;
 pub mod rest;
 pub mod signal;
 pub mod util;
+pub mod webhooks;
 
 #[cfg(feature = "liquid")]
 pub mod elements;
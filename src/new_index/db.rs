@@ -3,6 +3,7 @@ use rocksdb;
 use std::path::Path;
 
 use crate::config::Config;
+use crate::ratelimit;
 use crate::util::Bytes;
 
 static DB_VERSION: u32 = 1;
@@ -31,6 +32,7 @@ impl<'a> Iterator for ScanIterator<'a> {
             self.done = true;
             return None;
         }
+        ratelimit::record_scanned_row();
         Some(DBRow {
             key: key.to_vec(),
             value: value.to_vec(),
@@ -58,6 +60,7 @@ impl<'a> Iterator for ReverseScanIterator<'a> {
             return None;
         }
 
+        ratelimit::record_scanned_row();
         let row = DBRow {
             key: key.into(),
             value: self.iter.value().unwrap().into(),
@@ -192,6 +195,10 @@ impl DB {
         self.db.put_opt(key, value, &opts).unwrap();
     }
 
+    pub fn delete(&self, key: &[u8]) {
+        self.db.delete(key).unwrap();
+    }
+
     pub fn get(&self, key: &[u8]) -> Option<Bytes> {
         self.db.get(key).unwrap().map(|v| v.to_vec())
     }
@@ -1,50 +1,75 @@
 use arraydeque::{ArrayDeque, Wrapping};
-use bitcoin::consensus::encode::deserialize;
-use bitcoin::Txid;
+use bitcoin::{BlockHash, Txid};
 use itertools::Itertools;
 
 #[cfg(not(feature = "liquid"))]
-use bitcoin::consensus::encode::serialize;
+use bitcoin::consensus::encode::{deserialize, serialize};
 #[cfg(feature = "liquid")]
-use elements::{encode::serialize, AssetId};
+use elements::{
+    encode::{deserialize, serialize},
+    AssetId,
+};
 
-use std::collections::{BTreeSet, HashMap, HashSet};
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::fs;
 use std::iter::FromIterator;
+use std::path::Path;
 use std::sync::Arc;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use crate::chain::{Network, OutPoint, Transaction, TxOut};
 use crate::config::Config;
 use crate::daemon::Daemon;
 use crate::errors::*;
-use crate::metrics::{GaugeVec, HistogramOpts, HistogramVec, MetricOpts, Metrics};
+use crate::metrics::{
+    CounterVec, GaugeVec, Histogram, HistogramOpts, HistogramVec, MetricOpts, Metrics,
+};
 use crate::new_index::{
-    compute_script_hash, schema::FullHash, ChainQuery, FundingInfo, ScriptStats, SpendingInfo,
-    SpendingInput, TxHistoryInfo, Utxo,
+    compute_script_hash, schema::FullHash, ChainQuery, Event, FundingInfo, ScriptStats,
+    SpendingInfo, SpendingInput, TouchedScripts, TxHistoryInfo, Utxo,
 };
 use crate::util::fees::{make_fee_histogram, TxFeeInfo};
-use crate::util::{extract_tx_prevouts, full_hash, has_prevout, is_spendable, Bytes};
+use crate::util::{extract_tx_prevouts, full_hash, has_prevout, is_spendable, BlockId, Bytes};
 
 #[cfg(feature = "liquid")]
 use crate::elements::asset;
 
 const RECENT_TXS_SIZE: usize = 10;
 const BACKLOG_STATS_TTL: u64 = 10;
+const RBF_HISTORY_SIZE: usize = 10_000;
+const DROPPED_TXS_SIZE: usize = 10_000;
+// Ancestor/descendant traversals stop after this many txs (the daemon's default limit is 25)
+const MAX_PACKAGE_TXS: usize = 100;
+const PROJECTED_BLOCKS: usize = 8; // the last projected block holds all the remaining txs
+const PROJECTED_BLOCK_VSIZE: u32 = 1_000_000; // in virtual bytes (= max block weight/4)
+const MEMPOOL_DUMP_VERSION: u32 = 1;
 
 pub struct Mempool {
     chain: Arc<ChainQuery>,
     config: Arc<Config>,
     txstore: HashMap<Txid, Transaction>,
     feeinfo: HashMap<Txid, TxFeeInfo>,
+    // When the txs entered the mempool (in unix seconds), saved to the db once they confirm
+    first_seen: HashMap<Txid, u64>,
     history: HashMap<FullHash, Vec<TxHistoryInfo>>, // ScriptHash -> {history_entries}
     edges: HashMap<OutPoint, (Txid, u32)>,          // OutPoint -> (spending_txid, spending_vin)
     recent: ArrayDeque<[TxOverview; RECENT_TXS_SIZE], Wrapping>, // The N most recent txs to enter the mempool
     backlog_stats: (BacklogStats, Instant),
+    projected_blocks: (ProjectedBlocks, Instant),
+    touched: TouchedScripts, // ScriptHashes affected since the last take_touched()
+    rbf: RbfHistory,         // The most recent replacements
+    dropped: DroppedTxs,     // The most recent txs to leave the mempool without confirming
+    // Txs loaded from the last dump (with their first seen time), added by the next update()
+    // if they're still in the daemon's mempool
+    loaded: HashMap<Txid, (Transaction, u64)>,
+    events: Vec<Event>, // for the streaming clients
 
     // monitoring
-    latency: HistogramVec, // mempool requests latency
-    delta: HistogramVec,   // # of added/removed txs
-    count: GaugeVec,       // current state of the mempool
+    latency: HistogramVec,           // mempool requests latency
+    delta: HistogramVec,             // # of added/removed txs
+    count: GaugeVec,                 // current state of the mempool
+    confirmation_latency: Histogram, // time from first seen to the confirming block
+    removed: CounterVec,             // # of removed txs, by removal reason
 
     // elements only
     #[cfg(feature = "liquid")]
@@ -54,11 +79,13 @@ pub struct Mempool {
 }
 
 // A simplified transaction view used for the list of most recent transactions
-#[derive(Serialize)]
+#[derive(Serialize, Clone)]
 pub struct TxOverview {
-    txid: Txid,
+    pub txid: Txid,
     fee: u64,
     vsize: u32,
+    effective_feerate: f32,
+    first_seen: u64,
     #[cfg(not(feature = "liquid"))]
     value: u64,
 }
@@ -70,6 +97,7 @@ impl Mempool {
             config,
             txstore: HashMap::new(),
             feeinfo: HashMap::new(),
+            first_seen: HashMap::new(),
             history: HashMap::new(),
             edges: HashMap::new(),
             recent: ArrayDeque::new(),
@@ -77,6 +105,15 @@ impl Mempool {
                 BacklogStats::default(),
                 Instant::now() - Duration::from_secs(BACKLOG_STATS_TTL),
             ),
+            projected_blocks: (
+                ProjectedBlocks::default(),
+                Instant::now() - Duration::from_secs(BACKLOG_STATS_TTL),
+            ),
+            touched: TouchedScripts::empty(),
+            rbf: RbfHistory::default(),
+            dropped: DroppedTxs::default(),
+            loaded: HashMap::new(),
+            events: vec![],
             latency: metrics.histogram_vec(
                 HistogramOpts::new("mempool_latency", "Mempool requests latency (in seconds)"),
                 &["part"],
@@ -89,6 +126,19 @@ impl Mempool {
                 MetricOpts::new("mempool_count", "# of elements currently at the mempool"),
                 &["type"],
             ),
+            confirmation_latency: metrics.histogram(
+                HistogramOpts::new(
+                    "mempool_confirmation_latency",
+                    "Time from first seen in the mempool to confirmation (in seconds)",
+                )
+                .buckets(vec![
+                    10.0, 30.0, 60.0, 300.0, 600.0, 1800.0, 3600.0, 7200.0, 21600.0, 86400.0,
+                ]),
+            ),
+            removed: metrics.counter_vec(
+                MetricOpts::new("mempool_removed", "# of txs removed from the mempool"),
+                &["reason"],
+            ),
 
             #[cfg(feature = "liquid")]
             asset_history: HashMap::new(),
@@ -125,6 +175,38 @@ impl Mempool {
         Some(self.feeinfo.get(txid)?.fee)
     }
 
+    /// Returns the time (in unix seconds) the tx was first seen in the mempool.
+    pub fn first_seen(&self, txid: &Txid) -> Option<u64> {
+        self.first_seen.get(txid).cloned()
+    }
+
+    /// Returns why and when the tx left the mempool, if it was recently dropped without confirming.
+    pub fn dropped(&self, txid: &Txid) -> Option<&DroppedTx> {
+        self.dropped.txs.get(txid).map(|(_, dropped)| dropped)
+    }
+
+    pub fn lookup_dropped_txn(&self, txid: &Txid) -> Option<Transaction> {
+        self.dropped.txs.get(txid).map(|(tx, _)| tx.clone())
+    }
+
+    /// Returns the replacement of the given (no longer in the mempool) tx, if it was replaced.
+    pub fn replaced_by(&self, txid: &Txid) -> Option<&Replacement> {
+        self.rbf.replaced_by.get(txid)
+    }
+
+    /// Returns the replacements of txs by the given tx.
+    pub fn replaces(&self, txid: &Txid) -> Vec<&Replacement> {
+        self.rbf
+            .replaces
+            .get(txid)
+            .map_or_else(Vec::new, |replaced| {
+                replaced
+                    .iter()
+                    .filter_map(|replaced_txid| self.rbf.replaced_by.get(replaced_txid))
+                    .collect()
+            })
+    }
+
     pub fn has_unconfirmed_parents(&self, txid: &Txid) -> bool {
         let tx = match self.txstore.get(txid) {
             Some(tx) => tx,
@@ -278,6 +360,29 @@ impl Mempool {
         &self.backlog_stats.0
     }
 
+    pub fn projected_blocks(&self) -> &[ProjectedBlock] {
+        &self.projected_blocks.0.blocks
+    }
+
+    /// Returns where the tx is expected to be mined, as of the last projection.
+    pub fn projected_position(&self, txid: &Txid) -> Option<&ProjectedPosition> {
+        if !self.txstore.contains_key(txid) {
+            return None;
+        }
+        self.projected_blocks.0.positions.get(txid)
+    }
+
+    /// Returns the scripthashes whose mempool history changed since the last call.
+    pub fn take_touched(&mut self) -> TouchedScripts {
+        std::mem::replace(&mut self.touched, TouchedScripts::empty())
+    }
+
+    /// Returns the added and removed txs, their script activity and the refreshed fee
+    /// histogram since the last call.
+    pub fn take_events(&mut self) -> Vec<Event> {
+        std::mem::replace(&mut self.events, vec![])
+    }
+
     pub fn update(&mut self, daemon: &Daemon) -> Result<()> {
         let _timer = self.latency.with_label_values(&["update"]).start_timer();
         let new_txids = daemon
@@ -286,15 +391,34 @@ impl Mempool {
         let old_txids = HashSet::from_iter(self.txstore.keys().cloned());
         let to_remove: HashSet<&Txid> = old_txids.difference(&new_txids).collect();
 
-        // Download and add new transactions from bitcoind's mempool
-        let txids: Vec<&Txid> = new_txids.difference(&old_txids).collect();
-        let to_add = match daemon.gettransactions(&txids) {
+        // Download and add new transactions from bitcoind's mempool (unless loaded from the dump)
+        let loaded = &self.loaded;
+        let txids: Vec<&Txid> = new_txids
+            .difference(&old_txids)
+            .filter(|txid| !loaded.contains_key(txid))
+            .collect();
+        let downloaded = match daemon.gettransactions(&txids) {
             Ok(txs) => txs,
             Err(err) => {
                 warn!("failed to get transactions {:?}: {}", txids, err); // e.g. new block or RBF
                 return Ok(()); // keep the mempool until next update()
             }
         };
+        // The loaded txs go first, in the order they were first seen, so that the most
+        // recent txs overview is restored as well
+        let mut loaded: Vec<(Transaction, u64)> = self
+            .loaded
+            .drain()
+            .filter(|(txid, _)| new_txids.contains(txid))
+            .map(|(_, loaded)| loaded)
+            .collect();
+        loaded.sort_unstable_by_key(|(_, first_seen)| *first_seen);
+        let mut to_add = vec![];
+        for (tx, first_seen) in loaded {
+            self.first_seen.insert(tx.txid(), first_seen);
+            to_add.push(tx);
+        }
+        to_add.extend(downloaded);
         // Add new transactions
         self.add(to_add);
         // Remove missing transactions
@@ -311,11 +435,52 @@ impl Mempool {
                 .with_label_values(&["update_backlog_stats"])
                 .start_timer();
             self.backlog_stats = (BacklogStats::new(&self.feeinfo), Instant::now());
+            let fee_histogram = self.backlog_stats.0.fee_histogram.clone();
+            self.events.push(Event::FeeHistogram(fee_histogram));
+        }
+
+        // Update cached projected blocks (if expired)
+        if self.projected_blocks.1.elapsed() > Duration::from_secs(BACKLOG_STATS_TTL) {
+            let _timer = self
+                .latency
+                .with_label_values(&["update_projected_blocks"])
+                .start_timer();
+            self.projected_blocks = (
+                ProjectedBlocks::new(&self.txstore, &self.feeinfo),
+                Instant::now(),
+            );
         }
 
         Ok(())
     }
 
+    /// Saves the mempool txs and their first seen times, to be loaded on the next start.
+    pub fn dump(&self, path: &Path) -> Result<()> {
+        let _timer = self.latency.with_label_values(&["dump"]).start_timer();
+        let dump = MempoolDump::new(
+            self.txstore
+                .iter()
+                .map(|(txid, tx)| (tx, self.first_seen[txid])),
+        );
+        dump.write(path)?;
+        info!("saved {} mempool txs to {:?}", dump.txs.len(), path);
+        Ok(())
+    }
+
+    /// Loads the mempool saved by dump(). The loaded txs are reconciled against the daemon's
+    /// mempool by the next update(), which only downloads the txs missing from the dump.
+    pub fn load(&mut self, path: &Path) -> Result<()> {
+        if !path.exists() {
+            return Ok(());
+        }
+        let _timer = self.latency.with_label_values(&["load"]).start_timer();
+        for (tx, first_seen) in MempoolDump::read(path)? {
+            self.loaded.insert(tx.txid(), (tx, first_seen));
+        }
+        info!("loaded {} mempool txs from {:?}", self.loaded.len(), path);
+        Ok(())
+    }
+
     pub fn add_by_txid(&mut self, daemon: &Daemon, txid: &Txid) {
         if self.txstore.get(txid).is_none() {
             if let Ok(tx) = daemon.getmempooltx(&txid) {
@@ -329,6 +494,10 @@ impl Mempool {
             .with_label_values(&["add"])
             .observe(txs.len() as f64);
         let _timer = self.latency.with_label_values(&["add"]).start_timer();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
 
         let mut txids = vec![];
         // Phase 1: add to txstore
@@ -336,7 +505,10 @@ impl Mempool {
             let txid = tx.txid();
             txids.push(txid);
             self.txstore.insert(txid, tx);
+            self.first_seen.entry(txid).or_insert(now);
+            self.dropped.forget(&txid);
         }
+        let added = txids.clone();
         // Phase 2: index history and spend edges (can fail if some txos cannot be found)
         let txos = match self.lookup_txos(&self.get_prevouts(&txids)) {
             Ok(txos) => txos,
@@ -346,6 +518,7 @@ impl Mempool {
                 return;
             }
         };
+        let mut overviews = vec![];
         for txid in txids {
             let tx = self.txstore.get(&txid).expect("missing mempool tx");
             let txid_bytes = full_hash(&txid[..]);
@@ -354,11 +527,12 @@ impl Mempool {
             // Get feeinfo for caching and recent tx overview
             let feeinfo = TxFeeInfo::new(&tx, &prevouts, self.config.network_type);
 
-            // recent is an ArrayDeque that automatically evicts the oldest elements
-            self.recent.push_front(TxOverview {
+            overviews.push(TxOverview {
                 txid,
                 fee: feeinfo.fee,
                 vsize: feeinfo.vsize,
+                effective_feerate: feeinfo.effective_fee_per_vbyte,
+                first_seen: self.first_seen[&txid],
                 #[cfg(not(feature = "liquid"))]
                 value: prevouts.values().map(|prevout| prevout.value).sum(),
             });
@@ -400,14 +574,43 @@ impl Mempool {
                 });
 
             // Index funding/spending history entries and spend edges
+            let mut scripthashes = HashSet::new();
             for (scripthash, entry) in funding.chain(spending) {
+                self.touched.insert(scripthash);
+                if scripthashes.insert(scripthash) {
+                    self.events.push(Event::ScriptActivity {
+                        scripthash,
+                        txid,
+                        confirmed: false,
+                    });
+                }
                 self.history
                     .entry(scripthash)
                     .or_insert_with(Vec::new)
                     .push(entry);
             }
+            // A conflicting spend means the previous spender was replaced. It is only
+            // removed from the mempool later on, as it's missing from the daemon's mempool.
+            let mut replaced = HashSet::new();
             for (i, txi) in tx.input.iter().enumerate() {
-                self.edges.insert(txi.previous_output, (txid, i as u32));
+                if let Some((spending_txid, _)) =
+                    self.edges.insert(txi.previous_output, (txid, i as u32))
+                {
+                    if spending_txid != txid {
+                        replaced.insert(spending_txid);
+                    }
+                }
+            }
+            self.rbf.forget(&txid);
+            let feeinfos = &self.feeinfo;
+            let fee = |txid: &Txid| feeinfos.get(txid).map_or(0, |info| info.fee as i64);
+            for replaced_txid in replaced {
+                self.rbf.insert(Replacement {
+                    txid,
+                    replaced_txid,
+                    fee_delta: fee(&txid) - fee(&replaced_txid),
+                    time: now,
+                });
             }
 
             // Index issued assets & native asset pegins/pegouts/burns
@@ -420,6 +623,22 @@ impl Mempool {
                 &mut self.asset_issuance,
             );
         }
+
+        // Phase 3: update the effective feerates of the affected packages
+        self.update_effective_feerates(&added);
+        for mut overview in overviews {
+            if let Some(feeinfo) = self.feeinfo.get(&overview.txid) {
+                overview.effective_feerate = feeinfo.effective_fee_per_vbyte;
+            }
+            self.events.push(Event::MempoolTx(overview.clone()));
+            // recent is an ArrayDeque that automatically evicts the oldest elements
+            self.recent.push_front(overview);
+        }
+        for overview in self.recent.iter_mut() {
+            if let Some(feeinfo) = self.feeinfo.get(&overview.txid) {
+                overview.effective_feerate = feeinfo.effective_fee_per_vbyte;
+            }
+        }
     }
 
     pub fn lookup_txo(&self, outpoint: &OutPoint) -> Result<TxOut> {
@@ -440,9 +659,7 @@ impl Mempool {
             .iter()
             .filter(|outpoint| !confirmed_txos.contains_key(outpoint))
             .map(|outpoint| {
-                self.txstore
-                    .get(&outpoint.txid)
-                    .and_then(|tx| tx.output.get(outpoint.vout as usize).cloned())
+                self.lookup_unconfirmed_txo(outpoint)
                     .map(|txout| (*outpoint, txout))
                     .chain_err(|| format!("missing outpoint {:?}", outpoint))
             })
@@ -453,6 +670,36 @@ impl Mempool {
         Ok(txos)
     }
 
+    /// Like lookup_txos(), but skips the outpoints that can't be found (e.g. the outputs of
+    /// dropped txs that are no longer kept).
+    pub fn lookup_avail_txos(&self, outpoints: &BTreeSet<OutPoint>) -> HashMap<OutPoint, TxOut> {
+        let _timer = self
+            .latency
+            .with_label_values(&["lookup_avail_txos"])
+            .start_timer();
+
+        let mut txos = self.chain.lookup_avail_txos(outpoints);
+        let mempool_txos: Vec<(OutPoint, TxOut)> = outpoints
+            .iter()
+            .filter(|outpoint| !txos.contains_key(outpoint))
+            .filter_map(|outpoint| {
+                self.lookup_unconfirmed_txo(outpoint)
+                    .map(|txout| (*outpoint, txout))
+            })
+            .collect();
+        txos.extend(mempool_txos);
+        txos
+    }
+
+    // Unconfirmed outputs are looked up in the mempool, and in the recently dropped txs
+    // (which may still be the parents of other dropped txs)
+    fn lookup_unconfirmed_txo(&self, outpoint: &OutPoint) -> Option<TxOut> {
+        self.txstore
+            .get(&outpoint.txid)
+            .or_else(|| self.dropped.txs.get(&outpoint.txid).map(|(tx, _)| tx))
+            .and_then(|tx| tx.output.get(outpoint.vout as usize).cloned())
+    }
+
     fn get_prevouts(&self, txids: &[Txid]) -> BTreeSet<OutPoint> {
         let _timer = self
             .latency
@@ -476,20 +723,104 @@ impl Mempool {
             .with_label_values(&["remove"])
             .observe(to_remove.len() as f64);
         let _timer = self.latency.with_label_values(&["remove"]).start_timer();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        // The feerates of the ancestors may no longer be boosted by the removed txs, so they're
+        // collected while the removed txs are still linked to them
+        let ancestors: HashSet<Txid> = to_remove
+            .iter()
+            .flat_map(|txid| self.ancestors(txid))
+            .collect();
+
+        // Classify the removals while the removed txs are still linked to each other
+        let confirming_blocks: HashMap<Txid, BlockId> = to_remove
+            .iter()
+            .filter_map(|txid| Some((**txid, self.chain.tx_confirming_block(txid)?)))
+            .collect();
+        let chain = &self.chain;
+        let mut drop_reasons = drop_reasons(
+            &self.txstore,
+            &self.rbf,
+            &self.dropped,
+            &to_remove,
+            &confirming_blocks,
+            |outpoint| chain.lookup_spend(outpoint),
+        );
 
+        // The status of mempool children changes too (their parents are no longer unconfirmed)
+        let mut affected: HashSet<Txid> = to_remove.iter().map(|txid| **txid).collect();
+        let mut confirmed = vec![];
         for txid in &to_remove {
-            self.txstore
+            let tx = self
+                .txstore
                 .remove(*txid)
                 .unwrap_or_else(|| panic!("missing mempool tx {}", txid));
+            for vout in 0..tx.output.len() {
+                let outpoint = OutPoint {
+                    txid: **txid,
+                    vout: vout as u32,
+                };
+                if let Some((child_txid, _)) = self.edges.get(&outpoint) {
+                    affected.insert(*child_txid);
+                }
+            }
 
-            self.feeinfo.remove(*txid).or_else(|| {
-                warn!("missing mempool tx feeinfo {}", txid);
-                None
+            let fee = self.feeinfo.remove(*txid).map_or_else(
+                || {
+                    warn!("missing mempool tx feeinfo {}", txid);
+                    0
+                },
+                |feeinfo| feeinfo.fee,
+            );
+
+            let first_seen = self.first_seen.remove(*txid);
+            let reason = match confirming_blocks.get(*txid) {
+                Some(blockid) => {
+                    if let Some(first_seen) = first_seen {
+                        let latency = (blockid.time as u64).saturating_sub(first_seen);
+                        self.confirmation_latency.observe(latency as f64);
+                        confirmed.push((**txid, first_seen));
+                    }
+                    RemovalReason::Confirmed {
+                        block_hash: blockid.hash,
+                        block_height: blockid.height,
+                    }
+                }
+                None => drop_reasons.remove(*txid).expect("missing drop reason"),
+            };
+            self.removed.with_label_values(&[reason.name()]).inc();
+            self.events.push(Event::TxRemoved {
+                txid: **txid,
+                reason: reason.clone(),
             });
+            match reason {
+                RemovalReason::Confirmed { .. } => (),
+                reason => self.dropped.insert(
+                    tx,
+                    DroppedTx {
+                        reason,
+                        time: now,
+                        fee,
+                    },
+                ),
+            }
+        }
+        if !confirmed.is_empty() {
+            self.chain.save_first_seen(&confirmed);
         }
 
         // TODO: make it more efficient (currently it takes O(|mempool|) time)
-        self.history.retain(|_scripthash, entries| {
+        let touched = &mut self.touched;
+        self.history.retain(|scripthash, entries| {
+            if entries
+                .iter()
+                .any(|entry| affected.contains(&entry.get_txid()))
+            {
+                touched.insert(*scripthash);
+            }
             entries.retain(|entry| !to_remove.contains(&entry.get_txid()));
             !entries.is_empty()
         });
@@ -503,6 +834,48 @@ impl Mempool {
 
         self.edges
             .retain(|_outpoint, (txid, _vin)| !to_remove.contains(txid));
+
+        // Children lose their removed ancestors, and ancestors their removed descendants
+        let remaining: Vec<Txid> = affected
+            .into_iter()
+            .chain(ancestors)
+            .filter(|txid| self.txstore.contains_key(txid))
+            .collect();
+        self.update_effective_feerates(&remaining);
+    }
+
+    /// Returns the unconfirmed txs the given tx depends on.
+    pub fn ancestors(&self, txid: &Txid) -> HashSet<Txid> {
+        tx_ancestors(&self.txstore, txid)
+    }
+
+    /// Returns the unconfirmed txs depending on the given tx.
+    pub fn descendants(&self, txid: &Txid) -> HashSet<Txid> {
+        tx_descendants(&self.txstore, &self.edges, txid)
+    }
+
+    /// Returns the ancestors, descendants and effective feerate of a mempool tx.
+    pub fn package_info(&self, txid: &Txid) -> Option<PackageInfo> {
+        let feeinfo = self.feeinfo.get(txid)?;
+        Some(PackageInfo {
+            ancestors: self.ancestors(txid).into_iter().collect(),
+            descendants: self.descendants(txid).into_iter().collect(),
+            effective_feerate: feeinfo.effective_fee_per_vbyte,
+        })
+    }
+
+    fn update_effective_feerates(&mut self, changed: &[Txid]) {
+        let _timer = self
+            .latency
+            .with_label_values(&["update_effective_feerates"])
+            .start_timer();
+
+        let feerates = effective_feerates(&self.txstore, &self.edges, &self.feeinfo, changed);
+        for (txid, feerate) in feerates {
+            if let Some(feeinfo) = self.feeinfo.get_mut(&txid) {
+                feeinfo.effective_fee_per_vbyte = feerate;
+            }
+        }
     }
 
     #[cfg(feature = "liquid")]
@@ -517,6 +890,519 @@ impl Mempool {
     }
 }
 
+// The unconfirmed txs the given mempool tx depends on
+fn tx_ancestors(txstore: &HashMap<Txid, Transaction>, txid: &Txid) -> HashSet<Txid> {
+    let mut ancestors = HashSet::new();
+    let mut queue = vec![*txid];
+    while let Some(txid) = queue.pop() {
+        let tx = match txstore.get(&txid) {
+            Some(tx) => tx,
+            None => continue,
+        };
+        for txin in &tx.input {
+            let parent_txid = txin.previous_output.txid;
+            if ancestors.len() < MAX_PACKAGE_TXS
+                && txstore.contains_key(&parent_txid)
+                && ancestors.insert(parent_txid)
+            {
+                queue.push(parent_txid);
+            }
+        }
+    }
+    ancestors
+}
+
+// The unconfirmed txs depending on the given mempool tx
+fn tx_descendants(
+    txstore: &HashMap<Txid, Transaction>,
+    edges: &HashMap<OutPoint, (Txid, u32)>,
+    txid: &Txid,
+) -> HashSet<Txid> {
+    let mut descendants = HashSet::new();
+    let mut queue = vec![*txid];
+    while let Some(txid) = queue.pop() {
+        let tx = match txstore.get(&txid) {
+            Some(tx) => tx,
+            None => continue,
+        };
+        for vout in 0..tx.output.len() {
+            let outpoint = OutPoint {
+                txid,
+                vout: vout as u32,
+            };
+            if let Some((child_txid, _)) = edges.get(&outpoint) {
+                if descendants.len() < MAX_PACKAGE_TXS && descendants.insert(*child_txid) {
+                    queue.push(*child_txid);
+                }
+            }
+        }
+    }
+    descendants
+}
+
+// The feerate of the tx along with all its unconfirmed ancestors
+fn ancestor_feerate(
+    txstore: &HashMap<Txid, Transaction>,
+    feeinfo: &HashMap<Txid, TxFeeInfo>,
+    txid: &Txid,
+) -> f32 {
+    let (fee, vsize) = tx_ancestors(txstore, txid)
+        .iter()
+        .chain(std::iter::once(txid))
+        .filter_map(|txid| feeinfo.get(txid))
+        .fold((0, 0), |(fee, vsize), feeinfo| {
+            (fee + feeinfo.fee, vsize + feeinfo.vsize as u64)
+        });
+    fee as f32 / vsize.max(1) as f32
+}
+
+// A tx gets mined along with the best-paying package it is part of, which is either its own
+// ancestor package or the ancestor package of one of its descendants. Changes to a tx affect
+// the effective feerates of its descendants, and of all their ancestors.
+fn effective_feerates(
+    txstore: &HashMap<Txid, Transaction>,
+    edges: &HashMap<OutPoint, (Txid, u32)>,
+    feeinfo: &HashMap<Txid, TxFeeInfo>,
+    changed: &[Txid],
+) -> Vec<(Txid, f32)> {
+    let mut affected = HashSet::new();
+    for txid in changed {
+        affected.insert(*txid);
+        affected.extend(tx_descendants(txstore, edges, txid));
+    }
+    for txid in affected.clone() {
+        affected.extend(tx_ancestors(txstore, &txid));
+    }
+
+    affected
+        .into_iter()
+        .map(|txid| {
+            let feerate = tx_descendants(txstore, edges, &txid)
+                .iter()
+                .map(|descendant| ancestor_feerate(txstore, feeinfo, descendant))
+                .fold(ancestor_feerate(txstore, feeinfo, &txid), f32::max);
+            (txid, feerate)
+        })
+        .collect()
+}
+
+// Classifies why the unconfirmed txs left the daemon's mempool. The descendants of replaced
+// and conflicted txs leave along with them, and are attributed to that ancestor.
+fn drop_reasons<F>(
+    txstore: &HashMap<Txid, Transaction>,
+    rbf: &RbfHistory,
+    dropped: &DroppedTxs,
+    to_remove: &HashSet<&Txid>,
+    confirming_blocks: &HashMap<Txid, BlockId>,
+    lookup_spend: F,
+) -> HashMap<Txid, RemovalReason>
+where
+    F: Fn(&OutPoint) -> Option<SpendingInput>,
+{
+    let mut reasons: HashMap<Txid, RemovalReason> = to_remove
+        .iter()
+        .filter(|txid| !confirming_blocks.contains_key(**txid))
+        .map(|txid| {
+            (
+                **txid,
+                drop_reason(rbf, txid, &txstore[*txid], &lookup_spend),
+            )
+        })
+        .collect();
+    let evicted: Vec<Txid> = reasons
+        .iter()
+        .filter_map(|(txid, reason)| match reason {
+            RemovalReason::Evicted => Some(*txid),
+            _ => None,
+        })
+        .collect();
+    for txid in evicted {
+        if let Some(reason) = ancestor_drop_reason(txstore, dropped, &txid, &reasons) {
+            reasons.insert(txid, reason);
+        }
+    }
+    reasons
+}
+
+// Looks for a replaced or conflicted ancestor, dropped along with the tx or previously
+fn ancestor_drop_reason(
+    txstore: &HashMap<Txid, Transaction>,
+    dropped: &DroppedTxs,
+    txid: &Txid,
+    reasons: &HashMap<Txid, RemovalReason>,
+) -> Option<RemovalReason> {
+    let mut visited = HashSet::new();
+    let mut queue = vec![*txid];
+    while let Some(txid) = queue.pop() {
+        let tx = match txstore
+            .get(&txid)
+            .or_else(|| dropped.txs.get(&txid).map(|(tx, _)| tx))
+        {
+            Some(tx) => tx,
+            None => continue,
+        };
+        for txin in &tx.input {
+            let parent_txid = txin.previous_output.txid;
+            if visited.len() >= MAX_PACKAGE_TXS || !visited.insert(parent_txid) {
+                continue;
+            }
+            let reason = reasons.get(&parent_txid).or_else(|| {
+                dropped
+                    .txs
+                    .get(&parent_txid)
+                    .map(|(_, dropped)| &dropped.reason)
+            });
+            match reason {
+                Some(RemovalReason::Replaced { replaced_by }) => {
+                    return Some(RemovalReason::ReplacedAncestor {
+                        ancestor_txid: parent_txid,
+                        replaced_by: *replaced_by,
+                    })
+                }
+                Some(RemovalReason::Conflicted {
+                    conflicting_txid,
+                    block_hash,
+                    block_height,
+                }) => {
+                    return Some(RemovalReason::ConflictedAncestor {
+                        ancestor_txid: parent_txid,
+                        conflicting_txid: *conflicting_txid,
+                        block_hash: *block_hash,
+                        block_height: *block_height,
+                    })
+                }
+                Some(reason @ RemovalReason::ReplacedAncestor { .. })
+                | Some(reason @ RemovalReason::ConflictedAncestor { .. }) => {
+                    return Some(reason.clone())
+                }
+                _ => queue.push(parent_txid),
+            }
+        }
+    }
+    None
+}
+
+// Classifies why an unconfirmed tx left the daemon's mempool
+fn drop_reason<F>(
+    rbf: &RbfHistory,
+    txid: &Txid,
+    tx: &Transaction,
+    lookup_spend: &F,
+) -> RemovalReason
+where
+    F: Fn(&OutPoint) -> Option<SpendingInput>,
+{
+    if let Some(replacement) = rbf.replaced_by.get(txid) {
+        return RemovalReason::Replaced {
+            replaced_by: replacement.txid,
+        };
+    }
+    tx.input
+        .iter()
+        .filter(|txin| has_prevout(txin))
+        .filter_map(|txin| lookup_spend(&txin.previous_output))
+        .find(|spend| spend.txid != *txid)
+        .and_then(|spend| {
+            let blockid = spend.confirmed?;
+            Some(RemovalReason::Conflicted {
+                conflicting_txid: spend.txid,
+                block_hash: blockid.hash,
+                block_height: blockid.height,
+            })
+        })
+        // the daemon doesn't tell evicted and expired txs apart
+        .unwrap_or(RemovalReason::Evicted)
+}
+
+/// A block that is expected to be mined out of the mempool
+#[derive(Serialize, Default)]
+pub struct ProjectedBlock {
+    pub tx_count: u32,
+    pub vsize: u32,
+    pub total_fee: u64,
+    pub min_feerate: f32, // effective feerates (taking CPFP into account), in sat/vbyte
+    pub max_feerate: f32,
+}
+
+#[derive(Serialize)]
+pub struct ProjectedPosition {
+    pub block: usize,     // 0 for the next block
+    pub position: usize,  // within the block
+    pub vsize_ahead: u64, // of the txs expected to be mined before it, in any block
+}
+
+#[derive(Default)]
+struct ProjectedBlocks {
+    blocks: Vec<ProjectedBlock>,
+    positions: HashMap<Txid, ProjectedPosition>,
+}
+
+impl ProjectedBlocks {
+    // Greedily fills the blocks by effective (ancestor package) feerate, placing every tx after
+    // its unconfirmed ancestors.
+    fn new(txstore: &HashMap<Txid, Transaction>, feeinfos: &HashMap<Txid, TxFeeInfo>) -> Self {
+        let mut txids: Vec<(&Txid, &TxFeeInfo)> = feeinfos.iter().collect();
+        txids.sort_unstable_by(|(_, e1), (_, e2)| {
+            e2.effective_fee_per_vbyte
+                .partial_cmp(&e1.effective_fee_per_vbyte)
+                .unwrap()
+        });
+
+        let mut projection = ProjectedBlocks::default();
+        let mut vsize_ahead = 0;
+        for (txid, _) in txids {
+            let mut package = vec![];
+            projection.collect_package(txstore, feeinfos, txid, &mut package);
+            for txid in package {
+                let feeinfo = &feeinfos[&txid];
+                let full = projection.blocks.last().map_or(true, |block| {
+                    block.vsize + feeinfo.vsize > PROJECTED_BLOCK_VSIZE && block.tx_count > 0
+                });
+                if full && projection.blocks.len() < PROJECTED_BLOCKS {
+                    projection.blocks.push(ProjectedBlock {
+                        min_feerate: feeinfo.effective_fee_per_vbyte,
+                        max_feerate: feeinfo.effective_fee_per_vbyte,
+                        ..Default::default()
+                    });
+                }
+                let index = projection.blocks.len() - 1;
+                let block = &mut projection.blocks[index];
+                projection.positions.insert(
+                    txid,
+                    ProjectedPosition {
+                        block: index,
+                        position: block.tx_count as usize,
+                        vsize_ahead,
+                    },
+                );
+                block.tx_count += 1;
+                block.vsize += feeinfo.vsize;
+                block.total_fee += feeinfo.fee;
+                block.min_feerate = block.min_feerate.min(feeinfo.effective_fee_per_vbyte);
+                block.max_feerate = block.max_feerate.max(feeinfo.effective_fee_per_vbyte);
+                vsize_ahead += feeinfo.vsize as u64;
+            }
+        }
+        projection
+    }
+
+    // Collects the tx and its unplaced ancestors, parents first
+    fn collect_package(
+        &self,
+        txstore: &HashMap<Txid, Transaction>,
+        feeinfo: &HashMap<Txid, TxFeeInfo>,
+        txid: &Txid,
+        package: &mut Vec<Txid>,
+    ) {
+        if self.positions.contains_key(txid) || package.contains(txid) {
+            return;
+        }
+        let tx = match txstore.get(txid) {
+            Some(tx) => tx,
+            None => return,
+        };
+        for txin in &tx.input {
+            let parent_txid = &txin.previous_output.txid;
+            if feeinfo.contains_key(parent_txid) {
+                self.collect_package(txstore, feeinfo, parent_txid, package);
+            }
+        }
+        package.push(*txid);
+    }
+}
+
+// The mempool state saved across restarts
+#[derive(Serialize, Deserialize)]
+struct MempoolDump {
+    version: u32,
+    txs: Vec<(Bytes, u64)>, // raw tx and first seen time
+}
+
+impl MempoolDump {
+    fn new<'a>(txs: impl Iterator<Item = (&'a Transaction, u64)>) -> Self {
+        MempoolDump {
+            version: MEMPOOL_DUMP_VERSION,
+            txs: txs
+                .map(|(tx, first_seen)| (serialize(tx), first_seen))
+                .collect(),
+        }
+    }
+
+    // Written to a temporary file first, so that a crash never leaves a partial dump behind
+    fn write(&self, path: &Path) -> Result<()> {
+        let tmp_path = path.with_extension("tmp");
+        let data = bincode::serialize(self).chain_err(|| "failed to serialize mempool")?;
+        fs::write(&tmp_path, data).chain_err(|| format!("failed to write {:?}", tmp_path))?;
+        fs::rename(&tmp_path, path).chain_err(|| format!("failed to rename {:?}", tmp_path))
+    }
+
+    // Returns the dumped txs along with their first seen times
+    fn read(path: &Path) -> Result<Vec<(Transaction, u64)>> {
+        let data = fs::read(path).chain_err(|| format!("failed to read {:?}", path))?;
+        let dump: MempoolDump =
+            bincode::deserialize(&data).chain_err(|| format!("failed to parse {:?}", path))?;
+        if dump.version != MEMPOOL_DUMP_VERSION {
+            bail!("unsupported mempool dump version {}", dump.version);
+        }
+        dump.txs
+            .into_iter()
+            .map(|(raw_tx, first_seen)| {
+                let tx: Transaction = deserialize(&raw_tx).chain_err(|| "failed to parse tx")?;
+                Ok((tx, first_seen))
+            })
+            .collect()
+    }
+}
+
+/// The unconfirmed ancestors and descendants of a mempool tx, and the feerate it is effectively
+/// mined at (taking CPFP into account)
+#[derive(Serialize, Deserialize)]
+pub struct PackageInfo {
+    pub ancestors: Vec<Txid>,
+    pub descendants: Vec<Txid>,
+    pub effective_feerate: f32,
+}
+
+/// A mempool tx replaced by a conflicting one
+#[derive(Serialize)]
+pub struct Replacement {
+    pub txid: Txid, // the replacing tx
+    pub replaced_txid: Txid,
+    pub fee_delta: i64, // the fee of the replacing tx minus the fee of the replaced one
+    pub time: u64,      // when the replacement was seen
+}
+
+/// Why a tx left the mempool
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "reason", rename_all = "snake_case")]
+pub enum RemovalReason {
+    Confirmed {
+        block_hash: BlockHash,
+        block_height: usize,
+    },
+    Replaced {
+        replaced_by: Txid,
+    },
+    // a block tx spent one of its inputs
+    Conflicted {
+        conflicting_txid: Txid,
+        block_hash: BlockHash,
+        block_height: usize,
+    },
+    // one of its unconfirmed ancestors was replaced
+    ReplacedAncestor {
+        ancestor_txid: Txid,
+        replaced_by: Txid,
+    },
+    // a block tx spent one of the inputs of one of its unconfirmed ancestors
+    ConflictedAncestor {
+        ancestor_txid: Txid,
+        conflicting_txid: Txid,
+        block_hash: BlockHash,
+        block_height: usize,
+    },
+    Evicted, // evicted or expired
+}
+
+impl RemovalReason {
+    fn name(&self) -> &'static str {
+        match self {
+            RemovalReason::Confirmed { .. } => "confirmed",
+            RemovalReason::Replaced { .. } => "replaced",
+            RemovalReason::Conflicted { .. } => "conflicted",
+            RemovalReason::ReplacedAncestor { .. } => "replaced_ancestor",
+            RemovalReason::ConflictedAncestor { .. } => "conflicted_ancestor",
+            RemovalReason::Evicted => "evicted",
+        }
+    }
+}
+
+/// A tx that left the mempool without confirming
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DroppedTx {
+    #[serde(flatten)]
+    pub reason: RemovalReason,
+    pub time: u64, // when it was dropped
+    // the fee as of when it was dropped, as its prevouts may no longer be available
+    #[serde(skip)]
+    pub fee: u64,
+}
+
+// The most recently dropped txs, forgetting the oldest ones first
+#[derive(Default)]
+struct DroppedTxs {
+    txs: HashMap<Txid, (Transaction, DroppedTx)>,
+    order: VecDeque<Txid>, // oldest first
+}
+
+impl DroppedTxs {
+    fn insert(&mut self, tx: Transaction, dropped: DroppedTx) {
+        let txid = tx.txid();
+        if self.txs.insert(txid, (tx, dropped)).is_none() {
+            self.order.push_back(txid);
+        }
+        while self.txs.len() > DROPPED_TXS_SIZE {
+            let oldest = self.order.pop_front().unwrap();
+            self.txs.remove(&oldest);
+        }
+    }
+
+    // Forgets a dropped tx when it re-enters the mempool
+    fn forget(&mut self, txid: &Txid) {
+        if self.txs.remove(txid).is_some() {
+            self.order.retain(|dropped_txid| dropped_txid != txid);
+        }
+    }
+}
+
+// A bounded replacement graph, which forgets the oldest replacements first
+#[derive(Default)]
+struct RbfHistory {
+    replaced_by: HashMap<Txid, Replacement>, // replaced txid -> replacement
+    replaces: HashMap<Txid, Vec<Txid>>,      // replacing txid -> replaced txids
+    order: VecDeque<Txid>,                   // replaced txids, oldest first
+}
+
+impl RbfHistory {
+    fn insert(&mut self, replacement: Replacement) {
+        let replaced_txid = replacement.replaced_txid;
+        self.forget(&replaced_txid);
+        self.replaces
+            .entry(replacement.txid)
+            .or_insert_with(Vec::new)
+            .push(replaced_txid);
+        self.replaced_by.insert(replaced_txid, replacement);
+        self.order.push_back(replaced_txid);
+
+        while self.replaced_by.len() > RBF_HISTORY_SIZE {
+            let oldest = self.order.pop_front().unwrap();
+            self.remove(&oldest);
+        }
+    }
+
+    // Forgets the replacement of a tx, e.g. when it re-enters the mempool by replacing its own
+    // replacement
+    fn forget(&mut self, txid: &Txid) {
+        if self.remove(txid) {
+            self.order.retain(|replaced_txid| replaced_txid != txid);
+        }
+    }
+
+    fn remove(&mut self, replaced_txid: &Txid) -> bool {
+        let replacement = match self.replaced_by.remove(replaced_txid) {
+            Some(replacement) => replacement,
+            None => return false,
+        };
+        if let Some(replaced) = self.replaces.get_mut(&replacement.txid) {
+            replaced.retain(|txid| txid != replaced_txid);
+            if replaced.is_empty() {
+                self.replaces.remove(&replacement.txid);
+            }
+        }
+        true
+    }
+}
+
 #[derive(Serialize)]
 pub struct BacklogStats {
     pub count: u32,
@@ -550,3 +1436,377 @@ impl BacklogStats {
         }
     }
 }
+
+#[cfg(all(test, not(feature = "liquid")))]
+mod tests {
+    use super::*;
+    use bitcoin::hashes::Hash;
+    use bitcoin::{Script, TxIn};
+
+    // A confirmed outpoint, which isn't part of the synthetic mempools
+    fn confirmed_outpoint(n: u8) -> OutPoint {
+        OutPoint {
+            txid: Txid::hash(&[n]),
+            vout: 0,
+        }
+    }
+
+    // A tx spending the given outpoints, with a single output (the value keeps the txids unique)
+    fn tx(inputs: &[OutPoint], value: u64) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: 0,
+            input: inputs
+                .iter()
+                .map(|outpoint| TxIn {
+                    previous_output: *outpoint,
+                    script_sig: Script::new(),
+                    sequence: 0xffff_ffff,
+                    witness: vec![],
+                })
+                .collect(),
+            output: vec![TxOut {
+                value,
+                script_pubkey: Script::new(),
+            }],
+        }
+    }
+
+    fn output(tx: &Transaction) -> OutPoint {
+        OutPoint {
+            txid: tx.txid(),
+            vout: 0,
+        }
+    }
+
+    fn feeinfo(fee: u64, vsize: u32) -> TxFeeInfo {
+        let fee_per_vbyte = fee as f32 / vsize as f32;
+        TxFeeInfo {
+            fee,
+            vsize,
+            fee_per_vbyte,
+            effective_fee_per_vbyte: fee_per_vbyte,
+        }
+    }
+
+    // A mempool of (tx, fee, vsize) entries, indexed the way Mempool::add() does
+    struct TestMempool {
+        txstore: HashMap<Txid, Transaction>,
+        edges: HashMap<OutPoint, (Txid, u32)>,
+        feeinfo: HashMap<Txid, TxFeeInfo>,
+    }
+
+    impl TestMempool {
+        fn new(entries: &[(&Transaction, u64, u32)]) -> Self {
+            let mut mempool = TestMempool {
+                txstore: HashMap::new(),
+                edges: HashMap::new(),
+                feeinfo: HashMap::new(),
+            };
+            for (tx, fee, vsize) in entries {
+                let txid = tx.txid();
+                for (vin, txin) in tx.input.iter().enumerate() {
+                    mempool
+                        .edges
+                        .insert(txin.previous_output, (txid, vin as u32));
+                }
+                mempool.txstore.insert(txid, (*tx).clone());
+                mempool.feeinfo.insert(txid, feeinfo(*fee, *vsize));
+            }
+            let txids: Vec<Txid> = mempool.txstore.keys().cloned().collect();
+            let feerates =
+                effective_feerates(&mempool.txstore, &mempool.edges, &mempool.feeinfo, &txids);
+            for (txid, feerate) in feerates {
+                if let Some(feeinfo) = mempool.feeinfo.get_mut(&txid) {
+                    feeinfo.effective_fee_per_vbyte = feerate;
+                }
+            }
+            mempool
+        }
+
+        fn effective_feerate(&self, tx: &Transaction) -> f32 {
+            self.feeinfo[&tx.txid()].effective_fee_per_vbyte
+        }
+    }
+
+    fn replacement(txid: Txid, replaced_txid: Txid) -> Replacement {
+        Replacement {
+            txid,
+            replaced_txid,
+            fee_delta: 0,
+            time: 0,
+        }
+    }
+
+    #[test]
+    fn test_effective_feerates() {
+        // a low-fee parent bumped by its child (CPFP)
+        let parent = tx(&[confirmed_outpoint(1)], 1);
+        let child = tx(&[output(&parent)], 2);
+        // a high-fee parent that isn't dragged down by its low-fee child
+        let rich_parent = tx(&[confirmed_outpoint(2)], 3);
+        let poor_child = tx(&[output(&rich_parent)], 4);
+        // and an unrelated tx
+        let single = tx(&[confirmed_outpoint(3)], 5);
+        let mempool = TestMempool::new(&[
+            (&parent, 100, 100),
+            (&child, 900, 100),
+            (&rich_parent, 1000, 100),
+            (&poor_child, 100, 100),
+            (&single, 300, 100),
+        ]);
+
+        assert_eq!(mempool.effective_feerate(&parent), 5.0);
+        assert_eq!(mempool.effective_feerate(&child), 5.0);
+        assert_eq!(mempool.effective_feerate(&rich_parent), 10.0);
+        assert_eq!(mempool.effective_feerate(&poor_child), 5.5);
+        assert_eq!(mempool.effective_feerate(&single), 3.0);
+
+        assert_eq!(
+            tx_ancestors(&mempool.txstore, &child.txid()),
+            HashSet::from_iter(vec![parent.txid()])
+        );
+        assert_eq!(
+            tx_descendants(&mempool.txstore, &mempool.edges, &parent.txid()),
+            HashSet::from_iter(vec![child.txid()])
+        );
+        assert!(tx_ancestors(&mempool.txstore, &single.txid()).is_empty());
+    }
+
+    #[test]
+    fn test_effective_feerates_of_a_chain() {
+        // the grandchild pays for both of its ancestors, while the child only pays for itself
+        let parent = tx(&[confirmed_outpoint(1)], 1);
+        let child = tx(&[output(&parent)], 2);
+        let grandchild = tx(&[output(&child)], 3);
+        let mempool = TestMempool::new(&[
+            (&parent, 100, 100),
+            (&child, 200, 100),
+            (&grandchild, 1200, 100),
+        ]);
+
+        assert_eq!(mempool.effective_feerate(&parent), 5.0);
+        assert_eq!(mempool.effective_feerate(&child), 5.0);
+        assert_eq!(mempool.effective_feerate(&grandchild), 5.0);
+        assert_eq!(
+            tx_descendants(&mempool.txstore, &mempool.edges, &parent.txid()),
+            HashSet::from_iter(vec![child.txid(), grandchild.txid()])
+        );
+    }
+
+    #[test]
+    fn test_projected_blocks() {
+        let parent = tx(&[confirmed_outpoint(1)], 1);
+        let child = tx(&[output(&parent)], 2);
+        let rich = tx(&[confirmed_outpoint(2)], 3);
+        // too large to fit in the first block along with the others
+        let large = tx(&[confirmed_outpoint(3)], 4);
+        let mempool = TestMempool::new(&[
+            (&parent, 100, 100),
+            (&child, 900, 100),
+            (&rich, 1000, 100),
+            (&large, 2_997_000, 999_000),
+        ]);
+        let projection = ProjectedBlocks::new(&mempool.txstore, &mempool.feeinfo);
+
+        assert_eq!(projection.blocks.len(), 2);
+        let first = &projection.blocks[0];
+        assert_eq!(first.tx_count, 3);
+        assert_eq!(first.vsize, 300);
+        assert_eq!(first.total_fee, 2000);
+        assert_eq!(first.min_feerate, 5.0);
+        assert_eq!(first.max_feerate, 10.0);
+        assert_eq!(projection.blocks[1].tx_count, 1);
+
+        let position = |tx: &Transaction| &projection.positions[&tx.txid()];
+        assert_eq!(position(&rich).position, 0);
+        // the parent is placed before its child
+        assert_eq!(position(&parent).position, 1);
+        assert_eq!(position(&child).position, 2);
+        assert_eq!(position(&child).vsize_ahead, 200);
+        assert_eq!(position(&large).block, 1);
+        assert_eq!(position(&large).position, 0);
+        assert_eq!(position(&large).vsize_ahead, 300);
+    }
+
+    #[test]
+    fn test_rbf_history() {
+        let (a, b, c) = (Txid::hash(&[1]), Txid::hash(&[2]), Txid::hash(&[3]));
+        let mut rbf = RbfHistory::default();
+
+        // a is replaced by b, which is replaced by c
+        rbf.insert(replacement(b, a));
+        rbf.insert(replacement(c, b));
+        assert_eq!(rbf.replaced_by[&a].txid, b);
+        assert_eq!(rbf.replaced_by[&b].txid, c);
+        assert_eq!(rbf.replaces[&b], vec![a]);
+        assert_eq!(rbf.replaces[&c], vec![b]);
+
+        // a re-enters the mempool by replacing c, and is no longer considered replaced
+        rbf.forget(&a);
+        rbf.insert(replacement(a, c));
+        assert!(!rbf.replaced_by.contains_key(&a));
+        assert!(!rbf.replaces.contains_key(&b));
+        assert_eq!(rbf.replaced_by[&c].txid, a);
+        assert_eq!(rbf.replaces[&a], vec![c]);
+        assert_eq!(rbf.order, vec![b, c]);
+    }
+
+    #[test]
+    fn test_rbf_history_forgets_the_oldest_replacements() {
+        let replacing = Txid::hash(&[0]);
+        let replaced = |n: usize| Txid::hash(&n.to_be_bytes());
+        let mut rbf = RbfHistory::default();
+        for n in 0..=RBF_HISTORY_SIZE {
+            rbf.insert(replacement(replacing, replaced(n)));
+        }
+        assert_eq!(rbf.replaced_by.len(), RBF_HISTORY_SIZE);
+        assert_eq!(rbf.order.len(), RBF_HISTORY_SIZE);
+        assert_eq!(rbf.replaces[&replacing].len(), RBF_HISTORY_SIZE);
+        assert!(!rbf.replaced_by.contains_key(&replaced(0)));
+        assert!(rbf.replaced_by.contains_key(&replaced(1)));
+    }
+
+    #[test]
+    fn test_drop_reasons() {
+        let block = BlockId {
+            height: 100,
+            hash: BlockHash::hash(&[100]),
+            time: 0,
+        };
+        // replaced, along with its child
+        let replaced = tx(&[confirmed_outpoint(1)], 1);
+        let replaced_child = tx(&[output(&replaced)], 2);
+        let replacing = tx(&[confirmed_outpoint(1)], 3);
+        // conflicted by a block tx, along with its child
+        let conflicted = tx(&[confirmed_outpoint(2)], 4);
+        let conflicted_child = tx(&[output(&conflicted)], 5);
+        let conflicting = tx(&[confirmed_outpoint(2)], 6);
+        // the child of a tx replaced earlier on
+        let earlier = tx(&[confirmed_outpoint(3)], 7);
+        let earlier_child = tx(&[output(&earlier)], 8);
+        // dropped on its own
+        let evicted = tx(&[confirmed_outpoint(4)], 9);
+        // and mined
+        let mined = tx(&[confirmed_outpoint(5)], 10);
+
+        let mempool = TestMempool::new(&[
+            (&replaced, 100, 100),
+            (&replaced_child, 100, 100),
+            (&conflicted, 100, 100),
+            (&conflicted_child, 100, 100),
+            (&earlier_child, 100, 100),
+            (&evicted, 100, 100),
+            (&mined, 100, 100),
+        ]);
+        let mut rbf = RbfHistory::default();
+        rbf.insert(replacement(replacing.txid(), replaced.txid()));
+        let mut dropped = DroppedTxs::default();
+        dropped.insert(
+            earlier.clone(),
+            DroppedTx {
+                reason: RemovalReason::Replaced {
+                    replaced_by: replacing.txid(),
+                },
+                time: 0,
+                fee: 100,
+            },
+        );
+        let conflicting_txid = conflicting.txid();
+        let lookup_spend = |outpoint: &OutPoint| {
+            if *outpoint == confirmed_outpoint(2) {
+                Some(SpendingInput {
+                    txid: conflicting_txid,
+                    vin: 0,
+                    confirmed: Some(block.clone()),
+                })
+            } else {
+                None
+            }
+        };
+
+        let txids: Vec<Txid> = mempool.txstore.keys().cloned().collect();
+        let to_remove: HashSet<&Txid> = txids.iter().collect();
+        let mut confirming_blocks = HashMap::new();
+        confirming_blocks.insert(mined.txid(), block.clone());
+        let reasons = drop_reasons(
+            &mempool.txstore,
+            &rbf,
+            &dropped,
+            &to_remove,
+            &confirming_blocks,
+            lookup_spend,
+        );
+
+        let reason = |tx: &Transaction| serde_json::to_value(&reasons[&tx.txid()]).unwrap();
+        assert_eq!(reasons.len(), 6);
+        assert!(!reasons.contains_key(&mined.txid()));
+        assert_eq!(
+            reason(&replaced),
+            json!({ "reason": "replaced", "replaced_by": replacing.txid() })
+        );
+        assert_eq!(
+            reason(&replaced_child),
+            json!({
+                "reason": "replaced_ancestor",
+                "ancestor_txid": replaced.txid(),
+                "replaced_by": replacing.txid(),
+            })
+        );
+        assert_eq!(
+            reason(&conflicted),
+            json!({
+                "reason": "conflicted",
+                "conflicting_txid": conflicting.txid(),
+                "block_hash": block.hash,
+                "block_height": 100,
+            })
+        );
+        assert_eq!(
+            reason(&conflicted_child),
+            json!({
+                "reason": "conflicted_ancestor",
+                "ancestor_txid": conflicted.txid(),
+                "conflicting_txid": conflicting.txid(),
+                "block_hash": block.hash,
+                "block_height": 100,
+            })
+        );
+        assert_eq!(
+            reason(&earlier_child),
+            json!({
+                "reason": "replaced_ancestor",
+                "ancestor_txid": earlier.txid(),
+                "replaced_by": replacing.txid(),
+            })
+        );
+        assert_eq!(reason(&evicted), json!({ "reason": "evicted" }));
+    }
+
+    #[test]
+    fn test_dump_and_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("mempool.dat");
+        let parent = tx(&[confirmed_outpoint(1)], 1);
+        let child = tx(&[output(&parent)], 2);
+
+        MempoolDump::new(vec![(&parent, 1000), (&child, 2000)].into_iter())
+            .write(&path)
+            .unwrap();
+        assert!(!path.with_extension("tmp").exists());
+        let loaded: HashMap<Txid, (Transaction, u64)> = MempoolDump::read(&path)
+            .unwrap()
+            .into_iter()
+            .map(|(tx, first_seen)| (tx.txid(), (tx, first_seen)))
+            .collect();
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded[&parent.txid()], (parent.clone(), 1000));
+        assert_eq!(loaded[&child.txid()], (child, 2000));
+
+        // dumps of other versions are rejected
+        let mut dump = MempoolDump::new(vec![(&parent, 1000)].into_iter());
+        dump.version = MEMPOOL_DUMP_VERSION + 1;
+        dump.write(&path).unwrap();
+        assert!(MempoolDump::read(&path).is_err());
+    }
+}
@@ -7,9 +7,13 @@ pub mod schema;
 
 pub use self::db::{DBRow, DB};
 pub use self::fetch::{BlockEntry, FetchFrom};
-pub use self::mempool::Mempool;
-pub use self::query::Query;
+pub use self::mempool::{DroppedTx, Mempool, PackageInfo, RemovalReason, TxOverview};
+pub use self::query::{Query, UtxoCursor};
 pub use self::schema::{
-    compute_script_hash, parse_hash, ChainQuery, FundingInfo, Indexer, ScriptStats, SpendingInfo,
-    SpendingInput, Store, TxHistoryInfo, TxHistoryKey, TxHistoryRow, Utxo,
+    compute_script_hash, parse_hash, ChainQuery, Event, FundingInfo, Indexer, ScriptStats,
+    SpendingInfo, SpendingInput, Store, TouchedScripts, TxHistoryInfo, TxHistoryKey, TxHistoryRow,
+    Utxo,
 };
+
+#[cfg(not(feature = "liquid"))]
+pub use self::schema::{BalanceInterval, BalancePoint, ChainStats};
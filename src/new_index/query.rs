@@ -1,16 +1,22 @@
+use itertools::Itertools;
 use rayon::prelude::*;
 
 use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::str::FromStr;
 use std::sync::{Arc, RwLock, RwLockReadGuard};
 use std::time::{Duration, Instant};
 
 use crate::chain::{Network, OutPoint, Transaction, TxOut};
-use crate::config::Config;
-use crate::daemon::Daemon;
+use crate::config::{Config, FeeEstimator};
+use crate::daemon::{Daemon, MempoolAcceptResult};
 use crate::errors::*;
 use crate::new_index::{ChainQuery, Mempool, ScriptStats, SpendingInput, Utxo};
+use crate::ratelimit;
+use crate::util::fees::estimate_feerates;
 use crate::util::{is_spendable, BlockId, Bytes, TransactionStatus};
 
+use bitcoin::hashes::hex::{FromHex, ToHex};
 use bitcoin::Txid;
 
 #[cfg(feature = "liquid")]
@@ -21,6 +27,16 @@ use crate::{
 
 const FEE_ESTIMATES_TTL: u64 = 60; // seconds
 
+// The number of recent blocks considered by the local fee estimator, and the vsize above which
+// they are considered full (the lowest feerate they included was required to get in)
+#[cfg(not(feature = "liquid"))]
+const FEE_ESTIMATES_BLOCKS: usize = 24;
+#[cfg(not(feature = "liquid"))]
+const FULL_BLOCK_VSIZE: u32 = 950_000;
+
+// The height used to order unconfirmed utxos, after all confirmed ones
+const MEMPOOL_HEIGHT: u32 = u32::max_value();
+
 const CONF_TARGETS: [u16; 28] = [
     1u16, 2u16, 3u16, 4u16, 5u16, 6u16, 7u16, 8u16, 9u16, 10u16, 11u16, 12u16, 13u16, 14u16, 15u16,
     16u16, 17u16, 18u16, 19u16, 20u16, 21u16, 22u16, 23u16, 24u16, 25u16, 144u16, 504u16, 1008u16,
@@ -80,14 +96,94 @@ impl Query {
         Ok(txid)
     }
 
+    pub fn test_mempool_accept(&self, txhexes: &[String]) -> Result<Vec<MempoolAcceptResult>> {
+        self.daemon.test_mempool_accept(txhexes)
+    }
+
+    /// Broadcasts the txs of a package in order, stopping at the first rejected one.
+    pub fn broadcast_package(&self, txhexes: &[String]) -> Vec<Result<Txid>> {
+        let mut results = vec![];
+        for txhex in txhexes {
+            let result = self.broadcast_raw(txhex);
+            let rejected = result.is_err();
+            results.push(result);
+            if rejected {
+                break;
+            }
+        }
+        results
+    }
+
     pub fn utxo(&self, scripthash: &[u8]) -> Result<Vec<Utxo>> {
-        let mut utxos = self.chain.utxo(scripthash, self.config.utxos_limit)?;
+        self.utxo_with_limit(scripthash, self.config.utxos_limit)
+    }
+
+    pub fn utxo_with_limit(&self, scripthash: &[u8], limit: usize) -> Result<Vec<Utxo>> {
+        let mut utxos = self.chain.utxo(scripthash, limit)?;
         let mempool = self.mempool();
         utxos.retain(|utxo| !mempool.has_spend(&OutPoint::from(utxo)));
         utxos.extend(mempool.utxo(scripthash));
         Ok(utxos)
     }
 
+    /// Returns a page of at most `limit` utxos following `after`, in (height, txid, vout) order,
+    /// along with the cursor of the next page (if there are more results).
+    /// Unlike utxo(), this is not subject to the utxos limit, as the history is only scanned from
+    /// the cursor height and until the page is filled.
+    pub fn utxo_page(
+        &self,
+        scripthash: &[u8],
+        after: Option<&UtxoCursor>,
+        min_value: u64,
+        min_conf: u32,
+        limit: usize,
+    ) -> Result<(Vec<Utxo>, Option<UtxoCursor>)> {
+        let is_match = |utxo: &Utxo| {
+            explicit_value(utxo).map_or(min_value == 0, |value| value >= min_value)
+                && !self.mempool().has_spend(&OutPoint::from(utxo))
+        };
+        let is_after = |cursor: &UtxoCursor| after.map_or(true, |after| cursor > after);
+
+        let mut utxos: Vec<(UtxoCursor, Utxo)> = vec![];
+        // confirmed utxos are read height by height, as the rows of a height aren't in cursor order
+        let tip_height = self.chain.best_height() as u32;
+        if let Some(max_height) = (tip_height + 1).checked_sub(min_conf) {
+            let start_height = after.map_or(0, |after| after.height);
+            let confirmed = self
+                .chain
+                .utxos_from(scripthash, start_height as usize)
+                .map(|utxo| (UtxoCursor::from(&utxo), utxo))
+                .take_while(|(cursor, _)| cursor.height <= max_height)
+                .filter(|(cursor, utxo)| is_after(cursor) && is_match(utxo));
+            for (_, height_utxos) in &confirmed.group_by(|(cursor, _)| cursor.height) {
+                let mut height_utxos: Vec<(UtxoCursor, Utxo)> = height_utxos.collect();
+                height_utxos.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+                utxos.extend(height_utxos);
+                if utxos.len() > limit {
+                    break;
+                }
+            }
+        }
+        if utxos.len() <= limit && min_conf == 0 {
+            let unconfirmed = self.mempool().utxo(scripthash);
+            let mut unconfirmed: Vec<(UtxoCursor, Utxo)> = unconfirmed
+                .into_iter()
+                .map(|utxo| (UtxoCursor::from(&utxo), utxo))
+                .filter(|(cursor, utxo)| is_after(cursor) && is_match(utxo))
+                .collect();
+            unconfirmed.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+            utxos.extend(unconfirmed);
+        }
+
+        let next = if utxos.len() > limit {
+            utxos.truncate(limit);
+            utxos.last().map(|(cursor, _)| *cursor)
+        } else {
+            None
+        };
+        Ok((utxos.into_iter().map(|(_, utxo)| utxo).collect(), next))
+    }
+
     pub fn history_txids(&self, scripthash: &[u8], limit: usize) -> Vec<(Txid, Option<BlockId>)> {
         let confirmed_txids = self.chain.history_txids(scripthash, limit);
         let confirmed_len = confirmed_txids.len();
@@ -127,6 +223,12 @@ impl Query {
             .expect("failed loading txos")
     }
 
+    /// Like lookup_txos(), but skips the outpoints that can't be found, which is expected for
+    /// the inputs of dropped txs.
+    pub fn lookup_avail_txos(&self, outpoints: &BTreeSet<OutPoint>) -> HashMap<OutPoint, TxOut> {
+        self.mempool().lookup_avail_txos(outpoints)
+    }
+
     pub fn lookup_spend(&self, outpoint: &OutPoint) -> Option<SpendingInput> {
         self.chain
             .lookup_spend(outpoint)
@@ -136,24 +238,41 @@ impl Query {
     pub fn lookup_tx_spends(&self, tx: Transaction) -> Vec<Option<SpendingInput>> {
         let txid = tx.txid();
 
-        tx.output
+        let spends: Vec<(Option<SpendingInput>, u64)> = tx
+            .output
             .par_iter()
             .enumerate()
             .map(|(vout, txout)| {
-                if is_spendable(txout) {
-                    self.lookup_spend(&OutPoint {
-                        txid,
-                        vout: vout as u32,
-                    })
-                } else {
-                    None
-                }
+                ratelimit::measure_scanned_rows(|| {
+                    if is_spendable(txout) {
+                        self.lookup_spend(&OutPoint {
+                            txid,
+                            vout: vout as u32,
+                        })
+                    } else {
+                        None
+                    }
+                })
             })
-            .collect()
+            .collect();
+        ratelimit::record_scanned_rows(spends.iter().map(|(_, rows)| rows).sum());
+        spends.into_iter().map(|(spend, _)| spend).collect()
     }
 
     pub fn get_tx_status(&self, txid: &Txid) -> TransactionStatus {
-        TransactionStatus::from(self.chain.tx_confirming_block(txid))
+        let mut status = TransactionStatus::from(self.chain.tx_confirming_block(txid));
+        if let Some(block_time) = status.block_time {
+            status.first_seen = self.chain.get_first_seen(txid);
+            status.confirmation_latency = status
+                .first_seen
+                .map(|first_seen| (block_time as u64).saturating_sub(first_seen));
+        } else {
+            let mempool = self.mempool();
+            status.replaced_by = mempool.replaced_by(txid).map(|r| r.txid);
+            status.first_seen = mempool.first_seen(txid);
+            status.dropped = mempool.dropped(txid).cloned();
+        }
+        status
     }
 
     pub fn get_mempool_tx_fee(&self, txid: &Txid) -> Option<u64> {
@@ -195,7 +314,11 @@ impl Query {
     }
 
     fn update_fee_estimates(&self) {
-        match self.daemon.estimatesmartfee_batch(&CONF_TARGETS) {
+        let estimates = match self.config.fee_estimator {
+            FeeEstimator::Daemon => self.daemon.estimatesmartfee_batch(&CONF_TARGETS),
+            FeeEstimator::Local => self.local_fee_estimates(),
+        };
+        match estimates {
             Ok(estimates) => {
                 *self.cached_estimates.write().unwrap() = (estimates, Some(Instant::now()));
             }
@@ -205,6 +328,42 @@ impl Query {
         }
     }
 
+    fn local_fee_estimates(&self) -> Result<HashMap<u16, f64>> {
+        let relayfee = self.get_relayfee()?;
+        let fee_histogram = self.mempool().backlog_stats().fee_histogram.clone();
+        Ok(estimate_feerates(
+            &fee_histogram,
+            &self.recent_block_feerates(),
+            relayfee,
+            &CONF_TARGETS,
+        ))
+    }
+
+    // The lowest feerate required by each of the recent blocks, or 0 for blocks that weren't full
+    #[cfg(not(feature = "liquid"))]
+    fn recent_block_feerates(&self) -> Vec<f32> {
+        let best_height = self.chain.best_height();
+        (0..FEE_ESTIMATES_BLOCKS)
+            .take_while(|depth| *depth <= best_height)
+            .filter_map(|depth| self.chain.hash_by_height(best_height - depth))
+            .filter_map(|hash| self.chain.get_block_fees(&hash))
+            .map(|fees| {
+                let vsize: u32 = fees.fee_histogram.iter().map(|(_, vsize)| vsize).sum();
+                if vsize >= FULL_BLOCK_VSIZE {
+                    fees.min_feerate.unwrap_or(0.0)
+                } else {
+                    0.0
+                }
+            })
+            .collect()
+    }
+
+    // Block fees aren't indexed with liquid, only the mempool is used
+    #[cfg(feature = "liquid")]
+    fn recent_block_feerates(&self) -> Vec<f32> {
+        vec![]
+    }
+
     pub fn get_relayfee(&self) -> Result<f64> {
         if let Some(cached) = *self.cached_relayfee.read().unwrap() {
             return Ok(cached);
@@ -262,3 +421,69 @@ impl Query {
         Ok((total_num, results))
     }
 }
+
+/// The position of a utxo in the paged utxo set, encoded as `<height>:<txid>:<vout>`
+/// (with `mempool` as the height of unconfirmed utxos).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct UtxoCursor {
+    height: u32,
+    txid: Txid,
+    vout: u32,
+}
+
+impl From<&Utxo> for UtxoCursor {
+    fn from(utxo: &Utxo) -> Self {
+        UtxoCursor {
+            height: utxo
+                .confirmed
+                .as_ref()
+                .map_or(MEMPOOL_HEIGHT, |blockid| blockid.height as u32),
+            txid: utxo.txid,
+            vout: utxo.vout,
+        }
+    }
+}
+
+impl fmt::Display for UtxoCursor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.height == MEMPOOL_HEIGHT {
+            write!(f, "mempool:{}:{}", self.txid.to_hex(), self.vout)
+        } else {
+            write!(f, "{}:{}:{}", self.height, self.txid.to_hex(), self.vout)
+        }
+    }
+}
+
+impl FromStr for UtxoCursor {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parts: Vec<&str> = s.split(':').collect();
+        let (height, txid, vout) = match parts[..] {
+            [height, txid, vout] => (height, txid, vout),
+            _ => bail!("invalid utxo cursor"),
+        };
+        Ok(UtxoCursor {
+            height: match height {
+                "mempool" => MEMPOOL_HEIGHT,
+                height => height.parse().chain_err(|| "invalid utxo cursor height")?,
+            },
+            txid: Txid::from_hex(txid).chain_err(|| "invalid utxo cursor txid")?,
+            vout: vout.parse().chain_err(|| "invalid utxo cursor vout")?,
+        })
+    }
+}
+
+// Confidential values are only matched when no minimum value is requested
+#[cfg(not(feature = "liquid"))]
+fn explicit_value(utxo: &Utxo) -> Option<u64> {
+    Some(utxo.value)
+}
+
+#[cfg(feature = "liquid")]
+fn explicit_value(utxo: &Utxo) -> Option<u64> {
+    match utxo.value {
+        crate::chain::Value::Explicit(value) => Some(value),
+        _ => None,
+    }
+}
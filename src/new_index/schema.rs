@@ -1,5 +1,8 @@
 use bitcoin::blockdata::script::Script;
 use bitcoin::hashes::sha256d::Hash as Sha256dHash;
+use bitcoin::hashes::Hash;
+#[cfg(not(feature = "liquid"))]
+use bitcoin::util::bip158::GCSFilterWriter;
 #[cfg(not(feature = "liquid"))]
 use bitcoin::util::merkleblock::MerkleBlock;
 use bitcoin::{BlockHash, Txid, VarInt};
@@ -20,11 +23,14 @@ use std::collections::{BTreeSet, HashMap, HashSet};
 use std::path::Path;
 use std::sync::{Arc, RwLock};
 
+#[cfg(not(feature = "liquid"))]
+use crate::chain::Block;
 use crate::chain::{BlockHeader, Network, OutPoint, Transaction, TxOut, Value};
 use crate::config::Config;
 use crate::daemon::Daemon;
 use crate::errors::*;
 use crate::metrics::{HistogramOpts, HistogramTimer, HistogramVec, Metrics};
+use crate::ratelimit;
 use crate::util::{
     full_hash, has_prevout, is_spendable, script_to_address, BlockHeaderMeta, BlockId, BlockMeta,
     BlockStatus, Bytes, HeaderEntry, HeaderList,
@@ -32,11 +38,24 @@ use crate::util::{
 
 use crate::new_index::db::{DBFlush, DBRow, ReverseScanIterator, ScanIterator, DB};
 use crate::new_index::fetch::{start_fetcher, BlockEntry, FetchFrom};
+use crate::new_index::mempool::{RemovalReason, TxOverview};
 
 #[cfg(feature = "liquid")]
 use crate::elements::{asset, peg};
+#[cfg(not(feature = "liquid"))]
+use crate::util::{block_feeinfos, BlockFees, BlockStats};
 
 const MIN_HISTORY_ITEMS_TO_CACHE: usize = 100;
+const STATS_CHECKPOINT_INTERVAL: usize = 1000; // blocks
+
+// BIP158 basic filter parameters
+#[cfg(not(feature = "liquid"))]
+const FILTER_P: u8 = 19;
+#[cfg(not(feature = "liquid"))]
+const FILTER_M: u64 = 784_931;
+
+#[cfg(not(feature = "liquid"))]
+type FilterHeader = FullHash;
 
 pub struct Store {
     // TODO: should be column families
@@ -98,6 +117,19 @@ impl Store {
     pub fn done_initial_sync(&self) -> bool {
         self.txstore_db.get(b"t").is_some()
     }
+
+    // Block filters are chained from the genesis block, so they're either indexed for the whole
+    // chain or not at all
+    #[cfg(not(feature = "liquid"))]
+    fn has_block_filters(&self) -> bool {
+        let headers = self.indexed_headers.read().unwrap();
+        headers.header_by_height(0).map_or(true, |genesis| {
+            let blockhash = full_hash(&genesis.hash()[..]);
+            self.history_db
+                .get(&BlockRow::filter_header_key(blockhash))
+                .is_some()
+        })
+    }
 }
 
 type UtxoMap = HashMap<OutPoint, (BlockId, Value)>;
@@ -156,6 +188,71 @@ impl ScriptStats {
             spent_txo_sum: 0,
         }
     }
+
+    /// The funded minus the spent sum, which fails on an inconsistent index.
+    #[cfg(not(feature = "liquid"))]
+    pub fn balance(&self) -> Result<u64> {
+        self.funded_txo_sum
+            .checked_sub(self.spent_txo_sum)
+            .chain_err(|| "negative balance")
+    }
+}
+
+#[cfg(not(feature = "liquid"))]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BalanceInterval {
+    Tx,
+    Block,
+    Day,
+}
+
+// A point in the balance history of a scripthash, for a single tx or aggregated over a block/day
+#[cfg(not(feature = "liquid"))]
+#[derive(Serialize, Debug)]
+pub struct BalancePoint {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub txid: Option<Txid>,
+    pub height: usize, // the last block included in the point
+    pub time: u32,     // the block time, or the start of the day for daily points
+    pub tx_count: usize,
+    pub funded: u64,
+    pub spent: u64,
+    pub balance: u64, // the running balance after the point
+}
+
+/// Chain-wide totals as of some block
+#[cfg(not(feature = "liquid"))]
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct ChainStats {
+    pub tx_count: u64,
+    pub utxo_count: u64,
+    pub utxo_size: u64,     // serialized size of the unspent outputs, in bytes
+    pub supply: u64,        // total value of the unspent outputs
+    pub address_count: u64, // # of scripts ever funded
+}
+
+// The changes a single block makes to the ChainStats
+#[cfg(not(feature = "liquid"))]
+#[derive(Serialize, Deserialize, Default)]
+struct ChainStatsDelta {
+    tx_count: u64,
+    utxo_count: i64,
+    utxo_size: i64,
+    supply: i64,
+    new_addresses: u64,
+}
+
+#[cfg(not(feature = "liquid"))]
+impl ChainStats {
+    fn apply(&self, delta: &ChainStatsDelta) -> ChainStats {
+        ChainStats {
+            tx_count: self.tx_count + delta.tx_count,
+            utxo_count: (self.utxo_count as i64 + delta.utxo_count) as u64,
+            utxo_size: (self.utxo_size as i64 + delta.utxo_size) as u64,
+            supply: (self.supply as i64 + delta.supply) as u64,
+            address_count: self.address_count + delta.new_addresses,
+        }
+    }
 }
 
 pub struct Indexer {
@@ -164,12 +261,17 @@ pub struct Indexer {
     from: FetchFrom,
     iconfig: IndexerConfig,
     duration: HistogramVec,
+    touched: TouchedScripts,
+    events: Vec<Event>, // for the streaming clients
 }
 
 struct IndexerConfig {
     light_mode: bool,
     address_search: bool,
     index_unspendables: bool,
+    block_filters: bool,
+    #[cfg(not(feature = "liquid"))]
+    chain_stats: bool,
     network: Network,
     #[cfg(feature = "liquid")]
     parent_network: Network,
@@ -181,6 +283,9 @@ impl From<&Config> for IndexerConfig {
             light_mode: config.light_mode,
             address_search: config.address_search,
             index_unspendables: config.index_unspendables,
+            block_filters: config.block_filters,
+            #[cfg(not(feature = "liquid"))]
+            chain_stats: config.chain_stats,
             network: config.network_type,
             #[cfg(feature = "liquid")]
             parent_network: config.parent_network,
@@ -199,6 +304,12 @@ pub struct ChainQuery {
 // TODO: &[Block] should be an iterator / a queue.
 impl Indexer {
     pub fn open(store: Arc<Store>, from: FetchFrom, config: &Config, metrics: &Metrics) -> Self {
+        #[cfg(not(feature = "liquid"))]
+        {
+            if config.block_filters && !store.has_block_filters() {
+                panic!("block filters must be enabled from the initial sync, the index must be rebuilt to enable them");
+            }
+        }
         Indexer {
             store,
             flush: DBFlush::Disable,
@@ -208,6 +319,8 @@ impl Indexer {
                 HistogramOpts::new("index_duration", "Index update duration (in seconds)"),
                 &["step"],
             ),
+            touched: TouchedScripts::empty(),
+            events: vec![],
         }
     }
 
@@ -215,6 +328,16 @@ impl Indexer {
         self.duration.with_label_values(&[name]).start_timer()
     }
 
+    /// Returns the scripthashes touched by the blocks indexed since the last call.
+    pub fn take_touched(&mut self) -> TouchedScripts {
+        std::mem::replace(&mut self.touched, TouchedScripts::empty())
+    }
+
+    /// Returns the new blocks and confirmed script activity since the last call.
+    pub fn take_events(&mut self) -> Vec<Event> {
+        std::mem::replace(&mut self.events, vec![])
+    }
+
     fn headers_to_add(&self, new_headers: &[HeaderEntry]) -> Vec<HeaderEntry> {
         let added_blockhashes = self.store.added_blockhashes.read().unwrap();
         new_headers
@@ -258,6 +381,18 @@ impl Indexer {
         let daemon = daemon.reconnect()?;
         let tip = daemon.getbestblockhash()?;
         let new_headers = self.get_new_headers(&daemon, &tip)?;
+        // no subscribers can exist before the initial sync is done
+        let synced = match self.flush {
+            DBFlush::Enable => true,
+            DBFlush::Disable => false,
+        };
+
+        // a reorg invalidates history we can't cheaply enumerate, so notify everything
+        if let Some(first) = new_headers.first() {
+            if first.height() < self.store.indexed_headers.read().unwrap().len() {
+                self.touched = TouchedScripts::All;
+            }
+        }
 
         let to_add = self.headers_to_add(&new_headers);
         debug!(
@@ -274,7 +409,17 @@ impl Indexer {
             to_index.len(),
             self.from
         );
-        start_fetcher(self.from, &daemon, to_index)?.map(|blocks| self.index(&blocks));
+        start_fetcher(self.from, &daemon, to_index)?
+            .map(|blocks| self.index(&blocks, &new_headers));
+        #[cfg(not(feature = "liquid"))]
+        {
+            if self.iconfig.block_filters {
+                self.index_filter_headers(&new_headers);
+            }
+            if self.iconfig.chain_stats {
+                self.index_chain_stats(&new_headers);
+            }
+        }
         self.start_auto_compactions(&self.store.history_db);
 
         if let DBFlush::Disable = self.flush {
@@ -288,6 +433,13 @@ impl Indexer {
         debug!("updating synced tip to {:?}", tip);
         self.store.txstore_db.put_sync(b"t", &serialize(&tip));
 
+        if synced {
+            self.events.extend(
+                new_headers
+                    .iter()
+                    .map(|entry| Event::Block(BlockId::from(entry))),
+            );
+        }
         let mut headers = self.store.indexed_headers.write().unwrap();
         headers.apply(new_headers);
         assert_eq!(tip, *headers.tip());
@@ -317,7 +469,8 @@ impl Indexer {
             .extend(blocks.iter().map(|b| b.entry.hash()));
     }
 
-    fn index(&self, blocks: &[BlockEntry]) {
+    #[cfg_attr(feature = "liquid", allow(unused_variables))]
+    fn index(&mut self, blocks: &[BlockEntry], new_headers: &[HeaderEntry]) {
         let previous_txos_map = {
             let _timer = self.start_timer("index_lookup");
             lookup_txos(&self.store.txstore_db, &get_previous_txos(blocks), false)
@@ -332,10 +485,130 @@ impl Indexer {
                     panic!("cannot index block {} (missing from store)", blockhash);
                 }
             }
+            #[cfg(not(feature = "liquid"))]
+            {
+                self.store.txstore_db.write(
+                    block_stats_rows(blocks, &previous_txos_map, &self.iconfig),
+                    self.flush,
+                );
+                if self.iconfig.chain_stats {
+                    // the new headers replace the indexed ones from the fork point
+                    let headers = self.store.indexed_headers.read().unwrap();
+                    let in_best_chain = |height: usize, blockhash: &FullHash| {
+                        let entry = match new_headers.first() {
+                            Some(first) if height >= first.height() => {
+                                new_headers.get(height - first.height())
+                            }
+                            _ => headers.header_by_height(height),
+                        };
+                        entry.map_or(false, |entry| full_hash(&entry.hash()[..]) == *blockhash)
+                    };
+                    let txstore_db = &self.store.txstore_db;
+                    txstore_db.write(
+                        chain_stats_rows(blocks, &previous_txos_map, txstore_db, in_best_chain),
+                        self.flush,
+                    );
+                }
+            }
             index_blocks(blocks, &previous_txos_map, &self.iconfig)
         };
+        // no subscribers can exist before the initial sync is done
+        if let DBFlush::Enable = self.flush {
+            for row in rows.iter().filter(|row| row.key[0] == b'H') {
+                self.touched.insert(full_hash(&row.key[1..33]));
+                let key: TxHistoryKey = bincode::config()
+                    .big_endian()
+                    .deserialize(&row.key)
+                    .expect("failed to deserialize TxHistoryKey");
+                self.events.push(Event::ScriptActivity {
+                    scripthash: key.hash,
+                    txid: key.txinfo.get_txid(),
+                    confirmed: true,
+                });
+            }
+        }
         self.store.history_db.write(rows, self.flush);
     }
+
+    // Block filters may be indexed out of order (when reading blk*.dat files), so the filter
+    // headers are chained afterwards, following the order of the new headers.
+    #[cfg(not(feature = "liquid"))]
+    fn index_filter_headers(&self, new_headers: &[HeaderEntry]) {
+        let _timer = self.start_timer("index_filter_headers");
+        let history_db = &self.store.history_db;
+        let mut rows = vec![];
+        let mut prev_filter_header: Option<FilterHeader> = None;
+        for entry in new_headers {
+            let prev = prev_filter_header.take().or_else(|| {
+                if entry.height() == 0 {
+                    Some([0; 32])
+                } else {
+                    let prev_blockhash = full_hash(&entry.header().prev_blockhash[..]);
+                    history_db
+                        .get(&BlockRow::filter_header_key(prev_blockhash))
+                        .map(|val| full_hash(&val))
+                }
+            });
+            let blockhash = full_hash(&entry.hash()[..]);
+            let filter = history_db.get(&BlockRow::filter_key(blockhash));
+            match (prev, filter) {
+                (Some(prev), Some(filter)) => {
+                    let filter_header = compute_filter_header(&filter, &prev);
+                    rows.push(BlockRow::new_filter_header(blockhash, &filter_header).into_row());
+                    prev_filter_header = Some(filter_header);
+                }
+                _ => {
+                    warn!(
+                        "missing block filter for {:?}, block filters must be enabled from the initial sync",
+                        entry
+                    );
+                    break;
+                }
+            }
+        }
+        history_db.write(rows, self.flush);
+    }
+
+    // Like the filter headers, the chain stats are accumulated following the order of the new
+    // headers. They're keyed by blockhash, so a reorg simply continues from the fork point.
+    #[cfg(not(feature = "liquid"))]
+    fn index_chain_stats(&self, new_headers: &[HeaderEntry]) {
+        let _timer = self.start_timer("index_chain_stats");
+        let txstore_db = &self.store.txstore_db;
+        let mut rows = vec![];
+        let mut prev_stats: Option<ChainStats> = None;
+        for entry in new_headers {
+            let prev = prev_stats.take().or_else(|| {
+                if entry.height() == 0 {
+                    Some(ChainStats::default())
+                } else {
+                    let prev_blockhash = full_hash(&entry.header().prev_blockhash[..]);
+                    txstore_db
+                        .get(&BlockRow::chain_stats_key(prev_blockhash))
+                        .map(|val| bincode::deserialize(&val).expect("failed to parse ChainStats"))
+                }
+            });
+            let blockhash = full_hash(&entry.hash()[..]);
+            let delta: Option<ChainStatsDelta> = txstore_db
+                .get(&BlockRow::chain_stats_delta_key(blockhash))
+                .map(|val| bincode::deserialize(&val).expect("failed to parse ChainStatsDelta"));
+            match (prev, delta) {
+                (Some(prev), Some(delta)) => {
+                    let stats = prev.apply(&delta);
+                    rows.push(BlockRow::new_chain_stats(blockhash, &stats).into_row());
+                    prev_stats = Some(stats);
+                }
+                _ => {
+                    warn!(
+                        "missing chain stats for {:?}, chain stats must be enabled from the initial sync",
+                        entry
+                    );
+                    break;
+                }
+            }
+        }
+        txstore_db.write(rows, self.flush);
+    }
 }
 
 impl ChainQuery {
@@ -393,6 +666,72 @@ impl ChainQuery {
         }
     }
 
+    #[cfg(not(feature = "liquid"))]
+    pub fn get_block_stats(&self, hash: &BlockHash) -> Option<BlockStats> {
+        let _timer = self.start_timer("get_block_stats");
+        self.store
+            .txstore_db
+            .get(&BlockRow::stats_key(full_hash(&hash[..])))
+            .map(|val| bincode::deserialize(&val).expect("failed to parse BlockStats"))
+    }
+
+    #[cfg(not(feature = "liquid"))]
+    pub fn get_block_fees(&self, hash: &BlockHash) -> Option<BlockFees> {
+        let _timer = self.start_timer("get_block_fees");
+        self.store
+            .txstore_db
+            .get(&BlockRow::fees_key(full_hash(&hash[..])))
+            .map(|val| bincode::deserialize(&val).expect("failed to parse BlockFees"))
+    }
+
+    /// Returns the chain stats as of the given block (requires `--chain-stats`).
+    #[cfg(not(feature = "liquid"))]
+    pub fn get_chain_stats(&self, hash: &BlockHash) -> Option<ChainStats> {
+        let _timer = self.start_timer("get_chain_stats");
+        self.store
+            .txstore_db
+            .get(&BlockRow::chain_stats_key(full_hash(&hash[..])))
+            .map(|val| bincode::deserialize(&val).expect("failed to parse ChainStats"))
+    }
+
+    /// Returns the BIP158 basic filter of the block (requires `--block-filters`).
+    #[cfg(not(feature = "liquid"))]
+    pub fn get_block_filter(&self, hash: &BlockHash) -> Option<Bytes> {
+        let _timer = self.start_timer("get_block_filter");
+        self.store
+            .history_db
+            .get(&BlockRow::filter_key(full_hash(&hash[..])))
+    }
+
+    /// Returns the BIP157 filter header of the block (requires `--block-filters`).
+    #[cfg(not(feature = "liquid"))]
+    pub fn get_filter_header(&self, hash: &BlockHash) -> Option<Sha256dHash> {
+        let _timer = self.start_timer("get_filter_header");
+        self.store
+            .history_db
+            .get(&BlockRow::filter_header_key(full_hash(&hash[..])))
+            .map(|val| Sha256dHash::from_inner(full_hash(&val)))
+    }
+
+    /// Returns the time (in unix seconds) the confirmed tx was first seen in the mempool,
+    /// if it went through our mempool.
+    pub fn get_first_seen(&self, txid: &Txid) -> Option<u64> {
+        let _timer = self.start_timer("get_first_seen");
+        self.store
+            .txstore_db
+            .get(&TxFirstSeenRow::key(&txid[..]))
+            .map(|val| bincode::deserialize(&val).expect("failed to parse first seen time"))
+    }
+
+    /// Saves the mempool first-seen times of txs that got confirmed.
+    pub fn save_first_seen(&self, first_seen: &[(Txid, u64)]) {
+        let rows = first_seen
+            .iter()
+            .map(|(txid, time)| TxFirstSeenRow::new(txid, *time).into_row())
+            .collect();
+        self.store.txstore_db.write(rows, DBFlush::Enable);
+    }
+
     pub fn get_block_raw(&self, hash: &BlockHash) -> Option<Vec<u8>> {
         let _timer = self.start_timer("get_block_raw");
 
@@ -497,6 +836,59 @@ impl ChainQuery {
             .collect()
     }
 
+    /// Get the confirmed history of the scripthash between two heights (inclusive), in ascending
+    /// or descending order, resuming after `last_seen_txid`.
+    pub fn history_range_txids(
+        &self,
+        scripthash: &[u8],
+        start_height: usize,
+        end_height: usize,
+        descending: bool,
+        last_seen_txid: Option<&Txid>,
+        limit: usize,
+    ) -> Vec<(Txid, BlockId)> {
+        let _timer = self.start_timer("history_range");
+        let end_height = end_height.min(self.best_height());
+        if start_height > end_height {
+            return vec![];
+        }
+
+        let rows: Box<dyn Iterator<Item = TxHistoryRow> + '_> = if descending {
+            Box::new(
+                self.store
+                    .history_db
+                    .iter_scan_reverse(
+                        &TxHistoryRow::filter(b'H', scripthash),
+                        &TxHistoryRow::prefix_height(b'H', scripthash, end_height as u32 + 1),
+                    )
+                    .map(TxHistoryRow::from_row)
+                    .take_while(move |row| row.key.confirmed_height as usize >= start_height),
+            )
+        } else {
+            Box::new(
+                self.history_iter_scan(b'H', scripthash, start_height)
+                    .map(TxHistoryRow::from_row)
+                    .take_while(move |row| row.key.confirmed_height as usize <= end_height),
+            )
+        };
+
+        rows.map(|row| row.get_txid())
+            .unique()
+            .skip_while(|txid| {
+                // skip until we reach the last_seen_txid
+                last_seen_txid.map_or(false, |last_seen_txid| last_seen_txid != txid)
+            })
+            .skip(match last_seen_txid {
+                Some(_) => 1, // skip the last_seen_txid itself
+                None => 0,
+            })
+            .filter_map(|txid| self.tx_confirming_block(&txid).map(|b| (txid, b)))
+            // rows of orphaned blocks may have been confirmed again at a different height
+            .filter(|(_, blockid)| blockid.height >= start_height && blockid.height <= end_height)
+            .take(limit)
+            .collect()
+    }
+
     pub fn history_txids(&self, scripthash: &[u8], limit: usize) -> Vec<(Txid, BlockId)> {
         // scripthash lookup
         self._history_txids(b'H', scripthash, limit)
@@ -549,28 +941,57 @@ impl ChainQuery {
         // format as Utxo objects
         Ok(newutxos
             .into_iter()
-            .map(|(outpoint, (blockid, value))| {
-                // in elements/liquid chains, we have to lookup the txo in order to get its
-                // associated asset. the asset information could be kept in the db history rows
-                // alongside the value to avoid this.
-                #[cfg(feature = "liquid")]
-                let txo = self.lookup_txo(&outpoint).expect("missing utxo");
-
-                Utxo {
-                    txid: outpoint.txid,
-                    vout: outpoint.vout,
-                    value,
-                    confirmed: Some(blockid),
-
-                    #[cfg(feature = "liquid")]
-                    asset: txo.asset,
-                    #[cfg(feature = "liquid")]
-                    nonce: txo.nonce,
-                    #[cfg(feature = "liquid")]
-                    witness: txo.witness,
+            .map(|(outpoint, (blockid, value))| self.make_utxo(outpoint, blockid, value))
+            .collect())
+    }
+
+    /// Iterates over the confirmed utxos of the script that were funded at or after
+    /// `start_height`, in height order. Unlike utxo(), each funded output is checked for spends
+    /// individually, so a part of the utxo set can be read without loading all of it.
+    pub fn utxos_from<'a>(
+        &'a self,
+        scripthash: &[u8],
+        start_height: usize,
+    ) -> impl Iterator<Item = Utxo> + 'a {
+        self.history_iter_scan(b'H', scripthash, start_height)
+            .map(TxHistoryRow::from_row)
+            .filter_map(move |history| {
+                let value = match history.key.txinfo {
+                    TxHistoryInfo::Funding(ref info) => info.value,
+                    _ => return None,
+                };
+                let outpoint = history.get_funded_outpoint();
+                if self.lookup_spend(&outpoint).is_some() {
+                    return None;
                 }
+                // skip the rows of orphaned blocks
+                let blockid = self
+                    .tx_confirming_block(&outpoint.txid)
+                    .filter(|blockid| blockid.height == history.key.confirmed_height as usize)?;
+                Some(self.make_utxo(outpoint, blockid, value))
             })
-            .collect())
+    }
+
+    fn make_utxo(&self, outpoint: OutPoint, blockid: BlockId, value: Value) -> Utxo {
+        // in elements/liquid chains, we have to lookup the txo in order to get its
+        // associated asset. the asset information could be kept in the db history rows
+        // alongside the value to avoid this.
+        #[cfg(feature = "liquid")]
+        let txo = self.lookup_txo(&outpoint).expect("missing utxo");
+
+        Utxo {
+            txid: outpoint.txid,
+            vout: outpoint.vout,
+            value,
+            confirmed: Some(blockid),
+
+            #[cfg(feature = "liquid")]
+            asset: txo.asset,
+            #[cfg(feature = "liquid")]
+            nonce: txo.nonce,
+            #[cfg(feature = "liquid")]
+            witness: txo.witness,
+        }
     }
 
     fn utxo_delta(
@@ -635,8 +1056,8 @@ impl ChainQuery {
 
         // update stats with new transactions since
         let (newstats, lastblock) = cache.map_or_else(
-            || self.stats_delta(scripthash, ScriptStats::default(), 0),
-            |(oldstats, blockheight)| self.stats_delta(scripthash, oldstats, blockheight + 1),
+            || self.stats_delta(scripthash, ScriptStats::default(), 0, None),
+            |(oldstats, blockheight)| self.stats_delta(scripthash, oldstats, blockheight + 1, None),
         );
 
         // save updated stats to cache
@@ -652,20 +1073,76 @@ impl ChainQuery {
         newstats
     }
 
+    /// Get the stats of the scripthash as of the given height (including the block at that height).
+    pub fn stats_at(&self, scripthash: &[u8], height: usize) -> ScriptStats {
+        let _timer = self.start_timer("stats_at");
+        let height = height.min(self.best_height());
+
+        // start from the last checkpoint that is still part of the best chain.
+        // a checkpoint at height `h` holds the stats of the blocks below `h`.
+        let checkpoint_height =
+            (height + 1) / STATS_CHECKPOINT_INTERVAL * STATS_CHECKPOINT_INTERVAL;
+        let (stats, stats_height) = self
+            .store
+            .cache_db
+            .iter_scan_reverse(
+                &StatsCheckpointRow::filter(scripthash),
+                &StatsCheckpointRow::key(scripthash, checkpoint_height),
+            )
+            .map(StatsCheckpointRow::from_row)
+            .find(|(_, height, blockhash)| {
+                self.height_by_hash(blockhash).map(|h| h + 1) == Some(*height)
+            })
+            .map_or_else(
+                || (ScriptStats::default(), 0),
+                |(stats, height, _)| (stats, height),
+            );
+
+        // save the stats at the new checkpoint, if there are enough history items to make it useful
+        let stats = if checkpoint_height > stats_height {
+            let (stats, _) =
+                self.stats_delta(scripthash, stats, stats_height, Some(checkpoint_height - 1));
+            if stats.funded_txo_count + stats.spent_txo_count > MIN_HISTORY_ITEMS_TO_CACHE {
+                if let Some(blockhash) = self.hash_by_height(checkpoint_height - 1) {
+                    self.store.cache_db.write(
+                        vec![StatsCheckpointRow::new(
+                            scripthash,
+                            checkpoint_height,
+                            &stats,
+                            &blockhash,
+                        )],
+                        DBFlush::Enable,
+                    );
+                }
+            }
+            stats
+        } else {
+            stats
+        };
+
+        let (stats, _) = self.stats_delta(scripthash, stats, checkpoint_height, Some(height));
+        stats
+    }
+
     fn stats_delta(
         &self,
         scripthash: &[u8],
         init_stats: ScriptStats,
         start_height: usize,
+        end_height: Option<usize>, // inclusive
     ) -> (ScriptStats, Option<BlockHash>) {
         let _timer = self.start_timer("stats_delta"); // TODO: measure also the number of txns processed.
+        let end_height = end_height.unwrap_or(std::usize::MAX);
         let history_iter = self
             .history_iter_scan(b'H', scripthash, start_height)
             .map(TxHistoryRow::from_row)
+            .take_while(|history| history.key.confirmed_height as usize <= end_height)
             .filter_map(|history| {
                 self.tx_confirming_block(&history.get_txid())
                     .map(|blockid| (history, blockid))
-            });
+            })
+            // rows of orphaned blocks may have been confirmed again at a later height
+            .filter(|(_, blockid)| blockid.height <= end_height);
 
         let mut stats = init_stats;
         let mut seen_txids = HashSet::new();
@@ -716,6 +1193,119 @@ impl ChainQuery {
         (stats, lastblock)
     }
 
+    /// Get the running balance of the scripthash, starting at `start_height`, in pages of at least
+    /// `limit` points (pages are only cut at point boundaries). Returns the next page start height,
+    /// if there are more results.
+    #[cfg(not(feature = "liquid"))]
+    pub fn balance_history(
+        &self,
+        scripthash: &[u8],
+        interval: BalanceInterval,
+        start_height: usize,
+        limit: usize,
+    ) -> Result<(Vec<BalancePoint>, Option<usize>)> {
+        let _timer = self.start_timer("balance_history");
+        let mut balance = match start_height {
+            0 => 0,
+            height => self
+                .stats_at(scripthash, height - 1)
+                .balance()
+                .chain_err(|| format!("invalid balance at height {}", height - 1))?,
+        };
+
+        let mut rows = self
+            .history_iter_scan(b'H', scripthash, start_height)
+            .map(TxHistoryRow::from_row)
+            .filter_map(|history| {
+                self.tx_confirming_block(&history.get_txid())
+                    .map(|blockid| (history, blockid))
+            })
+            // skip rows of orphaned blocks (the rows of the confirming block are kept instead)
+            .filter(|(history, blockid)| history.key.confirmed_height as usize == blockid.height)
+            .peekable();
+
+        let mut points: Vec<BalancePoint> = vec![];
+        let mut next_height = None;
+        while let Some(blockid) = rows.peek().map(|(_, blockid)| blockid.clone()) {
+            let day_start = blockid.time - blockid.time % 86400;
+            let extends_last = interval == BalanceInterval::Day
+                && points.last().map_or(false, |point| point.time == day_start);
+            if points.len() >= limit && !extends_last {
+                next_height = Some(blockid.height);
+                break;
+            }
+
+            // sum the funded/spent amounts per tx, ordered by their position in the block
+            let mut deltas: HashMap<Txid, (u64, u64)> = HashMap::new();
+            while rows
+                .peek()
+                .map_or(false, |(_, b)| b.height == blockid.height)
+            {
+                let (history, _) = rows.next().unwrap();
+                let delta = deltas.entry(history.get_txid()).or_default();
+                match history.key.txinfo {
+                    TxHistoryInfo::Funding(ref info) => delta.0 += info.value,
+                    TxHistoryInfo::Spending(ref info) => delta.1 += info.value,
+                }
+            }
+            let mut txs: Vec<(Txid, (u64, u64))> = deltas.into_iter().collect();
+            if txs.len() > 1 {
+                let positions: HashMap<Txid, usize> = self
+                    .get_block_txids(&blockid.hash)
+                    .chain_err(|| format!("missing txids of block {}", blockid.hash))?
+                    .into_iter()
+                    .enumerate()
+                    .map(|(pos, txid)| (txid, pos))
+                    .collect();
+                txs.sort_by_key(|(txid, _)| positions.get(txid).cloned());
+            }
+
+            for (txid, (funded, spent)) in txs {
+                balance = balance
+                    .checked_add(funded)
+                    .and_then(|balance| balance.checked_sub(spent))
+                    .chain_err(|| format!("invalid balance after tx {}", txid))?;
+                match interval {
+                    BalanceInterval::Tx => points.push(BalancePoint {
+                        txid: Some(txid),
+                        height: blockid.height,
+                        time: blockid.time,
+                        tx_count: 1,
+                        funded,
+                        spent,
+                        balance,
+                    }),
+                    BalanceInterval::Block | BalanceInterval::Day => {
+                        let (time, same_bucket) = match interval {
+                            BalanceInterval::Day => (day_start, extends_last),
+                            _ => (blockid.time, false),
+                        };
+                        match points.last_mut() {
+                            Some(point) if same_bucket || point.height == blockid.height => {
+                                point.height = blockid.height;
+                                point.tx_count += 1;
+                                point.funded += funded;
+                                point.spent += spent;
+                                point.balance = balance;
+                            }
+                            _ => points.push(BalancePoint {
+                                txid: None,
+                                height: blockid.height,
+                                time,
+                                tx_count: 1,
+                                funded,
+                                spent,
+                                balance,
+                            }),
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok((points, next_height))
+    }
+
     pub fn address_search(&self, prefix: &str, limit: usize) -> Vec<String> {
         let _timer_scan = self.start_timer("address_search");
         self.store
@@ -782,6 +1372,14 @@ impl ChainQuery {
             .map(BlockId::from)
     }
 
+    pub fn height_by_time(&self, time: u32) -> usize {
+        self.store
+            .indexed_headers
+            .read()
+            .unwrap()
+            .height_by_time(time)
+    }
+
     pub fn best_height(&self) -> usize {
         self.store.indexed_headers.read().unwrap().len() - 1
     }
@@ -802,12 +1400,15 @@ impl ChainQuery {
     // TODO: should also use a custom ThreadPoolBuilder?
     pub fn lookup_txns(&self, txids: &[(Txid, BlockId)]) -> Result<Vec<Transaction>> {
         let _timer = self.start_timer("lookup_txns");
-        txids
+        let txns: Vec<(Option<Transaction>, u64)> = txids
             .par_iter()
             .map(|(txid, blockid)| {
-                self.lookup_txn(txid, Some(&blockid.hash))
-                    .chain_err(|| "missing tx")
+                ratelimit::measure_scanned_rows(|| self.lookup_txn(txid, Some(&blockid.hash)))
             })
+            .collect();
+        ratelimit::record_scanned_rows(txns.iter().map(|(_, rows)| rows).sum());
+        txns.into_iter()
+            .map(|(txn, _)| txn.chain_err(|| "missing tx"))
             .collect::<Result<Vec<Transaction>>>()
     }
 
@@ -1028,21 +1629,27 @@ fn lookup_txos(
         .thread_name(|i| format!("lookup-txo-{}", i))
         .build()
         .unwrap();
-    pool.install(|| {
+    let txos: Vec<(Option<TxOut>, u64)> = pool.install(|| {
         outpoints
             .par_iter()
-            .filter_map(|outpoint| {
-                lookup_txo(&txstore_db, &outpoint)
-                    .or_else(|| {
+            .map(|outpoint| {
+                ratelimit::measure_scanned_rows(|| {
+                    lookup_txo(&txstore_db, &outpoint).or_else(|| {
                         if !allow_missing {
                             panic!("missing txo {} in {:?}", outpoint, txstore_db);
                         }
                         None
                     })
-                    .map(|txo| (*outpoint, txo))
+                })
             })
             .collect()
-    })
+    });
+    ratelimit::record_scanned_rows(txos.iter().map(|(_, rows)| rows).sum());
+    outpoints
+        .iter()
+        .zip(txos)
+        .filter_map(|(outpoint, (txo, _))| txo.map(|txo| (*outpoint, txo)))
+        .collect()
 }
 
 fn lookup_txo(txstore_db: &DB, outpoint: &OutPoint) -> Option<TxOut> {
@@ -1064,6 +1671,15 @@ fn index_blocks(
                 let height = b.entry.height() as u32;
                 index_transaction(tx, height, previous_txos_map, &mut rows, iconfig);
             }
+            #[cfg(not(feature = "liquid"))]
+            {
+                if iconfig.block_filters {
+                    let filter = compute_block_filter(b.entry.hash(), &b.block, previous_txos_map);
+                    rows.push(
+                        BlockRow::new_filter(full_hash(&b.entry.hash()[..]), filter).into_row(),
+                    );
+                }
+            }
             rows.push(BlockRow::new_done(full_hash(&b.entry.hash()[..])).into_row()); // mark block as "indexed"
             rows
         })
@@ -1071,6 +1687,185 @@ fn index_blocks(
         .collect()
 }
 
+// persist block stats and fees, next to the block metadata:
+//      Z{blockhash} → {block-stats}
+//      E{blockhash} → {block-fees}
+#[cfg(not(feature = "liquid"))]
+fn block_stats_rows(
+    block_entries: &[BlockEntry],
+    previous_txos_map: &HashMap<OutPoint, TxOut>,
+    iconfig: &IndexerConfig,
+) -> Vec<DBRow> {
+    block_entries
+        .par_iter()
+        .map(|b| {
+            let blockhash = full_hash(&b.entry.hash()[..]);
+            let feeinfos = block_feeinfos(b, previous_txos_map, iconfig.network);
+            let stats = BlockStats::new(b, previous_txos_map, &feeinfos);
+            let fees = BlockFees::new(&feeinfos);
+            vec![
+                BlockRow::new_stats(blockhash, &stats).into_row(),
+                BlockRow::new_fees(blockhash, &fees).into_row(),
+            ]
+        })
+        .flatten()
+        .collect()
+}
+
+// persist the changes each block makes to the chain stats:
+//      q{blockhash} → {chain-stats-delta}
+//      a{scripthash} → {first-fundings}
+// a script counts as a new address for the lowest block of the best chain funding it.
+#[cfg(not(feature = "liquid"))]
+fn chain_stats_rows<F>(
+    block_entries: &[BlockEntry],
+    previous_txos_map: &HashMap<OutPoint, TxOut>,
+    txstore_db: &DB,
+    in_best_chain: F,
+) -> Vec<DBRow>
+where
+    F: Fn(usize, &FullHash) -> bool + Sync,
+{
+    // the first block of the batch funding each script
+    let mut first_funded: HashMap<FullHash, (usize, FullHash)> = HashMap::new();
+    for b in block_entries {
+        let height = b.entry.height();
+        let blockhash = full_hash(&b.entry.hash()[..]);
+        for txo in b.block.txdata.iter().flat_map(|tx| tx.output.iter()) {
+            if is_spendable(txo) {
+                let scripthash = compute_script_hash(&txo.script_pubkey);
+                let first = first_funded
+                    .entry(scripthash)
+                    .or_insert((height, blockhash));
+                if height < first.0 {
+                    *first = (height, blockhash);
+                }
+            }
+        }
+    }
+
+    // scripts funded by previously indexed blocks of the best chain aren't new. the first
+    // funding of the other branches is kept too, in case they become the best chain again.
+    let new_addresses: Vec<(usize, Option<FullHash>, DBRow)> = first_funded
+        .into_par_iter()
+        .filter_map(|(scripthash, (height, blockhash))| {
+            let key = first_funding_key(&scripthash);
+            let mut fundings: Vec<(u32, FullHash)> = txstore_db
+                .get(&key)
+                .map(|val| bincode::deserialize(&val).expect("failed to parse first fundings"))
+                .unwrap_or_default();
+            let first = fundings
+                .iter()
+                .filter(|(height, blockhash)| in_best_chain(*height as usize, blockhash))
+                .min_by_key(|(height, _)| *height)
+                .cloned();
+            match first {
+                Some((first_height, _)) if first_height as usize <= height => None,
+                // blocks may be indexed out of order, in which case the script was
+                // wrongly counted as new by the later block
+                superseded => {
+                    fundings.push((height as u32, blockhash));
+                    let value = bincode::serialize(&fundings).unwrap();
+                    let superseded = superseded.map(|(_, blockhash)| blockhash);
+                    Some((height, superseded, DBRow { key, value }))
+                }
+            }
+        })
+        .collect();
+    let mut new_addresses_by_height: HashMap<usize, u64> = HashMap::new();
+    let mut superseded_by_block: HashMap<FullHash, u64> = HashMap::new();
+    let mut rows = vec![];
+    for (height, superseded, row) in new_addresses {
+        *new_addresses_by_height.entry(height).or_insert(0) += 1;
+        if let Some(blockhash) = superseded {
+            *superseded_by_block.entry(blockhash).or_insert(0) += 1;
+        }
+        rows.push(row);
+    }
+    for (blockhash, count) in superseded_by_block {
+        let key = BlockRow::chain_stats_delta_key(blockhash);
+        if let Some(val) = txstore_db.get(&key) {
+            let mut delta: ChainStatsDelta =
+                bincode::deserialize(&val).expect("failed to parse ChainStatsDelta");
+            delta.new_addresses = delta.new_addresses.saturating_sub(count);
+            rows.push(BlockRow::new_chain_stats_delta(blockhash, &delta).into_row());
+        }
+    }
+
+    rows.par_extend(block_entries.par_iter().map(|b| {
+        let mut delta = ChainStatsDelta {
+            tx_count: b.block.txdata.len() as u64,
+            new_addresses: *new_addresses_by_height.get(&b.entry.height()).unwrap_or(&0),
+            ..Default::default()
+        };
+        for tx in &b.block.txdata {
+            for txo in tx.output.iter().filter(|txo| is_spendable(txo)) {
+                delta.utxo_count += 1;
+                delta.utxo_size += serialize(txo).len() as i64;
+                delta.supply += txo.value as i64;
+            }
+            for txin in tx.input.iter().filter(|txin| has_prevout(txin)) {
+                let prevout = previous_txos_map
+                    .get(&txin.previous_output)
+                    .expect("missing prevout for chain stats");
+                delta.utxo_count -= 1;
+                delta.utxo_size -= serialize(prevout).len() as i64;
+                delta.supply -= prevout.value as i64;
+            }
+        }
+        BlockRow::new_chain_stats_delta(full_hash(&b.entry.hash()[..]), &delta).into_row()
+    }));
+    rows
+}
+
+#[cfg(not(feature = "liquid"))]
+fn first_funding_key(scripthash: &FullHash) -> Bytes {
+    [b"a", &scripthash[..]].concat()
+}
+
+// Builds the BIP158 basic filter of the block, keyed by its (heavy_hash) blockhash.
+// The filter matches all output scripts (except OP_RETURN ones) and all spent prevout scripts.
+#[cfg(not(feature = "liquid"))]
+fn compute_block_filter(
+    blockhash: &BlockHash,
+    block: &Block,
+    previous_txos_map: &HashMap<OutPoint, TxOut>,
+) -> Bytes {
+    let blockhash = &blockhash[..];
+    let k0 = u64::from_le_bytes(*array_ref![blockhash, 0, 8]);
+    let k1 = u64::from_le_bytes(*array_ref![blockhash, 8, 8]);
+
+    let mut filter = vec![];
+    {
+        let mut writer = GCSFilterWriter::new(&mut filter, k0, k1, FILTER_M, FILTER_P);
+        for tx in &block.txdata {
+            for txo in &tx.output {
+                if !txo.script_pubkey.is_empty() && !txo.script_pubkey.is_op_return() {
+                    writer.add_element(txo.script_pubkey.as_bytes());
+                }
+            }
+            for txin in tx.input.iter().filter(|txin| has_prevout(txin)) {
+                let prevout = previous_txos_map
+                    .get(&txin.previous_output)
+                    .expect("missing prevout for block filter");
+                // empty scripts are not part of the filter, whether created or spent
+                if !prevout.script_pubkey.is_empty() {
+                    writer.add_element(prevout.script_pubkey.as_bytes());
+                }
+            }
+        }
+        writer.finish().expect("failed writing block filter");
+    }
+    filter
+}
+
+// filter-header = sha256d(sha256d(filter) || prev-filter-header)
+#[cfg(not(feature = "liquid"))]
+fn compute_filter_header(filter: &[u8], prev_filter_header: &FilterHeader) -> FilterHeader {
+    let filter_hash = Sha256dHash::hash(filter);
+    Sha256dHash::hash(&[&filter_hash[..], &prev_filter_header[..]].concat()).into_inner()
+}
+
 // TODO: return an iterator?
 fn index_transaction(
     tx: &Transaction,
@@ -1160,6 +1955,69 @@ fn addr_search_filter(prefix: &str) -> Bytes {
 // TODO: replace by a separate opaque type (similar to Sha256dHash, but without the "double")
 pub type FullHash = [u8; 32]; // serialized SHA256 result
 
+/// A chain or mempool update, pushed to the streaming clients
+pub enum Event {
+    Block(BlockId),                // a block connected to the best chain
+    MempoolTx(TxOverview),         // a tx that entered the mempool
+    FeeHistogram(Vec<(f32, u32)>), // the refreshed mempool fee histogram
+    // a tx funding or spending from the script entered the mempool or confirmed
+    ScriptActivity {
+        scripthash: FullHash,
+        txid: Txid,
+        confirmed: bool,
+    },
+    // a tx that left the mempool
+    TxRemoved {
+        txid: Txid,
+        reason: RemovalReason,
+    },
+}
+
+// Scripthashes whose history was affected by an index or mempool update, used to notify
+// subscribed clients. `All` is used when the affected set is unknown (e.g. after a reorg).
+#[derive(Debug)]
+pub enum TouchedScripts {
+    All,
+    Some(HashSet<FullHash>),
+}
+
+impl TouchedScripts {
+    pub fn empty() -> Self {
+        TouchedScripts::Some(HashSet::new())
+    }
+
+    pub fn insert(&mut self, scripthash: FullHash) {
+        if let TouchedScripts::Some(set) = self {
+            set.insert(scripthash);
+        }
+    }
+
+    pub fn extend(&mut self, other: TouchedScripts) {
+        match other {
+            TouchedScripts::All => *self = TouchedScripts::All,
+            TouchedScripts::Some(other) => {
+                if let TouchedScripts::Some(set) = self {
+                    set.extend(other);
+                }
+            }
+        }
+    }
+
+    pub fn contains(&self, scripthash: &FullHash) -> bool {
+        match self {
+            TouchedScripts::All => true,
+            TouchedScripts::Some(set) => set.contains(scripthash),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        match self {
+            TouchedScripts::All => false,
+            TouchedScripts::Some(set) => set.is_empty(),
+        }
+    }
+}
+
 pub fn compute_script_hash(script: &Script) -> FullHash {
     let mut hash = FullHash::default();
     let mut sha2 = Sha256::new();
@@ -1246,6 +2104,40 @@ impl TxConfRow {
     }
 }
 
+#[derive(Serialize, Deserialize)]
+struct TxFirstSeenKey {
+    code: u8,
+    txid: FullHash,
+}
+
+struct TxFirstSeenRow {
+    key: TxFirstSeenKey,
+    first_seen: u64, // unix seconds
+}
+
+impl TxFirstSeenRow {
+    fn new(txid: &Txid, first_seen: u64) -> TxFirstSeenRow {
+        TxFirstSeenRow {
+            key: TxFirstSeenKey {
+                code: b'f',
+                txid: full_hash(&txid[..]),
+            },
+            first_seen,
+        }
+    }
+
+    fn key(prefix: &[u8]) -> Bytes {
+        [b"f", prefix].concat()
+    }
+
+    fn into_row(self) -> DBRow {
+        DBRow {
+            key: bincode::serialize(&self.key).unwrap(),
+            value: bincode::serialize(&self.first_seen).unwrap(),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 struct TxOutKey {
     code: u8,
@@ -1322,6 +2214,38 @@ impl BlockRow {
         }
     }
 
+    #[cfg(not(feature = "liquid"))]
+    fn new_stats(hash: FullHash, stats: &BlockStats) -> BlockRow {
+        BlockRow {
+            key: BlockKey { code: b'Z', hash },
+            value: bincode::serialize(stats).unwrap(),
+        }
+    }
+
+    #[cfg(not(feature = "liquid"))]
+    fn new_fees(hash: FullHash, fees: &BlockFees) -> BlockRow {
+        BlockRow {
+            key: BlockKey { code: b'E', hash },
+            value: bincode::serialize(fees).unwrap(),
+        }
+    }
+
+    #[cfg(not(feature = "liquid"))]
+    fn new_chain_stats_delta(hash: FullHash, delta: &ChainStatsDelta) -> BlockRow {
+        BlockRow {
+            key: BlockKey { code: b'q', hash },
+            value: bincode::serialize(delta).unwrap(),
+        }
+    }
+
+    #[cfg(not(feature = "liquid"))]
+    fn new_chain_stats(hash: FullHash, stats: &ChainStats) -> BlockRow {
+        BlockRow {
+            key: BlockKey { code: b'Q', hash },
+            value: bincode::serialize(stats).unwrap(),
+        }
+    }
+
     fn new_done(hash: FullHash) -> BlockRow {
         BlockRow {
             key: BlockKey { code: b'D', hash },
@@ -1341,10 +2265,56 @@ impl BlockRow {
         [b"M", &hash[..]].concat()
     }
 
+    #[cfg(not(feature = "liquid"))]
+    fn stats_key(hash: FullHash) -> Bytes {
+        [b"Z", &hash[..]].concat()
+    }
+
+    #[cfg(not(feature = "liquid"))]
+    fn fees_key(hash: FullHash) -> Bytes {
+        [b"E", &hash[..]].concat()
+    }
+
+    #[cfg(not(feature = "liquid"))]
+    fn chain_stats_delta_key(hash: FullHash) -> Bytes {
+        [b"q", &hash[..]].concat()
+    }
+
+    #[cfg(not(feature = "liquid"))]
+    fn chain_stats_key(hash: FullHash) -> Bytes {
+        [b"Q", &hash[..]].concat()
+    }
+
     fn done_filter() -> Bytes {
         b"D".to_vec()
     }
 
+    #[cfg(not(feature = "liquid"))]
+    fn new_filter(hash: FullHash, filter: Bytes) -> BlockRow {
+        BlockRow {
+            key: BlockKey { code: b'G', hash },
+            value: filter,
+        }
+    }
+
+    #[cfg(not(feature = "liquid"))]
+    fn new_filter_header(hash: FullHash, filter_header: &FilterHeader) -> BlockRow {
+        BlockRow {
+            key: BlockKey { code: b'g', hash },
+            value: filter_header.to_vec(),
+        }
+    }
+
+    #[cfg(not(feature = "liquid"))]
+    fn filter_key(hash: FullHash) -> Bytes {
+        [b"G", &hash[..]].concat()
+    }
+
+    #[cfg(not(feature = "liquid"))]
+    fn filter_header_key(hash: FullHash) -> Bytes {
+        [b"g", &hash[..]].concat()
+    }
+
     fn into_row(self) -> DBRow {
         DBRow {
             key: bincode::serialize(&self.key).unwrap(),
@@ -1573,6 +2543,39 @@ impl StatsCacheRow {
     }
 }
 
+// Stats checkpoints are keyed by height (big-endian, for reverse scans), and kept next to the
+// blockhash of the last block they include.
+struct StatsCheckpointRow;
+
+impl StatsCheckpointRow {
+    fn new(scripthash: &[u8], height: usize, stats: &ScriptStats, blockhash: &BlockHash) -> DBRow {
+        DBRow {
+            key: StatsCheckpointRow::key(scripthash, height),
+            value: bincode::serialize(&(stats, blockhash)).unwrap(),
+        }
+    }
+
+    fn filter(scripthash: &[u8]) -> Bytes {
+        [b"K", scripthash].concat()
+    }
+
+    fn key(scripthash: &[u8], height: usize) -> Bytes {
+        bincode::config()
+            .big_endian()
+            .serialize(&(b'K', full_hash(scripthash), height as u32))
+            .unwrap()
+    }
+
+    fn from_row(row: DBRow) -> (ScriptStats, usize, BlockHash) {
+        let height: u32 = bincode::config()
+            .big_endian()
+            .deserialize(&row.key[row.key.len() - 4..])
+            .unwrap();
+        let (stats, blockhash) = bincode::deserialize(&row.value).unwrap();
+        (stats, height as usize, blockhash)
+    }
+}
+
 type CachedUtxoMap = HashMap<(Txid, u32), (u32, Value)>; // (txid,vout) => (block_height,output_value)
 
 struct UtxoCacheRow {
@@ -1631,3 +2634,62 @@ fn from_utxo_cache(utxos_cache: CachedUtxoMap, chain: &ChainQuery) -> UtxoMap {
         })
         .collect()
 }
+
+#[cfg(all(test, not(feature = "liquid")))]
+mod tests {
+    use super::*;
+    use bitcoin::hashes::hex::{FromHex, ToHex};
+
+    #[test]
+    fn test_block_filters() {
+        // the BIP158 test vectors, from bitcoin core's src/test/data/blockfilters.json
+        let vectors: Vec<serde_json::Value> =
+            serde_json::from_str(include_str!("../../test_data/blockfilters.json")).unwrap();
+        let hex = |value: &serde_json::Value| Vec::<u8>::from_hex(value.as_str().unwrap()).unwrap();
+
+        let mut prev_vector: Option<(u64, FilterHeader)> = None;
+        for vector in vectors.iter().skip(1) {
+            let vector = vector.as_array().unwrap();
+            let height = vector[0].as_u64().unwrap();
+            let blockhash = BlockHash::from_hex(vector[1].as_str().unwrap()).unwrap();
+            let block: Block = deserialize(&hex(&vector[2])).unwrap();
+
+            // the prevout scripts are listed in the order the inputs spend them
+            let mut prevout_scripts = vector[3].as_array().unwrap().iter();
+            let mut previous_txos_map = HashMap::new();
+            for txin in block.txdata.iter().flat_map(|tx| &tx.input) {
+                if has_prevout(txin) {
+                    let txo = TxOut {
+                        value: 0,
+                        script_pubkey: Script::from(hex(prevout_scripts.next().unwrap())),
+                    };
+                    previous_txos_map.insert(txin.previous_output, txo);
+                }
+            }
+            assert!(prevout_scripts.next().is_none());
+
+            let filter = compute_block_filter(&blockhash, &block, &previous_txos_map);
+            assert_eq!(filter, hex(&vector[5]), "filter at height {}", height);
+
+            let prev_filter_header = Sha256dHash::from_hex(vector[4].as_str().unwrap())
+                .unwrap()
+                .into_inner();
+            // the filter headers of consecutive blocks are chained, starting from zeros
+            match prev_vector {
+                Some((prev_height, prev_header)) if prev_height + 1 == height => {
+                    assert_eq!(prev_filter_header, prev_header)
+                }
+                None => assert_eq!(prev_filter_header, [0; 32]),
+                _ => (),
+            }
+            let filter_header = compute_filter_header(&filter, &prev_filter_header);
+            assert_eq!(
+                Sha256dHash::from_inner(filter_header).to_hex(),
+                vector[6].as_str().unwrap(),
+                "filter header at height {}",
+                height
+            );
+            prev_vector = Some((height, filter_header));
+        }
+    }
+}
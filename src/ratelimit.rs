@@ -0,0 +1,215 @@
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::apikeys::ApiKeys;
+use crate::config::Config;
+use crate::errors::*;
+use crate::metrics::{CounterVec, Gauge, MetricOpts, Metrics};
+
+// Each request costs at least one unit, plus one unit per this many scanned DB rows
+const ROWS_PER_COST_UNIT: u64 = 100;
+// Full buckets are forgotten once this many clients are tracked
+const MAX_TRACKED_CLIENTS: usize = 100_000;
+
+thread_local! {
+    static SCANNED_ROWS: Cell<u64> = Cell::new(0);
+}
+
+/// Accounts for a DB row read by the current thread.
+pub fn record_scanned_row() {
+    SCANNED_ROWS.with(|rows| rows.set(rows.get().wrapping_add(1)));
+}
+
+/// Accounts for DB rows read on behalf of the current thread (e.g. by a thread pool).
+pub fn record_scanned_rows(rows: u64) {
+    SCANNED_ROWS.with(|scanned| scanned.set(scanned.get().wrapping_add(rows)));
+}
+
+/// Runs `f` and returns the number of DB rows it read. The rows are taken off the current
+/// thread's count, so that thread pool workers can hand them back to the thread they run for
+/// (using `record_scanned_rows`), and so that work stolen by a worker isn't accounted twice.
+pub fn measure_scanned_rows<T, F: FnOnce() -> T>(f: F) -> (T, u64) {
+    let before = SCANNED_ROWS.with(|rows| rows.get());
+    let result = f();
+    let rows = SCANNED_ROWS.with(|rows| rows.replace(before));
+    (result, rows.wrapping_sub(before))
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub enum Client {
+    Ip(IpAddr),
+    ApiKey(String),
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+// A token bucket per client, refilled at `rate` units per second up to `burst` units.
+// Requests are admitted while the bucket is not empty, and charged after they are handled
+// (so expensive requests may put the bucket into debt).
+// Known API keys use the quota of their tier, everything else uses the global quota.
+pub struct RateLimiter {
+    rate: Option<f64>, // disabled if None
+    burst: f64,
+    api_keys: Option<Arc<ApiKeys>>,
+    buckets: Mutex<HashMap<Client, Bucket>>,
+
+    // monitoring
+    rejected: CounterVec,
+    cost: CounterVec,
+    clients: Gauge,
+}
+
+impl RateLimiter {
+    pub fn new(config: &Config, metrics: &Metrics, api_keys: Option<Arc<ApiKeys>>) -> Self {
+        RateLimiter {
+            rate: config.rate_limit,
+            burst: config.rate_limit_burst,
+            api_keys,
+            buckets: Mutex::new(HashMap::new()),
+            rejected: metrics.counter_vec(
+                MetricOpts::new("rate_limit_rejected", "# of rate limited requests"),
+                &["endpoint"],
+            ),
+            cost: metrics.counter_vec(
+                MetricOpts::new("rate_limit_cost", "Total cost of handled requests"),
+                &["endpoint"],
+            ),
+            clients: metrics.gauge(MetricOpts::new(
+                "rate_limit_clients",
+                "# of clients tracked by the rate limiter",
+            )),
+        }
+    }
+
+    // Returns the (rate, burst) quota of the client, if it is limited
+    fn quota(&self, client: &Client) -> Option<(f64, f64)> {
+        let tier = match client {
+            Client::ApiKey(key) => self.api_keys.as_ref().and_then(|keys| keys.lookup(key)),
+            Client::Ip(_) => None,
+        };
+        match tier {
+            // tiers without their own rate fall back to the default one
+            Some(key) => key.tier.rate_limit.or(self.rate).map(|rate| {
+                let burst = key.tier.rate_limit_burst.unwrap_or(self.burst);
+                (rate, burst)
+            }),
+            None => self.rate.map(|rate| (rate, self.burst)),
+        }
+    }
+
+    /// Fails if any of the clients has no budget left.
+    pub fn admit(&self, clients: &[Client], endpoint: &str) -> Result<()> {
+        let mut buckets = self.buckets.lock().unwrap();
+        let now = Instant::now();
+        for client in clients {
+            let (rate, burst) = match self.quota(client) {
+                Some(quota) => quota,
+                None => continue,
+            };
+            if let Some(bucket) = buckets.get_mut(client) {
+                let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+                bucket.tokens = (bucket.tokens + elapsed * rate).min(burst);
+                bucket.updated = now;
+                if bucket.tokens <= 0.0 {
+                    self.rejected.with_label_values(&[endpoint]).inc();
+                    bail!(ErrorKind::RateLimited);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Admits a batch of requests as a whole: fails if any of the clients can't afford their total
+    /// base cost (or its full burst, for larger batches), and reserves it otherwise. The handled
+    /// requests are then charged for the rows they read with `charge_rows`.
+    pub fn reserve(&self, clients: &[Client], endpoints: &[&str]) -> Result<()> {
+        let cost: u64 = endpoints.iter().map(|endpoint| base_cost(endpoint)).sum();
+        let mut buckets = self.buckets.lock().unwrap();
+        let now = Instant::now();
+        for client in clients {
+            let (rate, burst) = match self.quota(client) {
+                Some(quota) => quota,
+                None => continue,
+            };
+            if let Some(bucket) = buckets.get_mut(client) {
+                let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+                bucket.tokens = (bucket.tokens + elapsed * rate).min(burst);
+                bucket.updated = now;
+                if bucket.tokens < (cost as f64).min(burst) {
+                    for endpoint in endpoints {
+                        self.rejected.with_label_values(&[endpoint]).inc();
+                    }
+                    bail!(ErrorKind::RateLimited);
+                }
+            }
+        }
+        for endpoint in endpoints {
+            self.cost
+                .with_label_values(&[endpoint])
+                .inc_by(base_cost(endpoint));
+        }
+        // reserved under the same lock, so that concurrent requests can't spend the budget
+        self.debit(&mut buckets, clients, cost);
+        Ok(())
+    }
+
+    /// Charges the clients for a handled request, which has read `rows` DB rows.
+    pub fn charge(&self, clients: &[Client], endpoint: &str, rows: u64) {
+        let cost = base_cost(endpoint) + rows / ROWS_PER_COST_UNIT;
+        self.cost.with_label_values(&[endpoint]).inc_by(cost);
+        self.debit(&mut self.buckets.lock().unwrap(), clients, cost);
+    }
+
+    /// Charges the clients for the rows read by a request admitted with `reserve`.
+    pub fn charge_rows(&self, clients: &[Client], endpoint: &str, rows: u64) {
+        let cost = rows / ROWS_PER_COST_UNIT;
+        self.cost.with_label_values(&[endpoint]).inc_by(cost);
+        self.debit(&mut self.buckets.lock().unwrap(), clients, cost);
+    }
+
+    fn debit(&self, buckets: &mut HashMap<Client, Bucket>, clients: &[Client], cost: u64) {
+        if buckets.len() >= MAX_TRACKED_CLIENTS {
+            self.prune(buckets);
+        }
+        let now = Instant::now();
+        for client in clients {
+            let burst = match self.quota(client) {
+                Some((_rate, burst)) => burst,
+                None => continue,
+            };
+            let bucket = buckets.entry(client.clone()).or_insert_with(|| Bucket {
+                tokens: burst,
+                updated: now,
+            });
+            bucket.tokens -= cost as f64;
+        }
+        self.clients.set(buckets.len() as i64);
+    }
+
+    // Forget the clients whose buckets would be full by now
+    fn prune(&self, buckets: &mut HashMap<Client, Bucket>) {
+        buckets.retain(|client, bucket| match self.quota(client) {
+            Some((rate, burst)) => {
+                bucket.tokens + bucket.updated.elapsed().as_secs_f64() * rate < burst
+            }
+            None => false,
+        });
+    }
+}
+
+// The base cost of a request, on top of the DB rows it reads
+fn base_cost(endpoint: &str) -> u64 {
+    match endpoint {
+        // relayed to the daemon
+        "blockchain.transaction.broadcast" | "POST /tx" | "POST /txs/test" => 10,
+        // relayed to the daemon one tx at a time, for up to 25 txs
+        "POST /txs/package" => 250,
+        _ => 1,
+    }
+}
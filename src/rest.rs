@@ -1,25 +1,45 @@
 use crate::chain::{address, Network, OutPoint, Transaction, TxIn, TxOut};
+use crate::apikeys::{ApiKeys, Tier};
 use crate::config::Config;
+use crate::daemon::MempoolAcceptResult;
 use crate::errors;
-use crate::new_index::{compute_script_hash, Query, SpendingInput, Utxo};
+use crate::new_index::{
+    compute_script_hash, Event, PackageInfo, Query, RemovalReason, ScriptStats, SpendingInput,
+    Utxo, UtxoCursor,
+};
+#[cfg(not(feature = "liquid"))]
+use crate::new_index::{BalanceInterval, ChainStats};
+use crate::ratelimit::{self, Client, RateLimiter};
+#[cfg(not(feature = "liquid"))]
+use crate::util::BlockFees;
 use crate::util::{
     create_socket, electrum_merkle, extract_tx_prevouts, full_hash, get_innerscripts,
-    get_script_asm, get_tx_fee, has_prevout, is_coinbase, script_to_address, BlockHeaderMeta,
-    BlockId, FullHash, TransactionStatus,
+    get_script_asm, get_script_type, get_tx_fee, has_prevout, is_coinbase, script_to_address,
+    BlockHeaderMeta, BlockId, FullHash, TransactionStatus,
 };
+use crate::webhooks::{Subscription, Webhooks};
 
 #[cfg(not(feature = "liquid"))]
 use bitcoin::consensus::encode;
 use bitcoin::hashes::hex::{FromHex, ToHex};
 use bitcoin::hashes::Error as HashError;
 use bitcoin::{BlockHash, Script, Txid};
+use crypto::util::fixed_time_eq;
 use hex::{self, FromHexError};
+use hyper::server::conn::AddrStream;
 use hyper::service::{make_service_fn, service_fn};
-use hyper::{Body, Method, Response, Server, StatusCode};
-use tokio::sync::oneshot;
+use hyper::{Body, HeaderMap, Method, Response, Server, StatusCode};
+use tokio::sync::{mpsc, oneshot};
 
 use hyperlocal::UnixServerExt;
 use std::fs;
+
+#[cfg(not(feature = "liquid"))]
+use {
+    crate::util::xpub, crate::util::HeaderEntry, bitcoin::hashes::sha256d,
+    bitcoin::secp256k1::Secp256k1,
+};
+
 #[cfg(feature = "liquid")]
 use {
     crate::elements::{peg::PegoutValue, AssetSorting, IssuanceValue},
@@ -29,14 +49,17 @@ use {
     },
 };
 
+use rayon::prelude::*;
 use serde::Serialize;
 use serde_json;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::net::IpAddr;
 use std::num::ParseIntError;
 use std::os::unix::fs::FileTypeExt;
 use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 use url::form_urlencoded;
 use crate::util::heavyhash::heavy_hash;
 
@@ -44,6 +67,29 @@ const CHAIN_TXS_PER_PAGE: usize = 25;
 const MAX_MEMPOOL_TXS: usize = 50;
 const BLOCK_LIMIT: usize = 10;
 const ADDRESS_SEARCH_LIMIT: usize = 10;
+const UTXOS_PER_PAGE: usize = 100;
+const UTXOS_MAX_PER_PAGE: usize = 1000;
+const PACKAGE_MAX_TXS: usize = 25; // bitcoind's package count limit
+const EVENT_STREAMS_MAX: usize = 1000;
+const EVENT_STREAM_MAX_WATCHED: usize = 100; // addresses, scripthashes and txids per stream
+const EVENT_STREAM_QUEUE_SIZE: usize = 100; // pending updates before a slow client is dropped
+const EVENT_STREAM_KEEPALIVE: u64 = 15; // seconds
+#[cfg(not(feature = "liquid"))]
+const BLOCK_FEES_MAX_RANGE: usize = 1000;
+#[cfg(not(feature = "liquid"))]
+const FILTER_HEADERS_MAX_COUNT: usize = 2000;
+#[cfg(not(feature = "liquid"))]
+const CHAIN_STATS_DAYS: usize = 30;
+#[cfg(not(feature = "liquid"))]
+const CHAIN_STATS_MAX_DAYS: usize = 365;
+#[cfg(not(feature = "liquid"))]
+const XPUB_MAX_GAP_LIMIT: u32 = 100;
+#[cfg(not(feature = "liquid"))]
+const XPUB_MAX_ADDRESSES: u32 = 10_000; // per chain
+#[cfg(not(feature = "liquid"))]
+const BALANCE_POINTS_PER_PAGE: usize = 100;
+#[cfg(not(feature = "liquid"))]
+const BALANCE_POINTS_MAX_PER_PAGE: usize = 1000;
 
 #[cfg(feature = "liquid")]
 const ASSETS_PER_PAGE: usize = 25;
@@ -113,6 +159,14 @@ impl BlockValue {
     }
 }
 
+#[cfg(not(feature = "liquid"))]
+#[derive(Serialize)]
+struct FilterHeaderValue {
+    height: usize,
+    hash: BlockHash,
+    filter_header: sha256d::Hash,
+}
+
 #[derive(Serialize, Deserialize)]
 struct TransactionValue {
     txid: Txid,
@@ -125,6 +179,8 @@ struct TransactionValue {
     fee: u64,
     #[serde(skip_serializing_if = "Option::is_none")]
     status: Option<TransactionStatus>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    package: Option<PackageInfo>, // for unconfirmed txs
 }
 
 impl TransactionValue {
@@ -133,6 +189,22 @@ impl TransactionValue {
         blockid: Option<BlockId>,
         txos: &HashMap<OutPoint, TxOut>,
         config: &Config,
+    ) -> Self {
+        let fee = get_tx_fee(
+            &tx,
+            &extract_tx_prevouts(&tx, &txos, true),
+            config.network_type,
+        );
+        TransactionValue::with_fee(tx, blockid, txos, fee, config)
+    }
+
+    // For txs whose prevouts may be missing from `txos` (i.e. dropped txs), with a known fee
+    fn with_fee(
+        tx: Transaction,
+        blockid: Option<BlockId>,
+        txos: &HashMap<OutPoint, TxOut>,
+        fee: u64,
+        config: &Config,
     ) -> Self {
         let prevouts = extract_tx_prevouts(&tx, &txos, true);
         let vins: Vec<TxInValue> = tx
@@ -149,8 +221,6 @@ impl TransactionValue {
             .map(|txout| TxOutValue::new(txout, config))
             .collect();
 
-        let fee = get_tx_fee(&tx, &prevouts, config.network_type);
-
         TransactionValue {
             txid: tx.txid(),
             version: tx.version,
@@ -161,6 +231,7 @@ impl TransactionValue {
             weight: tx.get_weight() as u32,
             fee,
             status: Some(TransactionStatus::from(blockid)),
+            package: None,
         }
     }
 }
@@ -303,27 +374,10 @@ impl TxOutValue {
         let script_asm = get_script_asm(&script);
         let script_addr = script_to_address(&script, config.network_type);
 
-        // TODO should the following something to put inside rust-elements lib?
         let script_type = if is_fee {
             "fee"
-        } else if script.is_empty() {
-            "empty"
-        } else if script.is_op_return() {
-            "op_return"
-        } else if script.is_p2pk() {
-            "p2pk"
-        } else if script.is_p2pkh() {
-            "p2pkh"
-        } else if script.is_p2sh() {
-            "p2sh"
-        } else if script.is_v0_p2wpkh() {
-            "v0_p2wpkh"
-        } else if script.is_v0_p2wsh() {
-            "v0_p2wsh"
-        } else if script.is_provably_unspendable() {
-            "provably_unspendable"
         } else {
-            "unknown"
+            get_script_type(&script)
         };
 
         #[cfg(feature = "liquid")]
@@ -347,6 +401,49 @@ impl TxOutValue {
     }
 }
 
+#[derive(Serialize)]
+struct BalanceValue {
+    height: usize,
+    hash: BlockHash,
+    timestamp: u32,
+    chain_stats: ScriptStats,
+    #[cfg(not(feature = "liquid"))]
+    balance: u64,
+}
+
+impl BalanceValue {
+    fn new(blockid: BlockId, stats: ScriptStats) -> errors::Result<Self> {
+        Ok(BalanceValue {
+            height: blockid.height,
+            hash: blockid.hash,
+            timestamp: blockid.time,
+            #[cfg(not(feature = "liquid"))]
+            balance: stats.balance()?,
+            chain_stats: stats,
+        })
+    }
+}
+
+#[cfg(not(feature = "liquid"))]
+#[derive(Serialize)]
+struct BlockFeesValue {
+    height: usize,
+    hash: BlockHash,
+    timestamp: u32,
+    #[serde(flatten)]
+    fees: BlockFees,
+}
+
+#[cfg(not(feature = "liquid"))]
+#[derive(Serialize)]
+struct ChainStatsValue {
+    height: usize,
+    hash: BlockHash,
+    timestamp: u32,
+    #[serde(flatten)]
+    stats: ChainStats,
+}
+
 #[derive(Serialize)]
 struct UtxoValue {
     txid: Txid,
@@ -467,6 +564,20 @@ impl Default for SpendingValue {
     }
 }
 
+#[cfg(not(feature = "liquid"))]
+fn chain_stats_value(query: &Query, entry: &HeaderEntry) -> Result<ChainStatsValue, HttpError> {
+    let stats = query
+        .chain()
+        .get_chain_stats(entry.hash())
+        .ok_or_else(|| HttpError::not_found("Chain stats not found".to_string()))?;
+    Ok(ChainStatsValue {
+        height: entry.height(),
+        hash: *entry.hash(),
+        timestamp: entry.header().time,
+        stats,
+    })
+}
+
 fn ttl_by_depth(height: Option<usize>, query: &Query) -> u32 {
     height.map_or(TTL_SHORT, |height| {
         if query.chain().best_height() - height >= CONF_FINAL {
@@ -482,46 +593,122 @@ fn prepare_txs(
     query: &Query,
     config: &Config,
 ) -> Vec<TransactionValue> {
-    let outpoints = txs
-        .iter()
-        .flat_map(|(tx, _)| {
+    // the prevouts of dropped txs may no longer be available, their fee is kept instead
+    let dropped_txids: HashSet<Txid> = {
+        let mempool = query.mempool();
+        txs.iter()
+            .filter(|(_, blockid)| blockid.is_none())
+            .map(|(tx, _)| tx.txid())
+            .filter(|txid| mempool.dropped(txid).is_some())
+            .collect()
+    };
+    let (mut outpoints, mut dropped_outpoints) = (BTreeSet::new(), BTreeSet::new());
+    for (tx, _) in &txs {
+        let target = if dropped_txids.contains(&tx.txid()) {
+            &mut dropped_outpoints
+        } else {
+            &mut outpoints
+        };
+        target.extend(
             tx.input
                 .iter()
                 .filter(|txin| has_prevout(txin))
-                .map(|txin| txin.previous_output)
-        })
-        .collect();
-
-    let prevouts = query.lookup_txos(&outpoints);
+                .map(|txin| txin.previous_output),
+        );
+    }
+    let mut prevouts = query.lookup_txos(&outpoints);
+    prevouts.extend(query.lookup_avail_txos(&dropped_outpoints));
 
+    let mempool = query.mempool();
     txs.into_iter()
-        .map(|(tx, blockid)| TransactionValue::new(tx, blockid, &prevouts, config))
+        .map(|(tx, blockid)| {
+            let txid = tx.txid();
+            let (package, first_seen, dropped) = match blockid {
+                None => (
+                    mempool.package_info(&txid),
+                    mempool.first_seen(&txid),
+                    mempool.dropped(&txid).cloned(),
+                ),
+                Some(_) => (None, query.chain().get_first_seen(&txid), None),
+            };
+            let mut value = match dropped {
+                Some(ref dropped) if dropped_txids.contains(&txid) => {
+                    TransactionValue::with_fee(tx, blockid, &prevouts, dropped.fee, config)
+                }
+                _ => TransactionValue::new(tx, blockid, &prevouts, config),
+            };
+            value.package = package;
+            if let Some(ref mut status) = value.status {
+                status.first_seen = first_seen;
+                status.confirmation_latency = status.block_time.and_then(|block_time| {
+                    first_seen.map(|first_seen| (block_time as u64).saturating_sub(first_seen))
+                });
+                status.dropped = dropped;
+            }
+            value
+        })
         .collect()
 }
 
 #[tokio::main]
-async fn run_server(config: Arc<Config>, query: Arc<Query>, rx: oneshot::Receiver<()>) {
+async fn run_server(
+    config: Arc<Config>,
+    query: Arc<Query>,
+    limiter: Arc<RateLimiter>,
+    api_keys: Option<Arc<ApiKeys>>,
+    webhooks: Option<Arc<Webhooks>>,
+    events: Arc<EventStreams>,
+    rx: oneshot::Receiver<()>,
+) {
     let addr = &config.http_addr;
     let socket_file = &config.http_socket_file;
 
     let config = Arc::clone(&config);
     let query = Arc::clone(&query);
 
-    let make_service_fn_inn = || {
+    let make_service_fn_inn = |peer: Option<IpAddr>| {
         let query = Arc::clone(&query);
         let config = Arc::clone(&config);
+        let limiter = Arc::clone(&limiter);
+        let api_keys = api_keys.clone();
+        let webhooks = webhooks.clone();
+        let events = Arc::clone(&events);
 
         async move {
             Ok::<_, hyper::Error>(service_fn(move |req| {
                 let query = Arc::clone(&query);
                 let config = Arc::clone(&config);
+                let limiter = Arc::clone(&limiter);
+                let api_keys = api_keys.clone();
+                let webhooks = webhooks.clone();
+                let events = Arc::clone(&events);
 
                 async move {
                     let method = req.method().clone();
                     let uri = req.uri().clone();
+                    // the admin endpoints aren't rate limited
+                    let auth = if uri.path().starts_with("/admin/") {
+                        authenticate_admin(req.headers(), &config).map(|()| None)
+                    } else {
+                        authenticate(peer, req.headers(), &uri, &config, api_keys.as_deref())
+                            .map(Some)
+                    };
                     let body = hyper::body::to_bytes(req.into_body()).await?;
 
-                    let mut resp = handle_request(method, uri, body, &query, &config)
+                    let mut resp = auth
+                        .and_then(|auth| match auth {
+                            Some((clients, limits)) => handle_limited_request(
+                                method, uri, body, &query, &config, &limiter, &clients, &limits,
+                                &events,
+                            ),
+                            None => handle_admin_request(
+                                method,
+                                uri,
+                                &body,
+                                &config,
+                                webhooks.as_deref(),
+                            ),
+                        })
                         .unwrap_or_else(|err| {
                             warn!("{:?}", err);
                             Response::builder()
@@ -549,7 +736,9 @@ async fn run_server(config: Arc<Config>, query: Arc<Query>, rx: oneshot::Receive
 
             Server::from_tcp(socket.into_tcp_listener())
                 .expect("Server::from_tcp failed")
-                .serve(make_service_fn(move |_| make_service_fn_inn()))
+                .serve(make_service_fn(move |conn: &AddrStream| {
+                    make_service_fn_inn(Some(conn.remote_addr().ip()))
+                }))
                 .with_graceful_shutdown(async {
                     rx.await.ok();
                 })
@@ -567,7 +756,7 @@ async fn run_server(config: Arc<Config>, query: Arc<Query>, rx: oneshot::Receive
 
             Server::bind_unix(path)
                 .expect("Server::bind_unix failed")
-                .serve(make_service_fn(move |_| make_service_fn_inn()))
+                .serve(make_service_fn(move |_| make_service_fn_inn(None)))
                 .with_graceful_shutdown(async {
                     rx.await.ok();
                 })
@@ -580,35 +769,432 @@ async fn run_server(config: Arc<Config>, query: Arc<Query>, rx: oneshot::Receive
     }
 }
 
-pub fn start(config: Arc<Config>, query: Arc<Query>) -> Handle {
+pub fn start(
+    config: Arc<Config>,
+    query: Arc<Query>,
+    limiter: Arc<RateLimiter>,
+    api_keys: Option<Arc<ApiKeys>>,
+    webhooks: Option<Arc<Webhooks>>,
+) -> Handle {
     let (tx, rx) = oneshot::channel::<()>();
+    let events = Arc::new(EventStreams::default());
+    let server_events = Arc::clone(&events);
 
     Handle {
         tx,
         thread: thread::spawn(move || {
-            run_server(config, query, rx);
+            run_server(
+                config,
+                query,
+                limiter,
+                api_keys,
+                webhooks,
+                server_events,
+                rx,
+            );
         }),
+        events,
+    }
+}
+
+// Response size limits, which may be overridden by the tier of the API key
+struct ResponseLimits {
+    chain_txs_per_page: usize,
+    max_mempool_txs: usize,
+    utxos_limit: usize,
+}
+
+impl ResponseLimits {
+    fn new(config: &Config, tier: Option<&Tier>) -> Self {
+        ResponseLimits {
+            chain_txs_per_page: tier
+                .and_then(|tier| tier.chain_txs_per_page)
+                .unwrap_or(CHAIN_TXS_PER_PAGE),
+            max_mempool_txs: tier
+                .and_then(|tier| tier.max_mempool_txs)
+                .unwrap_or(MAX_MEMPOOL_TXS),
+            utxos_limit: tier
+                .and_then(|tier| tier.utxos_limit)
+                .unwrap_or(config.utxos_limit),
+        }
+    }
+}
+
+// Checks the API key (when API keys are configured), and returns the clients to rate limit
+// along with the response limits that apply to the request.
+fn authenticate(
+    peer: Option<IpAddr>,
+    headers: &HeaderMap,
+    uri: &hyper::Uri,
+    config: &Config,
+    api_keys: Option<&ApiKeys>,
+) -> Result<(Vec<Client>, ResponseLimits), HttpError> {
+    let key = api_key(headers, uri);
+    match (api_keys, key) {
+        (Some(api_keys), Some(key)) => match api_keys.lookup(&key) {
+            Some(api_key) => {
+                api_keys.record_request(&api_key);
+                let limits = ResponseLimits::new(config, Some(&*api_key.tier));
+                Ok((vec![Client::ApiKey(key)], limits))
+            }
+            None => Err(HttpError(
+                StatusCode::UNAUTHORIZED,
+                "Invalid API key".to_string(),
+            )),
+        },
+        (Some(_), None) if config.require_api_key => Err(HttpError(
+            StatusCode::UNAUTHORIZED,
+            "Missing API key".to_string(),
+        )),
+        (_, key) => {
+            let mut clients = rate_limit_clients(peer, headers, &config.trusted_proxies);
+            clients.extend(key.map(Client::ApiKey));
+            Ok((clients, ResponseLimits::new(config, None)))
+        }
+    }
+}
+
+// Checks the admin key of the /admin/ endpoints, which are only available when it is configured
+fn authenticate_admin(headers: &HeaderMap, config: &Config) -> Result<(), HttpError> {
+    let admin_key = match config.webhooks_admin_key {
+        Some(ref admin_key) => admin_key,
+        None => bail!(HttpError::not_found("Admin API is disabled".to_string())),
+    };
+    match headers.get("X-Admin-Key") {
+        Some(key) if fixed_time_eq(key.as_bytes(), admin_key.as_bytes()) => Ok(()),
+        _ => Err(HttpError(
+            StatusCode::UNAUTHORIZED,
+            "Invalid admin key".to_string(),
+        )),
     }
 }
 
 pub struct Handle {
     tx: oneshot::Sender<()>,
     thread: thread::JoinHandle<()>,
+    events: Arc<EventStreams>,
 }
 
 impl Handle {
+    /// Pushes the chain and mempool events to the subscribed event streams.
+    pub fn notify(&self, events: &[Event]) {
+        self.events.notify(events);
+    }
+
     pub fn stop(self) {
+        // open event streams would otherwise hold the graceful shutdown
+        self.events.close();
         self.tx.send(()).expect("failed to send shutdown signal");
         self.thread.join().expect("REST server failed");
     }
 }
 
+// The events an event stream is subscribed to
+struct EventFilter {
+    blocks: bool,
+    mempool_txs: bool,
+    fee_histogram: bool,
+    scripts: HashMap<FullHash, Option<String>>, // scripthash -> address (if watched by address)
+    txids: HashSet<Txid>,
+}
+
+impl EventFilter {
+    fn new(query_params: &HashMap<String, String>, network: Network) -> Result<Self, HttpError> {
+        let list = |name: &str| -> Vec<&str> {
+            query_params.get(name).map_or(vec![], |value| {
+                value.split(',').filter(|item| !item.is_empty()).collect()
+            })
+        };
+        let mut scripts = HashMap::new();
+        for address in list("address") {
+            let scripthash = address_to_scripthash(address, network)?;
+            scripts.insert(scripthash, Some(address.to_string()));
+        }
+        for scripthash in list("scripthash") {
+            scripts.insert(parse_scripthash(scripthash)?, None);
+        }
+        let mut txids = HashSet::new();
+        for txid in list("txid") {
+            txids.insert(Txid::from_hex(txid)?);
+        }
+        if scripts.len() + txids.len() > EVENT_STREAM_MAX_WATCHED {
+            bail!(HttpError::from(format!(
+                "Event streams are limited to {} addresses, scripthashes and txids",
+                EVENT_STREAM_MAX_WATCHED
+            )));
+        }
+
+        let filter = EventFilter {
+            blocks: query_params.contains_key("blocks"),
+            mempool_txs: query_params.contains_key("mempool-txs"),
+            fee_histogram: query_params.contains_key("fee-histogram"),
+            scripts,
+            txids,
+        };
+        if !filter.blocks
+            && !filter.mempool_txs
+            && !filter.fee_histogram
+            && filter.scripts.is_empty()
+            && filter.txids.is_empty()
+        {
+            bail!(HttpError::from("No events to subscribe to".to_string()));
+        }
+        Ok(filter)
+    }
+}
+
+struct EventStream {
+    filter: EventFilter,
+    sender: mpsc::Sender<hyper::body::Bytes>,
+    last_sent: Instant,
+}
+
+#[derive(Serialize)]
+struct ScriptEventValue<'a> {
+    scripthash: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    address: Option<&'a str>,
+    txid: &'a Txid,
+    confirmed: bool,
+}
+
+#[derive(Serialize)]
+struct TxRemovedValue<'a> {
+    txid: &'a Txid,
+    #[serde(flatten)]
+    reason: &'a RemovalReason,
+}
+
+fn sse_message<T: Serialize>(event: &str, data: &T) -> String {
+    format!(
+        "event: {}\ndata: {}\n\n",
+        event,
+        serde_json::to_string(data).unwrap()
+    )
+}
+
+/// The server-sent event streams of `GET /events`, fed with the chain and mempool events
+#[derive(Default)]
+struct EventStreams {
+    streams: Mutex<Vec<EventStream>>,
+}
+
+impl EventStreams {
+    fn subscribe(&self, filter: EventFilter) -> Result<Body, HttpError> {
+        let mut streams = self.streams.lock().unwrap();
+        if streams.len() >= EVENT_STREAMS_MAX {
+            bail!(HttpError(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Too many event streams".to_string()
+            ));
+        }
+        let (mut body_sender, body) = Body::channel();
+        let (sender, mut receiver) = mpsc::channel(EVENT_STREAM_QUEUE_SIZE);
+        // forward the queued updates until the client disconnects or the stream is dropped
+        tokio::spawn(async move {
+            while let Some(update) = receiver.recv().await {
+                if body_sender.send_data(update).await.is_err() {
+                    break;
+                }
+            }
+        });
+        streams.push(EventStream {
+            filter,
+            sender,
+            last_sent: Instant::now(),
+        });
+        Ok(body)
+    }
+
+    fn notify(&self, events: &[Event]) {
+        let mut streams = self.streams.lock().unwrap();
+        if streams.is_empty() {
+            return;
+        }
+
+        // the messages for the events that aren't specific to a stream are formatted once
+        let mut blocks = String::new();
+        let mut mempool_txs = String::new();
+        let mut fee_histogram = String::new();
+        for event in events {
+            match event {
+                Event::Block(blockid) => blocks.push_str(&sse_message("block", blockid)),
+                Event::MempoolTx(overview) => {
+                    mempool_txs.push_str(&sse_message("mempool-tx", overview))
+                }
+                // only the latest histogram is relevant
+                Event::FeeHistogram(histogram) => {
+                    fee_histogram = sse_message("fee-histogram", histogram)
+                }
+                _ => (),
+            }
+        }
+
+        let active: Vec<EventStream> = streams
+            .drain(..)
+            .filter_map(|mut stream| {
+                let mut update = String::new();
+                if stream.filter.blocks {
+                    update.push_str(&blocks);
+                }
+                if stream.filter.mempool_txs {
+                    update.push_str(&mempool_txs);
+                }
+                if stream.filter.fee_histogram {
+                    update.push_str(&fee_histogram);
+                }
+                for event in events {
+                    if let Some(message) = watched_event_message(event, &stream.filter) {
+                        update.push_str(&message);
+                    }
+                }
+                if update.is_empty() {
+                    if stream.last_sent.elapsed() < Duration::from_secs(EVENT_STREAM_KEEPALIVE) {
+                        return Some(stream);
+                    }
+                    // also detects the disconnected clients
+                    update.push_str(": keepalive\n\n");
+                }
+                // slow clients are dropped (and may reconnect)
+                match stream.sender.try_send(update.into()) {
+                    Ok(()) => {
+                        stream.last_sent = Instant::now();
+                        Some(stream)
+                    }
+                    Err(_) => None,
+                }
+            })
+            .collect();
+        *streams = active;
+    }
+
+    fn close(&self) {
+        self.streams.lock().unwrap().clear();
+    }
+}
+
+// Formats the events about the addresses, scripthashes and txids watched by the stream
+fn watched_event_message(event: &Event, filter: &EventFilter) -> Option<String> {
+    match event {
+        Event::ScriptActivity {
+            scripthash,
+            txid,
+            confirmed,
+        } => {
+            let address = filter.scripts.get(scripthash)?;
+            let value = ScriptEventValue {
+                scripthash: scripthash.to_hex(),
+                address: address.as_deref(),
+                txid,
+                confirmed: *confirmed,
+            };
+            Some(sse_message("scripthash-tx", &value))
+        }
+        Event::MempoolTx(overview) if filter.txids.contains(&overview.txid) => {
+            Some(sse_message("tx-added", overview))
+        }
+        Event::TxRemoved { txid, reason } if filter.txids.contains(txid) => {
+            Some(sse_message("tx-removed", &TxRemovedValue { txid, reason }))
+        }
+        _ => None,
+    }
+}
+
+// Identify the client by its IP address. Requests arriving through a trusted reverse proxy
+// (or the unix socket) are attributed to the address in X-Forwarded-For.
+fn rate_limit_clients(
+    peer: Option<IpAddr>,
+    headers: &HeaderMap,
+    trusted_proxies: &[IpAddr],
+) -> Vec<Client> {
+    let mut clients = vec![];
+    let forwarded_for = || {
+        headers
+            .get("X-Forwarded-For")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.rsplit(',').next())
+            .and_then(|ip| ip.trim().parse().ok())
+    };
+    let ip = match peer {
+        Some(ip) if trusted_proxies.contains(&ip) => forwarded_for().or(Some(ip)),
+        Some(ip) => Some(ip),
+        None => forwarded_for(),
+    };
+    if let Some(ip) = ip {
+        clients.push(Client::Ip(ip));
+    }
+    clients
+}
+
+// The API key is read from the X-API-Key header or the `api_key` query param
+fn api_key(headers: &HeaderMap, uri: &hyper::Uri) -> Option<String> {
+    headers
+        .get("X-API-Key")
+        .and_then(|value| value.to_str().ok())
+        .map(|key| key.to_string())
+        .or_else(|| {
+            uri.query().and_then(|query| {
+                form_urlencoded::parse(query.as_bytes())
+                    .find(|(name, _)| name == "api_key")
+                    .map(|(_, key)| key.into_owned())
+            })
+        })
+}
+
+// A coarse name for the requested endpoint, used for cost accounting and metrics
+fn endpoint_name(method: &Method, uri: &hyper::Uri) -> &'static str {
+    match (method, uri.path().split('/').nth(1).unwrap_or("")) {
+        (&Method::POST, "tx") | (_, "broadcast") => "POST /tx",
+        (&Method::POST, "txs") => match uri.path().split('/').nth(2) {
+            Some("package") => "POST /txs/package",
+            _ => "POST /txs/test",
+        },
+        (_, "blocks") => "/blocks",
+        (_, "block-height") => "/block-height",
+        (_, "block") => "/block",
+        (_, "filter-headers") => "/filter-headers",
+        (_, "chain") => "/chain",
+        (_, "tx") => "/tx",
+        (_, "address") | (_, "scripthash") => "/address",
+        (_, "addresses") | (_, "scripthashes") => "/addresses",
+        (_, "xpub") | (_, "descriptor") => "/xpub",
+        (_, "address-prefix") => "/address-prefix",
+        (_, "mempool") => "/mempool",
+        (_, "fee-estimates") => "/fee-estimates",
+        (_, "events") => "/events",
+        (_, "asset") | (_, "assets") => "/asset",
+        _ => "other",
+    }
+}
+
+fn handle_limited_request(
+    method: Method,
+    uri: hyper::Uri,
+    body: hyper::body::Bytes,
+    query: &Query,
+    config: &Config,
+    limiter: &RateLimiter,
+    clients: &[Client],
+    limits: &ResponseLimits,
+    events: &EventStreams,
+) -> Result<Response<Body>, HttpError> {
+    let endpoint = endpoint_name(&method, &uri);
+    limiter.admit(clients, endpoint)?;
+    let (resp, rows) = ratelimit::measure_scanned_rows(|| {
+        handle_request(method, uri, body, query, config, limits, events)
+    });
+    limiter.charge(clients, endpoint, rows);
+    resp
+}
+
 fn handle_request(
     method: Method,
     uri: hyper::Uri,
     body: hyper::body::Bytes,
     query: &Query,
     config: &Config,
+    limits: &ResponseLimits,
+    events: &EventStreams,
 ) -> Result<Response<Body>, HttpError> {
     // TODO it looks hyper does not have routing and query parsing :(
     let path: Vec<&str> = uri.path().split('/').skip(1).collect();
@@ -640,6 +1226,52 @@ fn handle_request(
             TTL_SHORT,
         ),
 
+        #[cfg(not(feature = "liquid"))]
+        (&Method::GET, Some(&"blocks"), Some(&"fees"), None, None, None) => {
+            let start_height: usize = query_params
+                .get("start")
+                .ok_or_else(|| HttpError::from("Missing start height".to_string()))?
+                .parse()?;
+            let end_height: usize = match query_params.get("end") {
+                Some(end_height) => end_height.parse()?,
+                None => query.chain().best_height(),
+            };
+            if end_height < start_height {
+                bail!(HttpError::from("Invalid height range".to_string()));
+            }
+            if end_height - start_height >= BLOCK_FEES_MAX_RANGE {
+                bail!(HttpError::from(format!(
+                    "Height range is limited to {} blocks",
+                    BLOCK_FEES_MAX_RANGE
+                )));
+            }
+
+            let mut values = vec![];
+            for height in start_height..=end_height {
+                let entry = match query.chain().header_by_height(height) {
+                    Some(entry) => entry,
+                    None => break,
+                };
+                let fees = query
+                    .chain()
+                    .get_block_fees(entry.hash())
+                    .ok_or_else(|| HttpError::not_found("Block fees not found".to_string()))?;
+                values.push(BlockFeesValue {
+                    height,
+                    hash: *entry.hash(),
+                    timestamp: entry.header().time,
+                    fees,
+                });
+            }
+
+            Ok(Response::builder()
+                .status(StatusCode::OK)
+                // Disable caching because we don't currently support caching with query string params
+                .header("Cache-Control", "no-store")
+                .header("Content-Type", "application/json")
+                .body(Body::from(serde_json::to_string(&values)?))
+                .unwrap())
+        }
         (&Method::GET, Some(&"blocks"), start_height, None, None, None) => {
             let start_height = start_height.and_then(|height| height.parse::<usize>().ok());
             blocks(&query, &config, start_height)
@@ -686,6 +1318,119 @@ fn handle_request(
             let header_hex = hex::encode(encode::serialize(&header));
             http_message(StatusCode::OK, header_hex, TTL_LONG)
         }
+        #[cfg(not(feature = "liquid"))]
+        (&Method::GET, Some(&"block"), Some(hash), Some(&"stats"), None, None) => {
+            let hash = BlockHash::from_hex(hash)?;
+            let stats = query
+                .chain()
+                .get_block_stats(&hash)
+                .ok_or_else(|| block_data_not_found(query, &hash, "stats"))?;
+            json_response(stats, TTL_LONG)
+        }
+        #[cfg(not(feature = "liquid"))]
+        (&Method::GET, Some(&"block"), Some(hash), Some(&"fees"), None, None) => {
+            let hash = BlockHash::from_hex(hash)?;
+            let fees = query
+                .chain()
+                .get_block_fees(&hash)
+                .ok_or_else(|| block_data_not_found(query, &hash, "fees"))?;
+            json_response(fees, TTL_LONG)
+        }
+        #[cfg(not(feature = "liquid"))]
+        (&Method::GET, Some(&"block"), Some(hash), Some(&"filter"), None, None) => {
+            let hash = BlockHash::from_hex(hash)?;
+            let filter = query
+                .chain()
+                .get_block_filter(&hash)
+                .ok_or_else(|| HttpError::not_found("Block filter not found".to_string()))?;
+            http_message(StatusCode::OK, hex::encode(filter), TTL_LONG)
+        }
+        #[cfg(not(feature = "liquid"))]
+        (&Method::GET, Some(&"block"), Some(hash), Some(&"filter-header"), None, None) => {
+            let hash = BlockHash::from_hex(hash)?;
+            let filter_header = query
+                .chain()
+                .get_filter_header(&hash)
+                .ok_or_else(|| HttpError::not_found("Filter header not found".to_string()))?;
+            http_message(StatusCode::OK, filter_header.to_hex(), TTL_LONG)
+        }
+        #[cfg(not(feature = "liquid"))]
+        (&Method::GET, Some(&"chain"), Some(&"stats"), None, None, None) => {
+            let days: usize = query_params
+                .get("days")
+                .map(|days| days.parse())
+                .transpose()?
+                .unwrap_or(CHAIN_STATS_DAYS)
+                .min(CHAIN_STATS_MAX_DAYS);
+
+            let tip = query.chain().best_header();
+            let tip_stats = chain_stats_value(query, &tip)?;
+
+            // the stats as of the last block of each previous day, most recent first
+            let mut daily = vec![];
+            let mut day = tip.header().time / 86400;
+            let mut height = tip.height();
+            while daily.len() < days && height > 0 {
+                height -= 1;
+                let entry = query
+                    .chain()
+                    .header_by_height(height)
+                    .expect("missing header");
+                if entry.header().time / 86400 < day {
+                    day = entry.header().time / 86400;
+                    daily.push(chain_stats_value(query, &entry)?);
+                }
+            }
+
+            Ok(Response::builder()
+                .status(StatusCode::OK)
+                // Disable caching because we don't currently support caching with query string params
+                .header("Cache-Control", "no-store")
+                .header("Content-Type", "application/json")
+                .body(Body::from(serde_json::to_string(&json!({
+                    "tip": tip_stats,
+                    "daily": daily,
+                }))?))
+                .unwrap())
+        }
+        #[cfg(not(feature = "liquid"))]
+        (&Method::GET, Some(&"filter-headers"), Some(start_height), None, None, None) => {
+            let start_height: usize = start_height.parse()?;
+            let count: usize = query_params
+                .get("count")
+                .map(|count| count.parse())
+                .transpose()?
+                .unwrap_or(FILTER_HEADERS_MAX_COUNT)
+                .min(FILTER_HEADERS_MAX_COUNT);
+
+            let mut filter_headers = vec![];
+            for height in start_height..start_height.saturating_add(count) {
+                let entry = match query.chain().header_by_height(height) {
+                    Some(entry) => entry,
+                    None => break,
+                };
+                let filter_header = match query.chain().get_filter_header(entry.hash()) {
+                    Some(filter_header) => filter_header,
+                    None => break,
+                };
+                filter_headers.push(FilterHeaderValue {
+                    height,
+                    hash: *entry.hash(),
+                    filter_header,
+                });
+            }
+            if filter_headers.is_empty() {
+                bail!(HttpError::not_found("Filter headers not found".to_string()));
+            }
+
+            Ok(Response::builder()
+                .status(StatusCode::OK)
+                // Disable caching because we don't currently support caching with query string params
+                .header("Cache-Control", "no-store")
+                .header("Content-Type", "application/json")
+                .body(Body::from(serde_json::to_string(&filter_headers)?))
+                .unwrap())
+        }
         (&Method::GET, Some(&"block"), Some(hash), Some(&"raw"), None, None) => {
             let hash = BlockHash::from_hex(hash)?;
             let raw = query
@@ -765,11 +1510,58 @@ fn handle_request(
                 TTL_SHORT,
             )
         }
+        (&Method::POST, Some(script_types @ &"addresses"), Some(&"stats"), None, None, None)
+        | (&Method::POST, Some(script_types @ &"scripthashes"), Some(&"stats"), None, None, None) =>
+        {
+            let (script_type, scripts) = parse_batch_scripts(script_types, &body, config)?;
+            let stats = par_map_scripts(&scripts, |script_hash| query.stats(&script_hash[..]));
+            let values: Vec<serde_json::Value> = scripts
+                .iter()
+                .zip(stats)
+                .map(|((script_str, _), stats)| {
+                    json!({
+                        script_type: script_str,
+                        "chain_stats": stats.0,
+                        "mempool_stats": stats.1,
+                    })
+                })
+                .collect();
+            json_response(values, TTL_SHORT)
+        }
+        (&Method::POST, Some(script_types @ &"addresses"), Some(&"utxo"), None, None, None)
+        | (&Method::POST, Some(script_types @ &"scripthashes"), Some(&"utxo"), None, None, None) => {
+            let (script_type, scripts) = parse_batch_scripts(script_types, &body, config)?;
+            json_response(
+                batch_utxos(script_type, &scripts, query, limits)?,
+                TTL_SHORT,
+            )
+        }
+        (&Method::POST, Some(script_types @ &"addresses"), Some(&"txs"), None, None, None)
+        | (&Method::POST, Some(script_types @ &"scripthashes"), Some(&"txs"), None, None, None) => {
+            let (_, scripts) = parse_batch_scripts(script_types, &body, config)?;
+            let txs = batch_history(&scripts, query, limits);
+            json_response(prepare_txs(txs, query, config), TTL_SHORT)
+        }
+        #[cfg(not(feature = "liquid"))]
+        (&Method::GET, Some(&"xpub"), Some(xpub), None, None, None) => {
+            let secp = Secp256k1::verification_only();
+            let chains = xpub::parse_xpub(&secp, xpub, config.network_type.into())?;
+            scan_key_chains(&chains, &query_params, query, config, limits)
+        }
+        #[cfg(not(feature = "liquid"))]
+        (&Method::GET, Some(&"descriptor"), None, None, None, None) => {
+            let descriptor = query_params
+                .get("descriptor")
+                .ok_or_else(|| HttpError::from("Missing descriptor".to_string()))?;
+            let secp = Secp256k1::verification_only();
+            let chains = xpub::parse_descriptor(&secp, descriptor, config.network_type.into())?;
+            scan_key_chains(&chains, &query_params, query, config, limits)
+        }
         (
             &Method::GET,
             Some(script_type @ &"address"),
             Some(script_str),
-            Some(&"txs"),
+            Some(&"balance"),
             None,
             None,
         )
@@ -777,26 +1569,136 @@ fn handle_request(
             &Method::GET,
             Some(script_type @ &"scripthash"),
             Some(script_str),
-            Some(&"txs"),
+            Some(&"balance"),
             None,
             None,
         ) => {
             let script_hash = to_scripthash(script_type, script_str, config.network_type)?;
+            let height: Option<usize> = query_params
+                .get("height")
+                .map(|height| height.parse())
+                .transpose()?;
+            let timestamp: Option<u32> = query_params
+                .get("timestamp")
+                .map(|timestamp| timestamp.parse())
+                .transpose()?;
+
+            let height = match (height, timestamp) {
+                (Some(_), Some(_)) => bail!(HttpError::from(
+                    "height and timestamp are mutually exclusive".to_string()
+                )),
+                (Some(height), None) => height,
+                // the last block with a timestamp at or before the requested time
+                (None, Some(timestamp)) => {
+                    match query.chain().height_by_time(timestamp.saturating_add(1)) {
+                        0 => bail!(HttpError::from(
+                            "Timestamp before the genesis block".to_string()
+                        )),
+                        height => height - 1,
+                    }
+                }
+                (None, None) => query.chain().best_height(),
+            };
+            let blockid = query
+                .chain()
+                .blockid_by_height(height)
+                .ok_or_else(|| HttpError::not_found("Block not found".to_string()))?;
+            let stats = query.chain().stats_at(&script_hash[..], height);
+
+            Ok(Response::builder()
+                // Disable caching because we don't currently support caching with query string params
+                .header("Cache-Control", "no-store")
+                .header("Content-Type", "application/json")
+                .body(Body::from(serde_json::to_string(&BalanceValue::new(
+                    blockid, stats,
+                )?)?))
+                .unwrap())
+        }
+        #[cfg(not(feature = "liquid"))]
+        (
+            &Method::GET,
+            Some(script_type @ &"address"),
+            Some(script_str),
+            Some(&"balance"),
+            Some(&"history"),
+            None,
+        )
+        | (
+            &Method::GET,
+            Some(script_type @ &"scripthash"),
+            Some(script_str),
+            Some(&"balance"),
+            Some(&"history"),
+            None,
+        ) => {
+            let script_hash = to_scripthash(script_type, script_str, config.network_type)?;
+            let interval = match query_params.get("interval").map(String::as_str) {
+                None | Some("tx") => BalanceInterval::Tx,
+                Some("block") => BalanceInterval::Block,
+                Some("day") => BalanceInterval::Day,
+                Some(_) => bail!(HttpError::from("Invalid interval".to_string())),
+            };
+            let start_height: usize = query_params
+                .get("cursor")
+                .map(|height| height.parse())
+                .transpose()?
+                .unwrap_or(0);
+            let limit: usize = query_params
+                .get("limit")
+                .map(|n| n.parse())
+                .transpose()?
+                .map(|n: usize| n.max(1).min(BALANCE_POINTS_MAX_PER_PAGE))
+                .unwrap_or(BALANCE_POINTS_PER_PAGE);
+
+            let (points, next_height) =
+                query
+                    .chain()
+                    .balance_history(&script_hash[..], interval, start_height, limit)?;
+
+            let mut resp = Response::builder()
+                .status(StatusCode::OK)
+                // Disable caching because we don't currently support caching with query string params
+                .header("Cache-Control", "no-store")
+                .header("Content-Type", "application/json");
+            if let Some(next_height) = next_height {
+                resp = resp.header("X-Next-Cursor", next_height.to_string());
+            }
+            Ok(resp
+                .body(Body::from(serde_json::to_string(&points)?))
+                .unwrap())
+        }
+        (
+            &Method::GET,
+            Some(script_type @ &"address"),
+            Some(script_str),
+            Some(&"txs"),
+            None,
+            None,
+        )
+        | (
+            &Method::GET,
+            Some(script_type @ &"scripthash"),
+            Some(script_str),
+            Some(&"txs"),
+            None,
+            None,
+        ) => {
+            let script_hash = to_scripthash(script_type, script_str, config.network_type)?;
+
+            let mut txs = vec![];
+
+            txs.extend(
+                query
+                    .mempool()
+                    .history(&script_hash[..], limits.max_mempool_txs)
+                    .into_iter()
+                    .map(|tx| (tx, None)),
+            );
 
-            let mut txs = vec![];
-
-            txs.extend(
-                query
-                    .mempool()
-                    .history(&script_hash[..], MAX_MEMPOOL_TXS)
-                    .into_iter()
-                    .map(|tx| (tx, None)),
-            );
-
             txs.extend(
                 query
                     .chain()
-                    .history(&script_hash[..], None, CHAIN_TXS_PER_PAGE)
+                    .history(&script_hash[..], None, limits.chain_txs_per_page)
                     .into_iter()
                     .map(|(tx, blockid)| (tx, Some(blockid))),
             );
@@ -828,7 +1730,7 @@ fn handle_request(
                 .history(
                     &script_hash[..],
                     last_seen_txid.as_ref(),
-                    CHAIN_TXS_PER_PAGE,
+                    limits.chain_txs_per_page,
                 )
                 .into_iter()
                 .map(|(tx, blockid)| (tx, Some(blockid)))
@@ -836,6 +1738,61 @@ fn handle_request(
 
             json_response(prepare_txs(txs, query, config), TTL_SHORT)
         }
+        (
+            &Method::GET,
+            Some(script_type @ &"address"),
+            Some(script_str),
+            Some(&"txs"),
+            Some(&"range"),
+            None,
+        )
+        | (
+            &Method::GET,
+            Some(script_type @ &"scripthash"),
+            Some(script_str),
+            Some(&"txs"),
+            Some(&"range"),
+            None,
+        ) => {
+            let script_hash = to_scripthash(script_type, script_str, config.network_type)?;
+            let descending = match query_params.get("order").map(String::as_str) {
+                None | Some("desc") => true,
+                Some("asc") => false,
+                Some(_) => bail!(HttpError::from("Invalid order".to_string())),
+            };
+            let last_seen_txid = query_params
+                .get("after_txid")
+                .map(|txid| Txid::from_hex(txid))
+                .transpose()?;
+
+            let txids = match height_range(&query_params, query)? {
+                Some((start_height, end_height)) => query.chain().history_range_txids(
+                    &script_hash[..],
+                    start_height,
+                    end_height,
+                    descending,
+                    last_seen_txid.as_ref(),
+                    limits.chain_txs_per_page,
+                ),
+                None => vec![],
+            };
+            let txs = query
+                .chain()
+                .lookup_txns(&txids)?
+                .into_iter()
+                .zip(txids)
+                .map(|(tx, (_, blockid))| (tx, Some(blockid)))
+                .collect();
+
+            Ok(Response::builder()
+                // Disable caching because we don't currently support caching with query string params
+                .header("Cache-Control", "no-store")
+                .header("Content-Type", "application/json")
+                .body(Body::from(serde_json::to_string(&prepare_txs(
+                    txs, query, config,
+                ))?))
+                .unwrap())
+        }
         (
             &Method::GET,
             Some(script_type @ &"address"),
@@ -856,7 +1813,7 @@ fn handle_request(
 
             let txs = query
                 .mempool()
-                .history(&script_hash[..], MAX_MEMPOOL_TXS)
+                .history(&script_hash[..], limits.max_mempool_txs)
                 .into_iter()
                 .map(|tx| (tx, None))
                 .collect();
@@ -882,13 +1839,71 @@ fn handle_request(
         ) => {
             let script_hash = to_scripthash(script_type, script_str, config.network_type)?;
             let utxos: Vec<UtxoValue> = query
-                .utxo(&script_hash[..])?
+                .utxo_with_limit(&script_hash[..], limits.utxos_limit)?
                 .into_iter()
                 .map(UtxoValue::from)
                 .collect();
-            // XXX paging?
             json_response(utxos, TTL_SHORT)
         }
+        (
+            &Method::GET,
+            Some(script_type @ &"address"),
+            Some(script_str),
+            Some(&"utxo"),
+            Some(&"page"),
+            None,
+        )
+        | (
+            &Method::GET,
+            Some(script_type @ &"scripthash"),
+            Some(script_str),
+            Some(&"utxo"),
+            Some(&"page"),
+            None,
+        ) => {
+            let script_hash = to_scripthash(script_type, script_str, config.network_type)?;
+            let cursor = query_params
+                .get("cursor")
+                .map(|cursor| cursor.parse::<UtxoCursor>())
+                .transpose()?;
+            let min_value: u64 = query_params
+                .get("min_value")
+                .map(|n| n.parse())
+                .transpose()?
+                .unwrap_or(0);
+            let min_conf: u32 = query_params
+                .get("min_conf")
+                .map(|n| n.parse())
+                .transpose()?
+                .unwrap_or(0);
+            let limit: usize = query_params
+                .get("limit")
+                .map(|n| n.parse())
+                .transpose()?
+                .map(|n: usize| n.max(1).min(UTXOS_MAX_PER_PAGE))
+                .unwrap_or(UTXOS_PER_PAGE);
+
+            let (utxos, next_cursor) = query.utxo_page(
+                &script_hash[..],
+                cursor.as_ref(),
+                min_value,
+                min_conf,
+                limit,
+            )?;
+            let utxos: Vec<UtxoValue> = utxos.into_iter().map(UtxoValue::from).collect();
+
+            let mut resp = Response::builder()
+                .status(StatusCode::OK)
+                // Disable caching because we don't currently support caching with query string params
+                .header("Cache-Control", "no-store")
+                .header("Content-Type", "application/json");
+            if let Some(next_cursor) = next_cursor {
+                resp = resp.header("X-Next-Cursor", next_cursor.to_string());
+            }
+            Ok(resp
+                .body(Body::from(serde_json::to_string(&utxos)?))
+                .unwrap())
+        }
         (&Method::GET, Some(&"address-prefix"), Some(prefix), None, None, None) => {
             if !config.address_search {
                 return Err(HttpError::from("address search disabled".to_string()));
@@ -898,8 +1913,10 @@ fn handle_request(
         }
         (&Method::GET, Some(&"tx"), Some(hash), None, None, None) => {
             let hash = Txid::from_hex(hash)?;
+            // recently dropped txs are returned with their removal reason
             let tx = query
                 .lookup_txn(&hash)
+                .or_else(|| query.mempool().lookup_dropped_txn(&hash))
                 .ok_or_else(|| HttpError::not_found("Transaction not found".to_string()))?;
             let blockid = query.chain().tx_confirming_block(&hash);
             let ttl = ttl_by_depth(blockid.as_ref().map(|b| b.height), query);
@@ -929,6 +1946,38 @@ fn handle_request(
                 .body(body)
                 .unwrap())
         }
+        (&Method::GET, Some(&"tx"), Some(hash), Some(&"projected-position"), None, None) => {
+            let hash = Txid::from_hex(hash)?;
+            let mempool = query.mempool();
+            let position = mempool.projected_position(&hash).ok_or_else(|| {
+                HttpError::not_found("Transaction not found in the mempool projection".to_string())
+            })?;
+            json_response(position, TTL_SHORT)
+        }
+        (&Method::GET, Some(&"tx"), Some(hash), Some(&"rbf"), None, None) => {
+            let hash = Txid::from_hex(hash)?;
+            let mempool = query.mempool();
+            let replaced_by = mempool.replaced_by(&hash);
+
+            // follow the replacements up to the latest one
+            let mut latest = replaced_by;
+            let mut seen = HashSet::new();
+            while let Some(replacement) = latest.and_then(|r| mempool.replaced_by(&r.txid)) {
+                if !seen.insert(replacement.txid) {
+                    break;
+                }
+                latest = Some(replacement);
+            }
+
+            json_response(
+                json!({
+                    "replaced_by": replaced_by,
+                    "replaces": mempool.replaces(&hash),
+                    "latest_replacement": latest.map(|r| r.txid),
+                }),
+                TTL_SHORT,
+            )
+        }
         (&Method::GET, Some(&"tx"), Some(hash), Some(&"status"), None, None) => {
             let hash = Txid::from_hex(hash)?;
             let status = query.get_tx_status(&hash);
@@ -1011,10 +2060,47 @@ fn handle_request(
                     .ok_or_else(|| HttpError::from("Missing tx".to_string()))?,
                 _ => return http_message(StatusCode::METHOD_NOT_ALLOWED, "Invalid method", 0),
             };
-            let txid = query
-                .broadcast_raw(&txhex)
-                .map_err(|err| HttpError::from(err.description().to_string()))?;
-            http_message(StatusCode::OK, txid.to_hex(), 0)
+            match query.broadcast_raw(&txhex) {
+                Ok(txid) => http_message(StatusCode::OK, txid.to_hex(), 0),
+                Err(err) => rpc_error_response(err),
+            }
+        }
+        (&Method::POST, Some(&"txs"), Some(&"test"), None, None, None) => {
+            let txhexes = parse_raw_txs(&body)?;
+            match query.test_mempool_accept(&txhexes) {
+                Ok(results) => json_response(
+                    results
+                        .into_iter()
+                        .map(MempoolAcceptValue::from)
+                        .collect::<Vec<_>>(),
+                    0,
+                ),
+                Err(err) => rpc_error_response(err),
+            }
+        }
+        (&Method::POST, Some(&"txs"), Some(&"package"), None, None, None) => {
+            let txhexes = parse_raw_txs(&body)?;
+            let txids = package_txids(&txhexes)?;
+            let results = query.broadcast_package(&txhexes);
+            let rejected = results.iter().any(Result::is_err);
+            // the txs following a rejected one are not broadcast
+            let values: Vec<PackageTxValue> = txids
+                .into_iter()
+                .enumerate()
+                .map(|(i, txid)| PackageTxValue {
+                    txid,
+                    accepted: results.get(i).map_or(false, Result::is_ok),
+                    error: results
+                        .get(i)
+                        .and_then(|result| result.as_ref().err())
+                        .map(RpcErrorValue::from),
+                })
+                .collect();
+            let mut resp = json_response(values, 0)?;
+            if rejected {
+                *resp.status_mut() = StatusCode::BAD_REQUEST;
+            }
+            Ok(resp)
         }
 
         (&Method::GET, Some(&"mempool"), None, None, None, None) => {
@@ -1023,6 +2109,9 @@ fn handle_request(
         (&Method::GET, Some(&"mempool"), Some(&"txids"), None, None, None) => {
             json_response(query.mempool().txids(), TTL_SHORT)
         }
+        (&Method::GET, Some(&"mempool"), Some(&"blocks"), None, None, None) => {
+            json_response(query.mempool().projected_blocks(), TTL_SHORT)
+        }
         (&Method::GET, Some(&"mempool"), Some(&"recent"), None, None, None) => {
             let mempool = query.mempool();
             let recent = mempool.recent_txs_overview();
@@ -1033,6 +2122,17 @@ fn handle_request(
             json_response(query.estimate_fee_map(), TTL_SHORT)
         }
 
+        (&Method::GET, Some(&"events"), None, None, None, None) => {
+            let filter = EventFilter::new(&query_params, config.network_type)?;
+            let body = events.subscribe(filter)?;
+            Ok(Response::builder()
+                .status(StatusCode::OK)
+                .header("Content-Type", "text/event-stream")
+                .header("Cache-Control", "no-store")
+                .body(body)
+                .unwrap())
+        }
+
         #[cfg(feature = "liquid")]
         (&Method::GET, Some(&"assets"), Some(&"registry"), None, None, None) => {
             let start_index: usize = query_params
@@ -1078,7 +2178,7 @@ fn handle_request(
             txs.extend(
                 query
                     .mempool()
-                    .asset_history(&asset_id, MAX_MEMPOOL_TXS)
+                    .asset_history(&asset_id, limits.max_mempool_txs)
                     .into_iter()
                     .map(|tx| (tx, None)),
             );
@@ -1086,7 +2186,7 @@ fn handle_request(
             txs.extend(
                 query
                     .chain()
-                    .asset_history(&asset_id, None, CHAIN_TXS_PER_PAGE)
+                    .asset_history(&asset_id, None, limits.chain_txs_per_page)
                     .into_iter()
                     .map(|(tx, blockid)| (tx, Some(blockid))),
             );
@@ -1108,7 +2208,11 @@ fn handle_request(
 
             let txs = query
                 .chain()
-                .asset_history(&asset_id, last_seen_txid.as_ref(), CHAIN_TXS_PER_PAGE)
+                .asset_history(
+                    &asset_id,
+                    last_seen_txid.as_ref(),
+                    limits.chain_txs_per_page,
+                )
                 .into_iter()
                 .map(|(tx, blockid)| (tx, Some(blockid)))
                 .collect();
@@ -1122,7 +2226,7 @@ fn handle_request(
 
             let txs = query
                 .mempool()
-                .asset_history(&asset_id, MAX_MEMPOOL_TXS)
+                .asset_history(&asset_id, limits.max_mempool_txs)
                 .into_iter()
                 .map(|tx| (tx, None))
                 .collect();
@@ -1157,6 +2261,208 @@ fn handle_request(
     }
 }
 
+#[derive(Deserialize)]
+struct WebhookRequest {
+    address: Option<String>,
+    scripthash: Option<String>,
+    url: String,
+    min_confirmations: Option<u32>,
+    secret: String,
+}
+
+// A webhook subscription, without its secret
+#[derive(Serialize)]
+struct WebhookValue<'a> {
+    id: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    address: Option<&'a str>,
+    scripthash: &'a str,
+    url: &'a str,
+    min_confirmations: u32,
+}
+
+impl<'a> From<&'a Subscription> for WebhookValue<'a> {
+    fn from(sub: &'a Subscription) -> Self {
+        WebhookValue {
+            id: &sub.id,
+            address: sub.address.as_deref(),
+            scripthash: &sub.scripthash,
+            url: &sub.url,
+            min_confirmations: sub.min_confirmations,
+        }
+    }
+}
+
+fn handle_admin_request(
+    method: Method,
+    uri: hyper::Uri,
+    body: &[u8],
+    config: &Config,
+    webhooks: Option<&Webhooks>,
+) -> Result<Response<Body>, HttpError> {
+    let webhooks =
+        webhooks.ok_or_else(|| HttpError::not_found("Webhooks are disabled".to_string()))?;
+    let path: Vec<&str> = uri.path().split('/').skip(1).collect();
+
+    info!("handle admin {:?} {:?}", method, uri);
+    match (&method, path.get(1), path.get(2), path.get(3)) {
+        (&Method::GET, Some(&"webhooks"), None, None) => {
+            let subs = webhooks.subscriptions();
+            json_response(
+                subs.iter()
+                    .map(|sub| WebhookValue::from(&**sub))
+                    .collect::<Vec<_>>(),
+                0,
+            )
+        }
+        (&Method::POST, Some(&"webhooks"), None, None) => {
+            let request: WebhookRequest = serde_json::from_slice(body)?;
+            let (scripthash, address) = match (request.address, request.scripthash) {
+                (Some(address), None) => (
+                    address_to_scripthash(&address, config.network_type)?,
+                    Some(address),
+                ),
+                (None, Some(scripthash)) => (parse_scripthash(&scripthash)?, None),
+                _ => bail!(HttpError::from(
+                    "Either an address or a scripthash is required".to_string()
+                )),
+            };
+            let sub = webhooks.subscribe(
+                &scripthash,
+                address,
+                &request.url,
+                request.min_confirmations.unwrap_or(1),
+                request.secret,
+            )?;
+            json_response(WebhookValue::from(&*sub), 0)
+        }
+        (&Method::DELETE, Some(&"webhooks"), Some(id), None) => {
+            if webhooks.unsubscribe(id) {
+                http_message(StatusCode::OK, "Webhook removed", 0)
+            } else {
+                Err(HttpError::not_found("Webhook not found".to_string()))
+            }
+        }
+        _ => Err(HttpError::not_found(format!(
+            "endpoint does not exist {:?}",
+            uri.path()
+        ))),
+    }
+}
+
+// The raw txs of a POST /txs/... request: a JSON array of hex txs, or a single hex tx
+fn parse_raw_txs(body: &[u8]) -> Result<Vec<String>, HttpError> {
+    let body = String::from_utf8(body.to_vec())?;
+    let txhexes: Vec<String> = if body.trim_start().starts_with('[') {
+        serde_json::from_str(&body)?
+    } else {
+        vec![body.trim().to_string()]
+    };
+    if txhexes.is_empty() || txhexes.len() > PACKAGE_MAX_TXS {
+        bail!(HttpError::from(format!(
+            "Between 1 and {} txs are required",
+            PACKAGE_MAX_TXS
+        )))
+    }
+    Ok(txhexes)
+}
+
+// The txids of a package, which has to list the parents before their children
+fn package_txids(txhexes: &[String]) -> Result<Vec<Txid>, HttpError> {
+    let mut txs: Vec<Transaction> = vec![];
+    for txhex in txhexes {
+        txs.push(encode::deserialize(&hex::decode(txhex)?)?);
+    }
+    let txids: Vec<Txid> = txs.iter().map(|tx| tx.txid()).collect();
+    for (i, tx) in txs.iter().enumerate() {
+        if txids[..i].contains(&txids[i]) {
+            bail!(HttpError::from(format!(
+                "Duplicate tx {} in package",
+                txids[i]
+            )))
+        }
+        let spends_later_tx = tx
+            .input
+            .iter()
+            .any(|txin| txids[i..].contains(&txin.previous_output.txid));
+        if spends_later_tx {
+            bail!(HttpError::from(
+                "Package txs must be ordered with the parents before their children".to_string()
+            ))
+        }
+    }
+    Ok(txids)
+}
+
+// A daemon error, with the JSON-RPC error code when the daemon rejected the request
+#[derive(Serialize)]
+struct RpcErrorValue {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<i64>,
+    message: String,
+}
+
+impl From<&errors::Error> for RpcErrorValue {
+    fn from(err: &errors::Error) -> Self {
+        match err.kind() {
+            errors::ErrorKind::RpcError(_, code, message) => RpcErrorValue {
+                code: Some(*code),
+                message: message.clone(),
+            },
+            _ => RpcErrorValue {
+                code: None,
+                message: err.to_string(),
+            },
+        }
+    }
+}
+
+// Responds with the JSON-RPC error code and message of the requests rejected by the daemon
+fn rpc_error_response(err: errors::Error) -> Result<Response<Body>, HttpError> {
+    match err.kind() {
+        errors::ErrorKind::RpcError(..) => {
+            let mut resp = json_response(RpcErrorValue::from(&err), 0)?;
+            *resp.status_mut() = StatusCode::BAD_REQUEST;
+            Ok(resp)
+        }
+        _ => Err(HttpError::from(err)),
+    }
+}
+
+#[derive(Serialize)]
+struct MempoolAcceptValue {
+    txid: Txid,
+    allowed: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    vsize: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fee: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reject_reason: Option<String>,
+}
+
+impl From<MempoolAcceptResult> for MempoolAcceptValue {
+    fn from(result: MempoolAcceptResult) -> Self {
+        MempoolAcceptValue {
+            txid: result.txid,
+            allowed: result.allowed.unwrap_or(false),
+            vsize: result.vsize,
+            fee: result
+                .fees
+                .map(|fees| (fees.base * 100_000_000f64).round() as u64),
+            reject_reason: result.reject_reason,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct PackageTxValue {
+    txid: Txid,
+    accepted: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<RpcErrorValue>,
+}
+
 fn http_message<T>(status: StatusCode, message: T, ttl: u32) -> Result<Response<Body>, HttpError>
 where
     T: Into<Body>,
@@ -1218,6 +2524,238 @@ fn blocks(
     json_response(values, TTL_SHORT)
 }
 
+// Parses the (inclusive) height range of a history query, given as heights or as timestamps.
+// Returns None if the range is before the genesis block.
+fn height_range(
+    query_params: &HashMap<String, String>,
+    query: &Query,
+) -> Result<Option<(usize, usize)>, HttpError> {
+    let param = |name: &str| -> Result<Option<u32>, HttpError> {
+        query_params
+            .get(name)
+            .map(|value| {
+                value
+                    .parse()
+                    .map_err(|_| HttpError::from(format!("Invalid {}", name)))
+            })
+            .transpose()
+    };
+
+    let start_height = match (param("start_height")?, param("start_time")?) {
+        (Some(_), Some(_)) => bail!(HttpError::from(
+            "start_height and start_time are mutually exclusive".to_string()
+        )),
+        (Some(height), None) => height as usize,
+        (None, Some(time)) => query.chain().height_by_time(time),
+        (None, None) => 0,
+    };
+    let end_height = match (param("end_height")?, param("end_time")?) {
+        (Some(_), Some(_)) => bail!(HttpError::from(
+            "end_height and end_time are mutually exclusive".to_string()
+        )),
+        (Some(height), None) => height as usize,
+        (None, Some(time)) => match query.chain().height_by_time(time.saturating_add(1)) {
+            0 => return Ok(None),
+            height => height - 1,
+        },
+        (None, None) => query.chain().best_height(),
+    };
+    Ok(Some((start_height, end_height)))
+}
+
+// Parses the JSON array of addresses (or scripthashes) of a batch request
+fn parse_batch_scripts(
+    script_types: &str,
+    body: &[u8],
+    config: &Config,
+) -> Result<(&'static str, Vec<(String, FullHash)>), HttpError> {
+    let script_type = match script_types {
+        "addresses" => "address",
+        _ => "scripthash",
+    };
+    let scripts: Vec<String> = serde_json::from_slice(body)?;
+    if scripts.is_empty() || scripts.len() > config.addresses_batch_limit {
+        bail!(HttpError::from(format!(
+            "Batch size must be between 1 and {}",
+            config.addresses_batch_limit
+        )))
+    }
+    let scripts = scripts
+        .into_iter()
+        .map(|script_str| {
+            let script_hash = to_scripthash(script_type, &script_str, config.network_type)?;
+            Ok((script_str, script_hash))
+        })
+        .collect::<Result<_, HttpError>>()?;
+    Ok((script_type, scripts))
+}
+
+// Maps the scripts in parallel, accounting the DB rows read by the thread pool to the current
+// thread (so that batch requests are rate limited like the equivalent individual requests)
+fn par_map_scripts<T, F>(scripts: &[(String, FullHash)], f: F) -> Vec<T>
+where
+    T: Send,
+    F: Fn(&FullHash) -> T + Sync,
+{
+    let results: Vec<(T, u64)> = scripts
+        .par_iter()
+        .map(|(_, script_hash)| ratelimit::measure_scanned_rows(|| f(script_hash)))
+        .collect();
+    ratelimit::record_scanned_rows(results.iter().map(|(_, rows)| rows).sum());
+    results.into_iter().map(|(result, _)| result).collect()
+}
+
+// The utxos of the scripts, tagged with their address (or scripthash)
+fn batch_utxos(
+    script_type: &str,
+    scripts: &[(String, FullHash)],
+    query: &Query,
+    limits: &ResponseLimits,
+) -> Result<Vec<serde_json::Value>, HttpError> {
+    let utxos = par_map_scripts(scripts, |script_hash| {
+        query.utxo_with_limit(&script_hash[..], limits.utxos_limit)
+    });
+    let mut values = vec![];
+    for ((script_str, _), utxos) in scripts.iter().zip(utxos) {
+        for utxo in utxos? {
+            let mut value = serde_json::to_value(UtxoValue::from(utxo))?;
+            value[script_type] = json!(script_str);
+            values.push(value);
+        }
+    }
+    Ok(values)
+}
+
+// The most recent mempool and confirmed txs of the scripts, merged and deduplicated
+fn batch_history(
+    scripts: &[(String, FullHash)],
+    query: &Query,
+    limits: &ResponseLimits,
+) -> Vec<(Transaction, Option<BlockId>)> {
+    let histories = par_map_scripts(scripts, |script_hash| {
+        (
+            query
+                .mempool()
+                .history(&script_hash[..], limits.max_mempool_txs),
+            query
+                .chain()
+                .history(&script_hash[..], None, limits.chain_txs_per_page),
+        )
+    });
+
+    let mut seen = HashSet::new();
+    let mut mempool_txs = vec![];
+    let mut chain_txs = vec![];
+    for (mempool_history, chain_history) in histories {
+        for tx in mempool_history {
+            if seen.insert(tx.txid()) {
+                mempool_txs.push((tx, None));
+            }
+        }
+        for (tx, blockid) in chain_history {
+            if seen.insert(tx.txid()) {
+                chain_txs.push((tx, blockid));
+            }
+        }
+    }
+    mempool_txs.truncate(limits.max_mempool_txs);
+    chain_txs.sort_by_key(|(_, blockid)| std::cmp::Reverse(blockid.height));
+    chain_txs.truncate(limits.chain_txs_per_page);
+
+    let mut txs = mempool_txs;
+    txs.extend(
+        chain_txs
+            .into_iter()
+            .map(|(tx, blockid)| (tx, Some(blockid))),
+    );
+    txs
+}
+
+// Scans the key chains until `gap_limit` consecutive unused addresses are found, and returns
+// the used addresses along with their combined balance, utxos and history
+#[cfg(not(feature = "liquid"))]
+fn scan_key_chains(
+    chains: &[xpub::KeyChain],
+    query_params: &HashMap<String, String>,
+    query: &Query,
+    config: &Config,
+    limits: &ResponseLimits,
+) -> Result<Response<Body>, HttpError> {
+    let gap_limit: u32 = query_params
+        .get("gap_limit")
+        .map(|n| n.parse())
+        .transpose()?
+        .unwrap_or(config.xpub_gap_limit)
+        .max(1)
+        .min(XPUB_MAX_GAP_LIMIT);
+    let secp = Secp256k1::verification_only();
+
+    let mut used = vec![]; // (path, address, scripthash, stats)
+    let mut chain_values = vec![];
+    for chain in chains {
+        let mut next_index = 0;
+        let mut index = 0;
+        while index < next_index + gap_limit {
+            if index >= XPUB_MAX_ADDRESSES {
+                bail!(HttpError::from("Too many used addresses".to_string()))
+            }
+            // check the addresses up to the end of the gap in parallel
+            let end = next_index + gap_limit;
+            let scripts = (index..end)
+                .map(|i| {
+                    let address = chain.address(&secp, i)?;
+                    let script_hash = compute_script_hash(&address.script_pubkey());
+                    Ok((address.to_string(), script_hash))
+                })
+                .collect::<Result<Vec<_>, errors::Error>>()?;
+            let stats = par_map_scripts(&scripts, |script_hash| query.stats(&script_hash[..]));
+            for ((i, (address, script_hash)), stats) in (index..end).zip(scripts).zip(stats) {
+                if stats.0.tx_count + stats.1.tx_count > 0 {
+                    next_index = i + 1;
+                    let path = match chain.path.as_str() {
+                        "" => i.to_string(),
+                        chain_path => format!("{}/{}", chain_path, i),
+                    };
+                    used.push((path, address, script_hash, stats));
+                }
+            }
+            index = end;
+        }
+        chain_values.push(json!({ "path": chain.path, "next_index": next_index }));
+    }
+
+    let (mut confirmed, mut unconfirmed) = (0u64, 0i64);
+    for (_, _, _, (chain_stats, mempool_stats)) in &used {
+        confirmed += chain_stats.balance()?;
+        unconfirmed += mempool_stats.funded_txo_sum as i64 - mempool_stats.spent_txo_sum as i64;
+    }
+    let scripts: Vec<(String, FullHash)> = used
+        .iter()
+        .map(|(_, address, script_hash, _)| (address.clone(), *script_hash))
+        .collect();
+    let utxos = batch_utxos("address", &scripts, query, limits)?;
+    let txs = batch_history(&scripts, query, limits);
+
+    json_response(
+        json!({
+            "chains": chain_values,
+            "addresses": used
+                .into_iter()
+                .map(|(path, address, _, stats)| json!({
+                    "path": path,
+                    "address": address,
+                    "chain_stats": stats.0,
+                    "mempool_stats": stats.1,
+                }))
+                .collect::<Vec<_>>(),
+            "balance": { "confirmed": confirmed, "unconfirmed": unconfirmed },
+            "utxos": utxos,
+            "txs": prepare_txs(txs, query, config),
+        }),
+        TTL_SHORT,
+    )
+}
+
 fn to_scripthash(
     script_type: &str,
     script_str: &str,
@@ -1250,6 +2788,16 @@ fn address_to_scripthash(addr: &str, network: Network) -> Result<FullHash, HttpE
     Ok(compute_script_hash(&addr.script_pubkey()))
 }
 
+// Blocks indexed by older versions have no stats, which is told apart from unknown blocks
+#[cfg(not(feature = "liquid"))]
+fn block_data_not_found(query: &Query, hash: &BlockHash, what: &str) -> HttpError {
+    if query.chain().get_block_header(hash).is_some() {
+        HttpError::not_found(format!("Block {} not indexed", what))
+    } else {
+        HttpError::not_found("Block not found".to_string())
+    }
+}
+
 fn parse_scripthash(scripthash: &str) -> Result<FullHash, HttpError> {
     let bytes = hex::decode(scripthash)?;
     if bytes.len() != 32 {
@@ -1305,9 +2853,12 @@ impl From<bitcoin::util::address::Error> for HttpError {
 }
 impl From<errors::Error> for HttpError {
     fn from(e: errors::Error) -> Self {
+        if let errors::ErrorKind::RateLimited = e.kind() {
+            return HttpError(StatusCode::TOO_MANY_REQUESTS, e.to_string());
+        }
         warn!("errors::Error: {:?}", e);
-        match e.description().to_string().as_ref() {
-            "getblock RPC error: {\"code\":-5,\"message\":\"Block not found\"}" => {
+        match e.kind() {
+            errors::ErrorKind::RpcError(method, -5, _) if method == "getblock" => {
                 HttpError::not_found("Block not found".to_string())
             }
             _ => HttpError::from(e.to_string()),
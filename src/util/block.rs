@@ -2,8 +2,17 @@ use crate::chain::BlockHeader;
 use crate::errors::*;
 use crate::new_index::BlockEntry;
 
+#[cfg(not(feature = "liquid"))]
+use crate::chain::{Network, OutPoint, TxOut};
+#[cfg(not(feature = "liquid"))]
+use crate::util::fees::{feerate_percentiles, make_fee_histogram, sort_by_feerate, TxFeeInfo};
+#[cfg(not(feature = "liquid"))]
+use crate::util::{get_stats_script_type, has_prevout};
+
 use bitcoin::BlockHash;
 
+#[cfg(not(feature = "liquid"))]
+use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::fmt;
 use std::iter::FromIterator;
@@ -235,6 +244,22 @@ impl HeaderList {
         self.headers.iter()
     }
 
+    /// Get the height of the first block with a timestamp at or after `time` (or the chain length
+    /// if there is none). Block timestamps are not strictly monotonic, so blocks around the
+    /// boundary may be out of order.
+    pub fn height_by_time(&self, time: u32) -> usize {
+        let (mut low, mut high) = (0, self.headers.len());
+        while low < high {
+            let mid = (low + high) / 2;
+            if self.headers[mid].header.time < time {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        low
+    }
+
     /// Get the Median Time Past
     pub fn get_mtp(&self, height: usize) -> u32 {
         // Use the timestamp as the mtp of the genesis block.
@@ -286,6 +311,121 @@ pub struct BlockMeta {
     pub weight: u32,
 }
 
+// Feerate percentiles, weighted by vsize (like bitcoind's getblockstats)
+#[cfg(not(feature = "liquid"))]
+const FEERATE_PERCENTILES: [u64; 5] = [10, 25, 50, 75, 90];
+
+/// Script type and fee statistics of a block (excluding the coinbase for input and fee stats).
+#[cfg(not(feature = "liquid"))]
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct BlockStats {
+    pub tx_count: u32,
+    pub segwit_tx_count: u32,
+    pub input_count: u32,
+    pub output_count: u32,
+    pub inputs_by_type: BTreeMap<String, u32>, // by the script type of the spent prevout
+    pub outputs_by_type: BTreeMap<String, u32>,
+    pub total_out: u64,
+    pub total_fee: u64,
+    pub feerate_percentiles: Vec<f32>, // in sat/vbyte, for FEERATE_PERCENTILES
+}
+
+#[cfg(not(feature = "liquid"))]
+impl BlockStats {
+    pub fn new(
+        b: &BlockEntry,
+        previous_txos_map: &HashMap<OutPoint, TxOut>,
+        feeinfos: &[TxFeeInfo],
+    ) -> Self {
+        let mut stats = BlockStats {
+            tx_count: b.block.txdata.len() as u32,
+            total_fee: feeinfos.iter().map(|e| e.fee).sum(),
+            feerate_percentiles: feerate_percentiles(
+                &sort_by_feerate(feeinfos),
+                &FEERATE_PERCENTILES,
+            ),
+            ..Default::default()
+        };
+        for tx in &b.block.txdata {
+            if tx.input.iter().any(|txin| !txin.witness.is_empty()) {
+                stats.segwit_tx_count += 1;
+            }
+            for txo in &tx.output {
+                stats.output_count += 1;
+                stats.total_out += txo.value;
+                *stats
+                    .outputs_by_type
+                    .entry(get_stats_script_type(&txo.script_pubkey).to_string())
+                    .or_insert(0) += 1;
+            }
+            for txin in tx.input.iter().filter(|txin| has_prevout(txin)) {
+                let prevout = previous_txos_map
+                    .get(&txin.previous_output)
+                    .expect("missing prevout for block stats");
+                stats.input_count += 1;
+                *stats
+                    .inputs_by_type
+                    .entry(get_stats_script_type(&prevout.script_pubkey).to_string())
+                    .or_insert(0) += 1;
+            }
+        }
+        stats
+    }
+}
+
+/// Feerate distribution of a block (excluding the coinbase).
+#[cfg(not(feature = "liquid"))]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BlockFees {
+    pub total_fee: u64,
+    pub min_feerate: Option<f32>, // in sat/vbyte, None for blocks with the coinbase only
+    pub median_feerate: Option<f32>, // weighted by vsize
+    pub max_feerate: Option<f32>,
+    pub fee_histogram: Vec<(f32, u32)>, // in the same format as the mempool's
+}
+
+#[cfg(not(feature = "liquid"))]
+impl BlockFees {
+    pub fn new(feeinfos: &[TxFeeInfo]) -> Self {
+        let sorted = sort_by_feerate(feeinfos);
+        BlockFees {
+            total_fee: feeinfos.iter().map(|e| e.fee).sum(),
+            min_feerate: sorted.first().map(|e| e.fee_per_vbyte),
+            median_feerate: feerate_percentiles(&sorted, &[50]).first().cloned(),
+            max_feerate: sorted.last().map(|e| e.fee_per_vbyte),
+            fee_histogram: make_fee_histogram(sorted),
+        }
+    }
+}
+
+/// Returns the fee info of the block's transactions (except the coinbase).
+#[cfg(not(feature = "liquid"))]
+pub fn block_feeinfos(
+    b: &BlockEntry,
+    previous_txos_map: &HashMap<OutPoint, TxOut>,
+    network: Network,
+) -> Vec<TxFeeInfo> {
+    b.block
+        .txdata
+        .iter()
+        .filter(|tx| !tx.is_coin_base())
+        .map(|tx| {
+            let prevouts: HashMap<u32, &TxOut> = tx
+                .input
+                .iter()
+                .enumerate()
+                .map(|(index, txin)| {
+                    let prevout = previous_txos_map
+                        .get(&txin.previous_output)
+                        .expect("missing prevout for block fees");
+                    (index as u32, prevout)
+                })
+                .collect();
+            TxFeeInfo::new(tx, &prevouts, network)
+        })
+        .collect()
+}
+
 pub struct BlockHeaderMeta {
     pub header_entry: HeaderEntry,
     pub meta: BlockMeta,
@@ -2,11 +2,13 @@ use crate::chain::{Network, Transaction, TxOut};
 use std::collections::HashMap;
 
 const VSIZE_BIN_WIDTH: u32 = 50_000; // in vbytes
+const ESTIMATE_BLOCK_VSIZE: u64 = 1_000_000; // in vbytes (= max block weight/4)
 
 pub struct TxFeeInfo {
     pub fee: u64,   // in satoshis
     pub vsize: u32, // in virtual bytes (= weight/4)
     pub fee_per_vbyte: f32,
+    pub effective_fee_per_vbyte: f32, // taking CPFP into account (for mempool txs)
 }
 
 impl TxFeeInfo {
@@ -14,10 +16,12 @@ impl TxFeeInfo {
         let fee = get_tx_fee(tx, prevouts, network);
         let vsize = tx.get_weight() / 4;
 
+        let fee_per_vbyte = fee as f32 / vsize as f32;
         TxFeeInfo {
             fee,
             vsize: vsize as u32,
-            fee_per_vbyte: fee as f32 / vsize as f32,
+            fee_per_vbyte,
+            effective_fee_per_vbyte: fee_per_vbyte,
         }
     }
 }
@@ -38,19 +42,24 @@ pub fn get_tx_fee(tx: &Transaction, _prevouts: &HashMap<u32, &TxOut>, network: N
     tx.fee_in(*network.native_asset())
 }
 
+// Uses the effective feerates, so that CPFP packages are binned by the rate they get mined at
 pub fn make_fee_histogram(mut entries: Vec<&TxFeeInfo>) -> Vec<(f32, u32)> {
-    entries.sort_unstable_by(|e1, e2| e1.fee_per_vbyte.partial_cmp(&e2.fee_per_vbyte).unwrap());
+    entries.sort_unstable_by(|e1, e2| {
+        e1.effective_fee_per_vbyte
+            .partial_cmp(&e2.effective_fee_per_vbyte)
+            .unwrap()
+    });
 
     let mut histogram = vec![];
     let mut bin_size = 0;
     let mut last_fee_rate = 0.0;
     for e in entries.iter().rev() {
-        if bin_size > VSIZE_BIN_WIDTH && last_fee_rate != e.fee_per_vbyte {
+        if bin_size > VSIZE_BIN_WIDTH && last_fee_rate != e.effective_fee_per_vbyte {
             // vsize of transactions paying >= last_fee_rate
             histogram.push((last_fee_rate, bin_size));
             bin_size = 0;
         }
-        last_fee_rate = e.fee_per_vbyte;
+        last_fee_rate = e.effective_fee_per_vbyte;
         bin_size += e.vsize;
     }
     if bin_size > 0 {
@@ -58,3 +67,179 @@ pub fn make_fee_histogram(mut entries: Vec<&TxFeeInfo>) -> Vec<(f32, u32)> {
     }
     histogram
 }
+
+pub fn sort_by_feerate(entries: &[TxFeeInfo]) -> Vec<&TxFeeInfo> {
+    let mut sorted: Vec<&TxFeeInfo> = entries.iter().collect();
+    sorted.sort_unstable_by(|e1, e2| e1.fee_per_vbyte.partial_cmp(&e2.fee_per_vbyte).unwrap());
+    sorted
+}
+
+// Returns the feerates at the given percentiles (weighted by vsize) of the entries,
+// which must be sorted by feerate
+pub fn feerate_percentiles(sorted: &[&TxFeeInfo], percentiles: &[u64]) -> Vec<f32> {
+    let last = match sorted.last() {
+        Some(last) => last.fee_per_vbyte,
+        None => return vec![],
+    };
+    let total_vsize: u64 = sorted.iter().map(|e| e.vsize as u64).sum();
+
+    let mut percentiles = percentiles.iter().peekable();
+    let mut result = vec![];
+    let mut cumulative_vsize = 0;
+    for e in sorted {
+        cumulative_vsize += e.vsize as u64;
+        while let Some(percentile) = percentiles.peek() {
+            if cumulative_vsize * 100 < total_vsize * **percentile {
+                break;
+            }
+            result.push(e.fee_per_vbyte);
+            percentiles.next();
+        }
+    }
+    // rounding leftovers belong to the highest feerate
+    result.extend(percentiles.map(|_| last));
+    result
+}
+
+/// Estimates the feerate (in sat/vbyte) needed to confirm within each of the `conf_targets`,
+/// combining the current mempool depth with the feerates accepted by the recent blocks.
+///
+/// `fee_histogram` is the mempool histogram (as returned by `make_fee_histogram`) and
+/// `recent_feerates` holds the lowest feerate each recent block required, where blocks
+/// that weren't full required nothing (`0.0`). Estimates never go below `min_feerate`.
+pub fn estimate_feerates(
+    fee_histogram: &[(f32, u32)],
+    recent_feerates: &[f32],
+    min_feerate: f64,
+    conf_targets: &[u16],
+) -> HashMap<u16, f64> {
+    let mut recent_feerates = recent_feerates.to_vec();
+    recent_feerates.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap());
+
+    let mut conf_targets = conf_targets.to_vec();
+    conf_targets.sort_unstable();
+
+    // longer targets never need a higher feerate than shorter ones
+    let mut estimate = std::f64::INFINITY;
+    conf_targets
+        .into_iter()
+        .map(|target| {
+            let mempool_feerate = mempool_feerate(fee_histogram, target).unwrap_or(0.0);
+            let blocks_feerate = blocks_feerate(&recent_feerates, target).unwrap_or(0.0);
+            estimate = estimate
+                .min(mempool_feerate.max(blocks_feerate) as f64)
+                .max(min_feerate);
+            (target, estimate)
+        })
+        .collect()
+}
+
+// The feerate of the txs at the bottom of the first `target` projected blocks, if the mempool
+// is deep enough to fill them
+fn mempool_feerate(fee_histogram: &[(f32, u32)], target: u16) -> Option<f32> {
+    let depth = target as u64 * ESTIMATE_BLOCK_VSIZE;
+    let mut cumulative_vsize = 0;
+    for (feerate, vsize) in fee_histogram {
+        cumulative_vsize += *vsize as u64;
+        if cumulative_vsize >= depth {
+            return Some(*feerate);
+        }
+    }
+    None
+}
+
+// The feerate that at least one of the next `target` blocks should accept with a 50% chance,
+// assuming they require feerates distributed like the recent (sorted) ones did
+fn blocks_feerate(sorted_feerates: &[f32], target: u16) -> Option<f32> {
+    if sorted_feerates.is_empty() {
+        return None;
+    }
+    let quantile = 1.0 - 0.5f64.powf(1.0 / target as f64);
+    let index = (quantile * sorted_feerates.len() as f64) as usize;
+    sorted_feerates.get(index).cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TARGETS: [u16; 6] = [1, 2, 3, 6, 25, 144];
+
+    fn feeinfo(fee_per_vbyte: f32, vsize: u32) -> TxFeeInfo {
+        TxFeeInfo {
+            fee: (fee_per_vbyte * vsize as f32) as u64,
+            vsize,
+            fee_per_vbyte,
+            effective_fee_per_vbyte: fee_per_vbyte,
+        }
+    }
+
+    // A mempool of `count` txs of 10,000 vbytes for each of the feerates
+    fn mempool(feerates: &[(f32, usize)]) -> Vec<(f32, u32)> {
+        let entries: Vec<TxFeeInfo> = feerates
+            .iter()
+            .flat_map(|(feerate, count)| (0..*count).map(move |_| feeinfo(*feerate, 10_000)))
+            .collect();
+        make_fee_histogram(entries.iter().collect())
+    }
+
+    #[test]
+    fn test_empty_mempool_and_blocks() {
+        let estimates = estimate_feerates(&mempool(&[]), &[], 1.0, &TARGETS);
+        assert_eq!(estimates.len(), TARGETS.len());
+        assert!(estimates.values().all(|feerate| *feerate == 1.0));
+    }
+
+    #[test]
+    fn test_shallow_mempool() {
+        // less than one block worth of txs, all of them fit in the next block
+        let histogram = mempool(&[(50.0, 20), (10.0, 30)]);
+        let estimates = estimate_feerates(&histogram, &[], 1.0, &TARGETS);
+        assert!(estimates.values().all(|feerate| *feerate == 1.0));
+    }
+
+    #[test]
+    fn test_deep_mempool() {
+        // 1M vbytes at 100 sat/vb, 1M at 20, 2M at 5 and 1M at 2
+        let histogram = mempool(&[(100.0, 100), (20.0, 100), (5.0, 200), (2.0, 100)]);
+        let estimates = estimate_feerates(&histogram, &[], 1.0, &TARGETS);
+        assert_eq!(estimates[&1], 100.0);
+        assert_eq!(estimates[&2], 20.0);
+        assert_eq!(estimates[&3], 5.0);
+        assert_eq!(estimates[&6], 1.0);
+        assert_eq!(estimates[&144], 1.0);
+    }
+
+    #[test]
+    fn test_recent_blocks() {
+        // an empty mempool, but the recent blocks were mostly full
+        let recent = [0.0, 3.0, 8.0, 10.0, 12.0, 15.0, 20.0, 30.0];
+        let estimates = estimate_feerates(&mempool(&[]), &recent, 1.0, &TARGETS);
+        assert_eq!(estimates[&1], 12.0);
+        assert_eq!(estimates[&2], 8.0);
+        assert_eq!(estimates[&3], 3.0);
+        assert_eq!(estimates[&6], 1.0);
+        assert_eq!(estimates[&144], 1.0);
+    }
+
+    #[test]
+    fn test_mempool_and_recent_blocks() {
+        // the higher of both estimates is used
+        let histogram = mempool(&[(40.0, 100), (4.0, 150)]);
+        let recent = [6.0; 10];
+        let estimates = estimate_feerates(&histogram, &recent, 1.0, &TARGETS);
+        assert_eq!(estimates[&1], 40.0);
+        assert_eq!(estimates[&2], 6.0);
+        assert_eq!(estimates[&144], 6.0);
+    }
+
+    #[test]
+    fn test_estimates_are_monotonic() {
+        let histogram = mempool(&[(80.0, 50), (30.0, 120), (12.0, 90), (3.0, 300)]);
+        let recent = [0.0, 0.0, 2.0, 5.0, 9.0, 25.0, 60.0];
+        let estimates = estimate_feerates(&histogram, &recent, 1.5, &TARGETS);
+        let ordered: Vec<f64> = TARGETS.iter().map(|target| estimates[target]).collect();
+        assert!(ordered.windows(2).all(|pair| pair[0] >= pair[1]));
+        assert!(ordered.iter().all(|feerate| *feerate >= 1.5));
+    }
+}
@@ -5,10 +5,16 @@ mod transaction;
 pub mod electrum_merkle;
 pub mod fees;
 pub(crate) mod heavyhash;
+#[cfg(not(feature = "liquid"))]
+pub mod xpub;
 
+#[cfg(not(feature = "liquid"))]
+pub use self::block::{block_feeinfos, BlockFees, BlockStats};
 pub use self::block::{BlockHeaderMeta, BlockId, BlockMeta, BlockStatus, HeaderEntry, HeaderList};
 pub use self::fees::get_tx_fee;
-pub use self::script::{get_innerscripts, get_script_asm, script_to_address};
+#[cfg(not(feature = "liquid"))]
+pub use self::script::get_stats_script_type;
+pub use self::script::{get_innerscripts, get_script_asm, get_script_type, script_to_address};
 pub use self::transaction::{
     extract_tx_prevouts, has_prevout, is_coinbase, is_spendable, TransactionStatus, TxInput,
 };
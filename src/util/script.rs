@@ -1,3 +1,5 @@
+#[cfg(not(feature = "liquid"))]
+use bitcoin::blockdata::opcodes;
 use bitcoin::blockdata::script::{Instruction::PushBytes, Script};
 
 #[cfg(feature = "liquid")]
@@ -27,6 +29,47 @@ pub fn get_script_asm(script: &Script) -> String {
     (&asm[7..asm.len() - 1]).to_string()
 }
 
+// TODO should the following something to put inside rust-elements lib?
+pub fn get_script_type(script: &Script) -> &'static str {
+    if script.is_empty() {
+        "empty"
+    } else if script.is_op_return() {
+        "op_return"
+    } else if script.is_p2pk() {
+        "p2pk"
+    } else if script.is_p2pkh() {
+        "p2pkh"
+    } else if script.is_p2sh() {
+        "p2sh"
+    } else if script.is_v0_p2wpkh() {
+        "v0_p2wpkh"
+    } else if script.is_v0_p2wsh() {
+        "v0_p2wsh"
+    } else if script.is_provably_unspendable() {
+        "provably_unspendable"
+    } else {
+        "unknown"
+    }
+}
+
+// Like get_script_type(), but also tells v1_p2tr outputs apart. The tx and address apis keep
+// reporting them as "unknown", to avoid changing their existing scriptpubkey_type values.
+#[cfg(not(feature = "liquid"))]
+pub fn get_stats_script_type(script: &Script) -> &'static str {
+    if is_v1_p2tr(script) {
+        "v1_p2tr"
+    } else {
+        get_script_type(script)
+    }
+}
+
+// OP_1 <32-byte x-only pubkey>
+#[cfg(not(feature = "liquid"))]
+fn is_v1_p2tr(script: &Script) -> bool {
+    let bytes = script.as_bytes();
+    bytes.len() == 34 && bytes[0] == opcodes::all::OP_PUSHNUM_1.into_u8() && bytes[1] == 0x20
+}
+
 // Returns the witnessScript in the case of p2wsh, or the redeemScript in the case of p2sh.
 pub fn get_innerscripts(txin: &TxIn, prevout: &TxOut) -> InnerScripts {
     // Wrapped redeemScript for P2SH spends
@@ -4,6 +4,7 @@ use bitcoin::{BlockHash, Txid};
 use bitcoin::hashes::hex::FromHex;
 
 use crate::chain::{OutPoint, Transaction, TxIn, TxOut};
+use crate::new_index::DroppedTx;
 use crate::util::BlockId;
 
 use std::collections::HashMap;
@@ -23,6 +24,14 @@ pub struct TransactionStatus {
     pub block_hash: Option<BlockHash>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub block_time: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub replaced_by: Option<Txid>, // set for unconfirmed txs that were replaced (RBF)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub first_seen: Option<u64>, // when the tx entered the mempool, in unix seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub confirmation_latency: Option<u64>, // from first seen to the block time, in seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dropped: Option<DroppedTx>, // set for txs that recently left the mempool without confirming
 }
 
 impl From<Option<BlockId>> for TransactionStatus {
@@ -33,12 +42,20 @@ impl From<Option<BlockId>> for TransactionStatus {
                 block_height: Some(b.height as usize),
                 block_hash: Some(b.hash),
                 block_time: Some(b.time),
+                replaced_by: None,
+                first_seen: None,
+                confirmation_latency: None,
+                dropped: None,
             },
             None => TransactionStatus {
                 confirmed: false,
                 block_height: None,
                 block_hash: None,
                 block_time: None,
+                replaced_by: None,
+                first_seen: None,
+                confirmation_latency: None,
+                dropped: None,
             },
         }
     }
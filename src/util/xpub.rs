@@ -0,0 +1,357 @@
+use std::str::FromStr;
+
+use bitcoin::secp256k1::{Secp256k1, Verification};
+use bitcoin::util::base58;
+use bitcoin::util::bip32::{ChildNumber, ExtendedPubKey};
+use bitcoin::{Address, Network as BNetwork, Script};
+
+use crate::errors::*;
+
+// SLIP-132 version bytes, and the script type they stand for
+const VERSIONS: [(u32, BNetwork, ScriptKind); 6] = [
+    (0x0488_b21e, BNetwork::Bitcoin, ScriptKind::P2pkh), // xpub
+    (0x049d_7cb2, BNetwork::Bitcoin, ScriptKind::P2shP2wpkh), // ypub
+    (0x04b2_4746, BNetwork::Bitcoin, ScriptKind::P2wpkh), // zpub
+    (0x0435_87cf, BNetwork::Testnet, ScriptKind::P2pkh), // tpub
+    (0x044a_5262, BNetwork::Testnet, ScriptKind::P2shP2wpkh), // upub
+    (0x045f_1cf6, BNetwork::Testnet, ScriptKind::P2wpkh), // vpub
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ScriptKind {
+    P2pkh,
+    P2shP2wpkh,
+    P2wpkh,
+}
+
+/// A chain of addresses derived from an extended public key (e.g. its receive or change chain)
+pub struct KeyChain {
+    pub path: String, // relative to the extended public key
+    xpub: ExtendedPubKey,
+    kind: ScriptKind,
+    network: BNetwork,
+}
+
+impl KeyChain {
+    fn new<C: Verification>(
+        secp: &Secp256k1<C>,
+        xpub: &ExtendedPubKey,
+        path: &[u32],
+        kind: ScriptKind,
+        network: BNetwork,
+    ) -> Result<Self> {
+        let mut chain_xpub = *xpub;
+        for index in path {
+            chain_xpub = derive(secp, &chain_xpub, *index)?;
+        }
+        Ok(KeyChain {
+            path: path
+                .iter()
+                .map(|index| index.to_string())
+                .collect::<Vec<_>>()
+                .join("/"),
+            xpub: chain_xpub,
+            kind,
+            network,
+        })
+    }
+
+    /// Derives the address at the given index of the chain
+    pub fn address<C: Verification>(&self, secp: &Secp256k1<C>, index: u32) -> Result<Address> {
+        let key = derive(secp, &self.xpub, index)?.public_key;
+        Ok(match self.kind {
+            ScriptKind::P2pkh => Address::p2pkh(&key, self.network),
+            ScriptKind::P2shP2wpkh => Address::p2shwpkh(&key, self.network),
+            ScriptKind::P2wpkh => Address::p2wpkh(&key, self.network),
+        })
+    }
+
+    pub fn script<C: Verification>(&self, secp: &Secp256k1<C>, index: u32) -> Result<Script> {
+        Ok(self.address(secp, index)?.script_pubkey())
+    }
+}
+
+fn derive<C: Verification>(
+    secp: &Secp256k1<C>,
+    xpub: &ExtendedPubKey,
+    index: u32,
+) -> Result<ExtendedPubKey> {
+    let child = ChildNumber::from_normal_idx(index).chain_err(|| "invalid derivation index")?;
+    xpub.ckd_pub(secp, child)
+        .chain_err(|| "failed deriving public key")
+}
+
+/// Parses an extended public key with SLIP-132 version bytes (xpub/ypub/zpub or tpub/upub/vpub)
+/// into its receive and change chains.
+pub fn parse_xpub<C: Verification>(
+    secp: &Secp256k1<C>,
+    xpub: &str,
+    network: BNetwork,
+) -> Result<Vec<KeyChain>> {
+    let (xpub, kind) = decode_xpub(xpub, network)?;
+    Ok(vec![
+        KeyChain::new(secp, &xpub, &[0], kind, network)?,
+        KeyChain::new(secp, &xpub, &[1], kind, network)?,
+    ])
+}
+
+/// Parses an output descriptor of the `pkh(KEY)`, `wpkh(KEY)` or `sh(wpkh(KEY))` forms,
+/// where KEY is an extended public key (with an optional origin) ending with `/*` or `/<0;1>/*`.
+pub fn parse_descriptor<C: Verification>(
+    secp: &Secp256k1<C>,
+    descriptor: &str,
+    network: BNetwork,
+) -> Result<Vec<KeyChain>> {
+    // the checksum is optional, and not verified
+    let descriptor = descriptor.splitn(2, '#').next().unwrap().trim();
+    let (kind, key) = if let Some(key) = strip_wrapper(descriptor, "sh(wpkh(", "))") {
+        (ScriptKind::P2shP2wpkh, key)
+    } else if let Some(key) = strip_wrapper(descriptor, "wpkh(", ")") {
+        (ScriptKind::P2wpkh, key)
+    } else if let Some(key) = strip_wrapper(descriptor, "pkh(", ")") {
+        (ScriptKind::P2pkh, key)
+    } else {
+        bail!("unsupported descriptor, expected pkh(), wpkh() or sh(wpkh())")
+    };
+
+    // skip the key origin
+    let key = match key.find(']') {
+        Some(pos) if key.starts_with('[') => &key[pos + 1..],
+        _ => key,
+    };
+
+    let mut parts = key.split('/');
+    let (xpub, _) = decode_xpub(parts.next().unwrap(), network)?;
+    let parts: Vec<&str> = parts.collect();
+    ensure!(
+        parts.last() == Some(&"*"),
+        "descriptor key must end with a /* wildcard"
+    );
+
+    // a multipath step (e.g. <0;1>) expands into one chain per index
+    let mut paths: Vec<Vec<u32>> = vec![vec![]];
+    for part in &parts[..parts.len() - 1] {
+        let indexes: Vec<u32> = if part.starts_with('<') && part.ends_with('>') {
+            part[1..part.len() - 1]
+                .split(';')
+                .map(|index| index.parse())
+                .collect::<std::result::Result<_, _>>()
+                .chain_err(|| "invalid multipath derivation step")?
+        } else {
+            vec![part.parse().chain_err(|| "invalid derivation step")?]
+        };
+        paths = paths
+            .into_iter()
+            .flat_map(|path| {
+                indexes.iter().map(move |index| {
+                    let mut path = path.clone();
+                    path.push(*index);
+                    path
+                })
+            })
+            .collect();
+    }
+
+    paths
+        .iter()
+        .map(|path| KeyChain::new(secp, &xpub, path, kind, network))
+        .collect()
+}
+
+fn strip_wrapper<'a>(s: &'a str, prefix: &str, suffix: &str) -> Option<&'a str> {
+    if s.starts_with(prefix) && s.ends_with(suffix) && s.len() >= prefix.len() + suffix.len() {
+        Some(&s[prefix.len()..s.len() - suffix.len()])
+    } else {
+        None
+    }
+}
+
+// Decodes the extended public key, by normalizing its version bytes to xpub/tpub
+fn decode_xpub(xpub: &str, network: BNetwork) -> Result<(ExtendedPubKey, ScriptKind)> {
+    let mut data = base58::from_check(xpub).chain_err(|| "invalid extended public key")?;
+    ensure!(data.len() == 78, "invalid extended public key length");
+
+    let (key_network, kind) = VERSIONS
+        .iter()
+        .find(|(version, _, _)| data[0..4] == version.to_be_bytes())
+        .map(|(_, key_network, kind)| (*key_network, *kind))
+        .chain_err(|| "unknown extended public key version")?;
+    // regtest uses the testnet version bytes
+    let expected_network = match network {
+        BNetwork::Bitcoin => BNetwork::Bitcoin,
+        _ => BNetwork::Testnet,
+    };
+    ensure!(
+        key_network == expected_network,
+        "extended public key on invalid network"
+    );
+
+    let base_version = VERSIONS
+        .iter()
+        .find(|(_, version_network, version_kind)| {
+            *version_network == key_network && *version_kind == ScriptKind::P2pkh
+        })
+        .unwrap()
+        .0;
+    data[0..4].copy_from_slice(&base_version.to_be_bytes());
+    let xpub = ExtendedPubKey::from_str(&base58::check_encode_slice(&data))
+        .chain_err(|| "invalid extended public key")?;
+    Ok((xpub, kind))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The BIP44/49/84 account keys of the "abandon abandon ... about" test mnemonic
+    const XPUB: &str = "xpub6BosfCnifzxcFwrSzQiqu2DBVTshkCXacvNsWGYJVVhhawA7d4R5WSWGFNbi8Aw6ZRc1brxMyWMzG3DSSSSoekkudhUd9yLb6qx39T9nMdj";
+    const YPUB: &str = "ypub6Ww3ibxVfGzLrAH1PNcjyAWenMTbbAosGNB6VvmSEgytSER9azLDWCxoJwW7Ke7icmizBMXrzBx9979FfaHxHcrArf3zbeJJJUZPf663zsP";
+    const ZPUB: &str = "zpub6rFR7y4Q2AijBEqTUquhVz398htDFrtymD9xYYfG1m4wAcvPhXNfE3EfH1r1ADqtfSdVCToUG868RvUUkgDKf31mGDtKsAYz2oz2AGutZYs";
+    // the BIP84 key with the xpub version bytes, as used in descriptors
+    const ZPUB_AS_XPUB: &str = "xpub6CatWdiZiodmUeTDp8LT5or8nmbKNcuyvz7WyksVFkKB4RHwCD3XyuvPEbvqAQY3rAPshWcMLoP2fMFMKHPJ4ZeZXYVUhLv1VMrjPC7PW6V";
+    // the BIP49 test vector account key (testnet)
+    const TPUB: &str = "tpubDD7tXK8KeQ3YY83yWq755fHY2JW8Ha8Q765tknUM5rSvjPcGWfUppDFMpQ1ScziKfW3ZNtZvAD7M3u7bSs7HofjTD3KP3YxPK7X6hwV8Rk2";
+
+    fn addresses(chains: &[KeyChain], index: u32) -> Vec<String> {
+        let secp = Secp256k1::verification_only();
+        chains
+            .iter()
+            .map(|chain| chain.address(&secp, index).unwrap().to_string())
+            .collect()
+    }
+
+    fn descriptor_error(descriptor: &str) -> String {
+        let secp = Secp256k1::verification_only();
+        match parse_descriptor(&secp, descriptor, BNetwork::Bitcoin) {
+            Ok(_) => panic!("{} should be invalid", descriptor),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_xpub_derivation() {
+        let secp = Secp256k1::verification_only();
+        let chains = parse_xpub(&secp, XPUB, BNetwork::Bitcoin).unwrap();
+        assert_eq!(
+            chains.iter().map(|c| c.path.as_str()).collect::<Vec<_>>(),
+            vec!["0", "1"]
+        );
+        assert_eq!(
+            addresses(&chains, 0),
+            vec![
+                "1LqBGSKuX5yYUonjxT5qGfpUsXKYYWeabA",
+                "1J3J6EvPrv8q6AC3VCjWV45Uf3nssNMRtH"
+            ]
+        );
+        assert_eq!(
+            addresses(&chains[..1], 1),
+            vec!["1Ak8PffB2meyfYnbXZR9EGfLfFZVpzJvQP"]
+        );
+    }
+
+    #[test]
+    fn test_ypub_derivation() {
+        let secp = Secp256k1::verification_only();
+        let chains = parse_xpub(&secp, YPUB, BNetwork::Bitcoin).unwrap();
+        assert_eq!(
+            addresses(&chains, 0),
+            vec![
+                "37VucYSaXLCAsxYyAPfbSi9eh4iEcbShgf",
+                "34K56kSjgUCUSD8GTtuF7c9Zzwokbs6uZ7"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_zpub_derivation() {
+        let secp = Secp256k1::verification_only();
+        let chains = parse_xpub(&secp, ZPUB, BNetwork::Bitcoin).unwrap();
+        assert_eq!(
+            addresses(&chains, 0),
+            vec![
+                "bc1qcr8te4kr609gcawutmrza0j4xv80jy8z306fyu",
+                "bc1q8c6fshw2dlwun7ekn9qwf37cu2rn755upcp6el"
+            ]
+        );
+        assert_eq!(
+            addresses(&chains[..1], 1),
+            vec!["bc1qnjg0jd8228aq7egyzacy8cys3knf9xvrerkf9g"]
+        );
+    }
+
+    #[test]
+    fn test_xpub_network() {
+        let secp = Secp256k1::verification_only();
+        assert!(parse_xpub(&secp, ZPUB, BNetwork::Testnet).is_err());
+        assert!(parse_xpub(&secp, TPUB, BNetwork::Bitcoin).is_err());
+        // regtest uses the testnet keys
+        assert!(parse_xpub(&secp, TPUB, BNetwork::Regtest).is_ok());
+    }
+
+    #[test]
+    fn test_invalid_xpub() {
+        let secp = Secp256k1::verification_only();
+        // bad checksum
+        let xpub = format!("{}k", &XPUB[..XPUB.len() - 1]);
+        assert!(parse_xpub(&secp, &xpub, BNetwork::Bitcoin).is_err());
+        assert!(parse_xpub(&secp, "xpub", BNetwork::Bitcoin).is_err());
+    }
+
+    #[test]
+    fn test_pkh_descriptor() {
+        let secp = Secp256k1::verification_only();
+        let descriptor = format!("pkh({}/0/*)", XPUB);
+        let chains = parse_descriptor(&secp, &descriptor, BNetwork::Bitcoin).unwrap();
+        assert_eq!(chains.len(), 1);
+        assert_eq!(chains[0].path, "0");
+        assert_eq!(
+            addresses(&chains, 0),
+            vec!["1LqBGSKuX5yYUonjxT5qGfpUsXKYYWeabA"]
+        );
+    }
+
+    #[test]
+    fn test_wpkh_multipath_descriptor() {
+        let secp = Secp256k1::verification_only();
+        // with a key origin and a checksum (which isn't verified)
+        let descriptor = format!(
+            "wpkh([73c5da0a/84'/0'/0']{}/<0;1>/*)#abcdefgh",
+            ZPUB_AS_XPUB
+        );
+        let chains = parse_descriptor(&secp, &descriptor, BNetwork::Bitcoin).unwrap();
+        assert_eq!(
+            chains.iter().map(|c| c.path.as_str()).collect::<Vec<_>>(),
+            vec!["0", "1"]
+        );
+        assert_eq!(
+            addresses(&chains, 0),
+            vec![
+                "bc1qcr8te4kr609gcawutmrza0j4xv80jy8z306fyu",
+                "bc1q8c6fshw2dlwun7ekn9qwf37cu2rn755upcp6el"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sh_wpkh_descriptor() {
+        let secp = Secp256k1::verification_only();
+        let descriptor = format!("sh(wpkh({}/0/*))", TPUB);
+        let chains = parse_descriptor(&secp, &descriptor, BNetwork::Testnet).unwrap();
+        assert_eq!(
+            addresses(&chains, 0),
+            vec!["2Mww8dCYPUpKHofjgcXcBCEGmniw9CoaiD2"]
+        );
+    }
+
+    #[test]
+    fn test_invalid_descriptors() {
+        assert!(descriptor_error(&format!("tr({}/0/*)", XPUB)).contains("unsupported"));
+        assert!(descriptor_error(&format!("wpkh({}/0/*", XPUB)).contains("unsupported"));
+        assert!(descriptor_error(&format!("wpkh({}/0/1)", XPUB)).contains("wildcard"));
+        assert!(descriptor_error(&format!("wpkh({})", XPUB)).contains("wildcard"));
+        assert!(descriptor_error(&format!("wpkh({}/0h/*)", XPUB)).contains("derivation step"));
+        assert!(descriptor_error(&format!("wpkh({}/<0;x>/*)", XPUB)).contains("multipath"));
+        assert!(descriptor_error(&format!("wpkh({}/0/*)", TPUB)).contains("network"));
+        assert!(descriptor_error("wpkh(02aabb/0/*)").contains("extended public key"));
+    }
+}
@@ -0,0 +1,836 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use bitcoin::consensus::encode::{deserialize, serialize};
+use bitcoin::hashes::hex::ToHex;
+use bitcoin::Txid;
+use crossbeam_channel as channel;
+use crypto::digest::Digest;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha256;
+use error_chain::ChainedError;
+use url::{Position, Url};
+
+use crate::errors::*;
+use crate::metrics::{CounterVec, MetricOpts, Metrics};
+use crate::new_index::{ChainQuery, Event};
+use crate::util::{full_hash, spawn_thread, BlockId, FullHash};
+
+const MAX_SUBSCRIPTIONS: usize = 10_000;
+const MAX_PENDING_DELIVERIES: usize = 100_000;
+const DELIVERY_MAX_ATTEMPTS: u32 = 10;
+const DELIVERY_BACKOFF: Duration = Duration::from_secs(2); // doubled after every failed attempt
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+const DELIVERY_WORKERS: usize = 8;
+
+/// A webhook subscription, persisted in the cache db under `W{id}`
+#[derive(Serialize, Deserialize)]
+pub struct Subscription {
+    pub id: String,
+    pub address: Option<String>,
+    pub scripthash: String,
+    pub url: String,
+    pub min_confirmations: u32,
+    pub secret: String, // the HMAC-SHA256 key used to sign the notifications
+}
+
+#[derive(Default)]
+struct Subscriptions {
+    by_id: HashMap<String, Arc<Subscription>>,
+    by_script: HashMap<FullHash, Vec<Arc<Subscription>>>,
+}
+
+impl Subscriptions {
+    fn insert(&mut self, sub: Arc<Subscription>) {
+        self.remove(&sub.id);
+        self.by_script
+            .entry(script_key(&sub))
+            .or_insert_with(Vec::new)
+            .push(Arc::clone(&sub));
+        self.by_id.insert(sub.id.clone(), sub);
+    }
+
+    fn remove(&mut self, id: &str) -> bool {
+        let sub = match self.by_id.remove(id) {
+            Some(sub) => sub,
+            None => return false,
+        };
+        let key = script_key(&sub);
+        if let Some(subs) = self.by_script.get_mut(&key) {
+            subs.retain(|other| other.id != sub.id);
+            if subs.is_empty() {
+                self.by_script.remove(&key);
+            }
+        }
+        true
+    }
+}
+
+fn script_key(sub: &Subscription) -> FullHash {
+    full_hash(&hex::decode(&sub.scripthash).expect("invalid subscription scripthash"))
+}
+
+fn db_key(id: &str) -> Vec<u8> {
+    [b"W", id.as_bytes()].concat()
+}
+
+fn pending_key(id: &str, txid: &Txid) -> Vec<u8> {
+    [b"w", id.as_bytes(), &serialize(txid)].concat()
+}
+
+// A notification to send, as (subscription, event, txid, confirmations, confirming block)
+type Message = (Arc<Subscription>, &'static str, Txid, u32, Option<BlockId>);
+
+#[derive(Serialize)]
+struct Notification<'a> {
+    subscription: &'a str,
+    event: &'a str, // mempool, confirmed or confirmations
+    #[serde(skip_serializing_if = "Option::is_none")]
+    address: Option<&'a str>,
+    scripthash: &'a str,
+    txid: &'a Txid,
+    confirmations: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    block: Option<&'a BlockId>,
+    time: u64,
+}
+
+struct Delivery {
+    url: Url,
+    endpoint: String, // the url origin, which gets at most one delivery attempt in flight
+    body: Vec<u8>,
+    signature: String,
+    attempts: u32,
+    next_attempt: Instant,
+}
+
+/// Posts signed notifications to the subscribed urls when the watched scripts get mempool txs,
+/// when these txs confirm and when they reach the subscription's `min_confirmations`.
+pub struct Webhooks {
+    chain: Arc<ChainQuery>,
+    subscriptions: RwLock<Subscriptions>,
+    pending: Mutex<HashSet<(String, Txid)>>, // confirmed txs waiting for more confirmations
+    deliveries: channel::Sender<Delivery>,
+
+    // monitoring
+    notifications: CounterVec,
+}
+
+impl Webhooks {
+    /// Loads the persisted subscriptions and pending confirmations, and starts the delivery threads.
+    pub fn start(chain: Arc<ChainQuery>, metrics: &Metrics) -> Arc<Webhooks> {
+        let mut subscriptions = Subscriptions::default();
+        for row in chain.store().cache_db().iter_scan(b"W") {
+            let sub: Subscription =
+                serde_json::from_slice(&row.value).expect("failed to parse webhook subscription");
+            subscriptions.insert(Arc::new(sub));
+        }
+        let pending: HashSet<(String, Txid)> = chain
+            .store()
+            .cache_db()
+            .iter_scan(b"w")
+            .map(|row| {
+                let (id, txid) = row.key[1..].split_at(row.key.len() - 33);
+                let id = String::from_utf8(id.to_vec()).expect("invalid webhook subscription id");
+                let txid = deserialize(txid).expect("failed to parse webhook pending txid");
+                (id, txid)
+            })
+            .collect();
+        info!(
+            "loaded {} webhook subscriptions with {} pending confirmations",
+            subscriptions.by_id.len(),
+            pending.len()
+        );
+
+        let results = metrics.counter_vec(
+            MetricOpts::new(
+                "webhook_deliveries",
+                "# of webhook delivery attempts by result",
+            ),
+            &["result"],
+        );
+        Arc::new(Webhooks {
+            chain,
+            subscriptions: RwLock::new(subscriptions),
+            pending: Mutex::new(pending),
+            deliveries: start_deliveries(DELIVERY_BACKOFF, results),
+            notifications: metrics.counter_vec(
+                MetricOpts::new(
+                    "webhook_notifications",
+                    "# of webhook notifications by event",
+                ),
+                &["event"],
+            ),
+        })
+    }
+
+    pub fn subscribe(
+        &self,
+        scripthash: &FullHash,
+        address: Option<String>,
+        url: &str,
+        min_confirmations: u32,
+        secret: String,
+    ) -> Result<Arc<Subscription>> {
+        let parsed = Url::parse(url).chain_err(|| "invalid webhook url")?;
+        if parsed.scheme() != "http" || parsed.host_str().is_none() {
+            bail!("webhook urls must be http://host[:port]/path")
+        }
+        if min_confirmations == 0 {
+            bail!("min_confirmations must be at least 1")
+        }
+
+        let mut sha2 = Sha256::new();
+        sha2.input(scripthash);
+        sha2.input(url.as_bytes());
+        sha2.input(&min_confirmations.to_be_bytes());
+        let mut id = [0u8; 32];
+        sha2.result(&mut id);
+
+        let sub = Subscription {
+            id: id[..16].to_hex(),
+            address,
+            scripthash: scripthash.to_hex(),
+            url: url.to_string(),
+            min_confirmations,
+            secret,
+        };
+
+        let mut subscriptions = self.subscriptions.write().unwrap();
+        if !subscriptions.by_id.contains_key(&sub.id)
+            && subscriptions.by_id.len() >= MAX_SUBSCRIPTIONS
+        {
+            bail!("too many webhook subscriptions")
+        }
+        self.chain
+            .store()
+            .cache_db()
+            .put_sync(&db_key(&sub.id), &serde_json::to_vec(&sub).unwrap());
+        let sub = Arc::new(sub);
+        subscriptions.insert(Arc::clone(&sub));
+        Ok(sub)
+    }
+
+    pub fn unsubscribe(&self, id: &str) -> bool {
+        let removed = self.subscriptions.write().unwrap().remove(id);
+        if removed {
+            let cache_db = self.chain.store().cache_db();
+            cache_db.delete(&db_key(id));
+            self.pending.lock().unwrap().retain(|(sub_id, txid)| {
+                if sub_id != id {
+                    return true;
+                }
+                cache_db.delete(&pending_key(id, txid));
+                false
+            });
+        }
+        removed
+    }
+
+    pub fn subscriptions(&self) -> Vec<Arc<Subscription>> {
+        let subscriptions = self.subscriptions.read().unwrap();
+        let mut subs: Vec<_> = subscriptions.by_id.values().cloned().collect();
+        subs.sort_by(|a, b| a.id.cmp(&b.id));
+        subs
+    }
+
+    /// Queues the notifications for the chain and mempool events.
+    pub fn notify(&self, events: &[Event]) {
+        let subscriptions = self.subscriptions.read().unwrap();
+        if subscriptions.by_id.is_empty() {
+            return;
+        }
+
+        let (messages, new_block) = event_messages(&subscriptions, events, |txid| {
+            self.chain.tx_confirming_block(txid)
+        });
+        let cache_db = self.chain.store().cache_db();
+        for (sub, event, txid, confirmations, block) in messages {
+            if event == "confirmed"
+                && sub.min_confirmations > 1
+                && self.pending.lock().unwrap().insert((sub.id.clone(), txid))
+            {
+                cache_db.put(&pending_key(&sub.id, &txid), b"");
+            }
+            self.send(&sub, event, &txid, confirmations, block.as_ref());
+        }
+        if new_block {
+            self.check_confirmations(&subscriptions);
+        }
+    }
+
+    fn check_confirmations(&self, subscriptions: &Subscriptions) {
+        let mut pending = self.pending.lock().unwrap();
+        let (messages, settled) =
+            confirmation_messages(subscriptions, &pending, self.chain.best_height(), |txid| {
+                self.chain.tx_confirming_block(txid)
+            });
+        let cache_db = self.chain.store().cache_db();
+        for (id, txid) in settled {
+            cache_db.delete(&pending_key(&id, &txid));
+            pending.remove(&(id, txid));
+        }
+        drop(pending);
+        for (sub, event, txid, confirmations, block) in messages {
+            self.send(&sub, event, &txid, confirmations, block.as_ref());
+        }
+    }
+
+    fn send(
+        &self,
+        sub: &Subscription,
+        event: &str,
+        txid: &Txid,
+        confirmations: u32,
+        block: Option<&BlockId>,
+    ) {
+        let notification = Notification {
+            subscription: &sub.id,
+            event,
+            address: sub.address.as_deref(),
+            scripthash: &sub.scripthash,
+            txid,
+            confirmations,
+            block,
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+        };
+        let body = serde_json::to_vec(&notification).unwrap();
+        let signature = sign(&sub.secret, &body);
+        self.notifications.with_label_values(&[event]).inc();
+        self.deliveries
+            .send(Delivery::new(
+                Url::parse(&sub.url).unwrap(),
+                body,
+                signature,
+            ))
+            .expect("webhook delivery thread failed");
+    }
+}
+
+// Maps the script activity events to the `mempool` and `confirmed` notifications of the
+// subscriptions to the script, and reports whether a new block was connected.
+fn event_messages<F>(
+    subscriptions: &Subscriptions,
+    events: &[Event],
+    confirming_block: F,
+) -> (Vec<Message>, bool)
+where
+    F: Fn(&Txid) -> Option<BlockId>,
+{
+    let mut messages = vec![];
+    let mut new_block = false;
+    // a tx may have several outputs and inputs of the same script
+    let mut notified = HashSet::new();
+    for event in events {
+        match event {
+            Event::Block(_) => new_block = true,
+            Event::ScriptActivity {
+                scripthash,
+                txid,
+                confirmed,
+            } => {
+                let subs = match subscriptions.by_script.get(scripthash) {
+                    Some(subs) => subs,
+                    None => continue,
+                };
+                if !notified.insert((scripthash, txid, confirmed)) {
+                    continue;
+                }
+                let (event, confirmations, block) = if *confirmed {
+                    ("confirmed", 1, confirming_block(txid))
+                } else {
+                    ("mempool", 0, None)
+                };
+                for sub in subs {
+                    messages.push((Arc::clone(sub), event, *txid, confirmations, block.clone()));
+                }
+            }
+            _ => (),
+        }
+    }
+    (messages, new_block)
+}
+
+// Maps the pending confirmations that reached their subscription's `min_confirmations` to
+// `confirmations` notifications, and returns them along with the entries that are settled (the
+// notified ones, and those of removed subscriptions or of txs that were reorged out, which are
+// tracked again once they re-confirm).
+fn confirmation_messages<F>(
+    subscriptions: &Subscriptions,
+    pending: &HashSet<(String, Txid)>,
+    tip_height: usize,
+    confirming_block: F,
+) -> (Vec<Message>, Vec<(String, Txid)>)
+where
+    F: Fn(&Txid) -> Option<BlockId>,
+{
+    let mut messages = vec![];
+    let mut settled = vec![];
+    for (id, txid) in pending {
+        let sub = match subscriptions.by_id.get(id) {
+            Some(sub) => sub,
+            None => {
+                settled.push((id.clone(), *txid));
+                continue;
+            }
+        };
+        let block = match confirming_block(txid) {
+            Some(block) => block,
+            None => {
+                settled.push((id.clone(), *txid));
+                continue;
+            }
+        };
+        let confirmations = (tip_height + 1 - block.height) as u32;
+        if confirmations >= sub.min_confirmations {
+            messages.push((
+                Arc::clone(sub),
+                "confirmations",
+                *txid,
+                confirmations,
+                Some(block),
+            ));
+            settled.push((id.clone(), *txid));
+        }
+    }
+    (messages, settled)
+}
+
+fn sign(secret: &str, body: &[u8]) -> String {
+    let mut hmac = Hmac::new(Sha256::new(), secret.as_bytes());
+    hmac.input(body);
+    hex::encode(hmac.result().code())
+}
+
+impl Delivery {
+    fn new(url: Url, body: Vec<u8>, signature: String) -> Delivery {
+        Delivery {
+            endpoint: url.origin().ascii_serialization(),
+            url,
+            body,
+            signature,
+            attempts: 0,
+            next_attempt: Instant::now(),
+        }
+    }
+}
+
+// Delivers the notifications in the order they were queued, retrying the failed ones with an
+// exponential backoff. The attempts are made by a pool of workers, with at most one attempt per
+// endpoint in flight, so that an unresponsive endpoint doesn't hold back the others.
+fn start_deliveries(backoff: Duration, results: CounterVec) -> channel::Sender<Delivery> {
+    let (sender, receiver) = channel::unbounded::<Delivery>();
+    let (attempt_sender, attempt_receiver) = channel::unbounded::<Delivery>();
+    // the endpoint of every finished attempt, along with the delivery if it should be retried
+    let (done_sender, done_receiver) = channel::unbounded::<(String, Option<Delivery>)>();
+
+    for _ in 0..DELIVERY_WORKERS {
+        let attempt_receiver = attempt_receiver.clone();
+        let done_sender = done_sender.clone();
+        let results = results.clone();
+        spawn_thread("webhook-worker", move || {
+            for delivery in attempt_receiver {
+                let endpoint = delivery.endpoint.clone();
+                let retry = attempt(delivery, backoff, &results);
+                if done_sender.send((endpoint, retry)).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    spawn_thread("webhooks", move || {
+        let mut queue: Vec<Delivery> = vec![];
+        let mut in_flight: HashSet<String> = HashSet::new();
+        loop {
+            let now = Instant::now();
+            let mut waiting = vec![];
+            for delivery in queue.drain(..) {
+                if delivery.next_attempt <= now && !in_flight.contains(&delivery.endpoint) {
+                    in_flight.insert(delivery.endpoint.clone());
+                    attempt_sender.send(delivery).unwrap();
+                } else {
+                    waiting.push(delivery);
+                }
+            }
+            queue = waiting;
+
+            let next_attempt = queue
+                .iter()
+                .filter(|delivery| !in_flight.contains(&delivery.endpoint))
+                .map(|delivery| delivery.next_attempt)
+                .min();
+            let wakeup = match next_attempt {
+                Some(next_attempt) => channel::at(next_attempt),
+                None => channel::never(),
+            };
+            let disconnected = channel::select! {
+                recv(receiver) -> delivery => match delivery {
+                    Ok(delivery) => {
+                        if queue.len() < MAX_PENDING_DELIVERIES {
+                            queue.push(delivery);
+                        } else {
+                            warn!("too many pending webhook deliveries, dropping notification");
+                            results.with_label_values(&["dropped"]).inc();
+                        }
+                        false
+                    }
+                    Err(_) => true,
+                },
+                recv(done_receiver) -> done => {
+                    let (endpoint, retry) = done.unwrap();
+                    in_flight.remove(&endpoint);
+                    queue.extend(retry);
+                    false
+                },
+                recv(wakeup) -> _ => false,
+            };
+            if disconnected {
+                break;
+            }
+        }
+    });
+    sender
+}
+
+// Makes a delivery attempt, and returns the delivery if it should be retried
+fn attempt(mut delivery: Delivery, backoff: Duration, results: &CounterVec) -> Option<Delivery> {
+    let err = match post(&delivery.url, &delivery.body, &delivery.signature) {
+        Ok(()) => {
+            results.with_label_values(&["delivered"]).inc();
+            return None;
+        }
+        Err(e) => e,
+    };
+    delivery.attempts += 1;
+    if delivery.attempts >= DELIVERY_MAX_ATTEMPTS {
+        warn!(
+            "giving up on webhook delivery to {}: {}",
+            delivery.url,
+            err.display_chain()
+        );
+        results.with_label_values(&["failed"]).inc();
+        return None;
+    }
+    debug!("webhook delivery to {} failed: {}", delivery.url, err);
+    results.with_label_values(&["retried"]).inc();
+    delivery.next_attempt = Instant::now() + backoff * 2u32.pow(delivery.attempts - 1);
+    Some(delivery)
+}
+
+fn post(url: &Url, body: &[u8], signature: &str) -> Result<()> {
+    let host = url.host_str().ok_or("missing host")?;
+    let port = url.port_or_known_default().unwrap_or(80);
+    let addr = (host, port)
+        .to_socket_addrs()
+        .chain_err(|| format!("failed to resolve {}", host))?
+        .next()
+        .ok_or_else(|| format!("no address found for {}", host))?;
+
+    let mut stream = TcpStream::connect_timeout(&addr, DELIVERY_TIMEOUT)
+        .chain_err(|| format!("failed to connect to {}", addr))?;
+    stream
+        .set_read_timeout(Some(DELIVERY_TIMEOUT))
+        .chain_err(|| "failed to set read timeout")?;
+    stream
+        .set_write_timeout(Some(DELIVERY_TIMEOUT))
+        .chain_err(|| "failed to set write timeout")?;
+
+    let host_header = match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_string(),
+    };
+    let request = format!(
+        "POST {} HTTP/1.1\r\n\
+         Host: {}\r\n\
+         Content-Type: application/json\r\n\
+         Content-Length: {}\r\n\
+         X-Webhook-Signature: sha256={}\r\n\
+         Connection: close\r\n\r\n",
+        &url[Position::BeforePath..Position::AfterQuery],
+        host_header,
+        body.len(),
+        signature,
+    );
+    stream
+        .write_all(request.as_bytes())
+        .and_then(|_| stream.write_all(body))
+        .chain_err(|| "failed to send the notification")?;
+
+    let mut status_line = String::new();
+    BufReader::new(stream)
+        .read_line(&mut status_line)
+        .chain_err(|| "failed to read the response")?;
+    let status: u16 = status_line
+        .split(' ')
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .unwrap_or(0);
+    if !(200..300).contains(&status) {
+        bail!("unexpected response: {}", status_line.trim_end())
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::hashes::Hash;
+    use bitcoin::BlockHash;
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+
+    // A local HTTP sink answering with the given statuses, and reporting the requests it got
+    fn start_sink(statuses: Vec<u16>) -> (Url, mpsc::Receiver<(String, Vec<u8>)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!(
+            "http://{}/hook?source=electrs",
+            listener.local_addr().unwrap()
+        ))
+        .unwrap();
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            for status in statuses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut head = String::new();
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line == "\r\n" {
+                        break;
+                    }
+                    head.push_str(&line);
+                }
+                let length: usize = head
+                    .lines()
+                    .find(|line| line.starts_with("Content-Length: "))
+                    .unwrap()["Content-Length: ".len()..]
+                    .parse()
+                    .unwrap();
+                let mut body = vec![0u8; length];
+                reader.read_exact(&mut body).unwrap();
+                write!(
+                    reader.get_mut(),
+                    "HTTP/1.1 {} Status\r\nContent-Length: 0\r\n\r\n",
+                    status
+                )
+                .unwrap();
+                sender.send((head, body)).unwrap();
+            }
+        });
+        (url, receiver)
+    }
+
+    fn delivery(url: &Url, body: &[u8]) -> Delivery {
+        Delivery::new(url.clone(), body.to_vec(), sign("secret", body))
+    }
+
+    fn subscription(id: &str, scripthash: &FullHash, min_confirmations: u32) -> Arc<Subscription> {
+        Arc::new(Subscription {
+            id: id.to_string(),
+            address: None,
+            scripthash: scripthash.to_hex(),
+            url: "http://127.0.0.1/hook".to_string(),
+            min_confirmations,
+            secret: "secret".to_string(),
+        })
+    }
+
+    fn block(height: usize) -> BlockId {
+        BlockId {
+            height,
+            hash: BlockHash::hash(&height.to_be_bytes()),
+            time: 0,
+        }
+    }
+
+    // The messages as (subscription id, event, txid, confirmations, confirming block height)
+    fn summary(
+        mut messages: Vec<Message>,
+    ) -> Vec<(String, &'static str, Txid, u32, Option<usize>)> {
+        messages.sort_by(|a, b| (&a.0.id, a.1).cmp(&(&b.0.id, b.1)));
+        messages
+            .into_iter()
+            .map(|(sub, event, txid, confirmations, block)| {
+                (
+                    sub.id.clone(),
+                    event,
+                    txid,
+                    confirmations,
+                    block.map(|b| b.height),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_event_messages() {
+        let (script, other_script, unwatched_script) = ([1u8; 32], [2u8; 32], [3u8; 32]);
+        let mut subscriptions = Subscriptions::default();
+        subscriptions.insert(subscription("a", &script, 1));
+        subscriptions.insert(subscription("b", &script, 3));
+        subscriptions.insert(subscription("c", &other_script, 1));
+
+        let (mempool_tx, confirmed_tx) = (Txid::hash(b"mempool"), Txid::hash(b"confirmed"));
+        let activity = |scripthash: &FullHash, txid: &Txid, confirmed| Event::ScriptActivity {
+            scripthash: *scripthash,
+            txid: *txid,
+            confirmed,
+        };
+        let events = vec![
+            // a tx funding and spending from the same script is only notified once
+            activity(&script, &mempool_tx, false),
+            activity(&script, &mempool_tx, false),
+            activity(&unwatched_script, &mempool_tx, false),
+            activity(&script, &confirmed_tx, true),
+        ];
+        let confirming_block = |txid: &Txid| {
+            if *txid == confirmed_tx {
+                Some(block(100))
+            } else {
+                None
+            }
+        };
+
+        let (messages, new_block) = event_messages(&subscriptions, &events, confirming_block);
+        assert!(!new_block);
+        assert_eq!(
+            summary(messages),
+            vec![
+                ("a".to_string(), "confirmed", confirmed_tx, 1, Some(100)),
+                ("a".to_string(), "mempool", mempool_tx, 0, None),
+                ("b".to_string(), "confirmed", confirmed_tx, 1, Some(100)),
+                ("b".to_string(), "mempool", mempool_tx, 0, None),
+            ]
+        );
+
+        let events = vec![Event::Block(block(101))];
+        let (messages, new_block) = event_messages(&subscriptions, &events, confirming_block);
+        assert!(new_block);
+        assert!(messages.is_empty());
+    }
+
+    #[test]
+    fn test_confirmation_messages() {
+        let script = [1u8; 32];
+        let mut subscriptions = Subscriptions::default();
+        subscriptions.insert(subscription("a", &script, 3));
+
+        let (confirmed_tx, reorged_tx) = (Txid::hash(b"confirmed"), Txid::hash(b"reorged"));
+        let pending: HashSet<(String, Txid)> = vec![
+            ("a".to_string(), confirmed_tx),
+            ("a".to_string(), reorged_tx),
+            ("unsubscribed".to_string(), confirmed_tx),
+        ]
+        .into_iter()
+        .collect();
+        let confirming_block = |txid: &Txid| {
+            if *txid == confirmed_tx {
+                Some(block(100))
+            } else {
+                None
+            }
+        };
+
+        // 2 confirmations, only the entries of the reorged tx and removed subscription are settled
+        let (messages, mut settled) =
+            confirmation_messages(&subscriptions, &pending, 101, confirming_block);
+        settled.sort();
+        assert!(messages.is_empty());
+        assert_eq!(
+            settled,
+            vec![
+                ("a".to_string(), reorged_tx),
+                ("unsubscribed".to_string(), confirmed_tx)
+            ]
+        );
+
+        // 3 confirmations reach the subscription's min_confirmations
+        let pending = vec![("a".to_string(), confirmed_tx)].into_iter().collect();
+        let (messages, settled) =
+            confirmation_messages(&subscriptions, &pending, 102, confirming_block);
+        assert_eq!(
+            summary(messages),
+            vec![("a".to_string(), "confirmations", confirmed_tx, 3, Some(100))]
+        );
+        assert_eq!(settled, vec![("a".to_string(), confirmed_tx)]);
+    }
+
+    #[test]
+    fn test_post_signed() {
+        let (url, requests) = start_sink(vec![200]);
+        let body = br#"{"event":"mempool"}"#;
+        post(&url, body, &sign("secret", body)).unwrap();
+
+        let (head, received) = requests.recv().unwrap();
+        assert!(head.starts_with("POST /hook?source=electrs HTTP/1.1\r\n"));
+        assert!(head.contains("Content-Type: application/json\r\n"));
+        assert!(head.contains(&format!(
+            "X-Webhook-Signature: sha256={}\r\n",
+            sign("secret", body)
+        )));
+        assert_eq!(received, body.to_vec());
+    }
+
+    #[test]
+    fn test_post_error_status() {
+        let (url, _requests) = start_sink(vec![500]);
+        assert!(post(&url, b"{}", &sign("secret", b"{}")).is_err());
+    }
+
+    #[test]
+    fn test_sign() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn test_deliveries_retry() {
+        let metrics = Metrics::new("127.0.0.1:0".parse().unwrap());
+        let results = metrics.counter_vec(MetricOpts::new("test", "test"), &["result"]);
+        let (url, requests) = start_sink(vec![503, 500, 200]);
+        let deliveries = start_deliveries(Duration::from_millis(10), results.clone());
+        deliveries.send(delivery(&url, b"{}")).unwrap();
+
+        for _ in 0..3 {
+            let (_, body) = requests.recv_timeout(Duration::from_secs(5)).unwrap();
+            assert_eq!(body, b"{}".to_vec());
+        }
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(results.with_label_values(&["retried"]).get(), 2);
+        assert_eq!(results.with_label_values(&["delivered"]).get(), 1);
+    }
+
+    #[test]
+    fn test_deliveries_unresponsive_endpoint() {
+        let metrics = Metrics::new("127.0.0.1:0".parse().unwrap());
+        let results = metrics.counter_vec(MetricOpts::new("test", "test"), &["result"]);
+        // accepts the connections (through the listen backlog) but never answers
+        let unresponsive = TcpListener::bind("127.0.0.1:0").unwrap();
+        let unresponsive_url = Url::parse(&format!(
+            "http://{}/hook",
+            unresponsive.local_addr().unwrap()
+        ))
+        .unwrap();
+        let (url, requests) = start_sink(vec![200]);
+        let deliveries = start_deliveries(Duration::from_millis(10), results.clone());
+        deliveries.send(delivery(&unresponsive_url, b"{}")).unwrap();
+        deliveries.send(delivery(&url, b"{}")).unwrap();
+
+        let (_, body) = requests.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(body, b"{}".to_vec());
+    }
+}